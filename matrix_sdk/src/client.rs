@@ -13,22 +13,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#[cfg(any(feature = "encryption", feature = "metrics"))]
+use std::collections::BTreeMap;
 #[cfg(feature = "encryption")]
-use std::{collections::BTreeMap, io::Write, path::PathBuf};
+use std::path::PathBuf;
 use std::{
     convert::TryInto,
     fmt::{self, Debug},
     future::Future,
-    io::Read,
+    io::{Read, Result as IoResult, Write},
     path::Path,
     result::Result as StdResult,
-    sync::Arc,
+    sync::{Arc, Mutex as SyncMutex},
 };
 
+use bytes::Bytes;
 #[cfg(feature = "encryption")]
 use dashmap::DashMap;
+use futures::{
+    channel::mpsc::UnboundedReceiver,
+    future::{abortable, AbortHandle},
+    stream::{self, Stream, StreamExt},
+};
 use futures_timer::Delay as sleep;
-use http::HeaderValue;
+use http::{header::HeaderName, HeaderMap, HeaderValue};
 use mime::{self, Mime};
 use reqwest::header::InvalidHeaderValue;
 use url::Url;
@@ -36,12 +44,13 @@ use url::Url;
 use zeroize::Zeroizing;
 
 #[cfg(feature = "encryption")]
-use tracing::{debug, warn};
-use tracing::{error, info, instrument};
+use tracing::debug;
+use tracing::{error, info, instrument, warn};
 
 use matrix_sdk_base::{
     deserialized_responses::{MembersResponse, SyncResponse},
-    BaseClient, BaseClientConfig, EventEmitter, InvitedRoom, JoinedRoom, LeftRoom, Session, Store,
+    BaseClient, BaseClientConfig, EventEmitter, InvitedRoom, JoinedRoom, LeftRoom, Session,
+    SessionInfo, Store,
 };
 
 #[cfg(feature = "encryption")]
@@ -65,23 +74,26 @@ pub enum LoopCtrl {
 }
 
 use matrix_sdk_common::{
+    api::error::ErrorKind,
     api::r0::{
         account::register,
         device::{delete_devices, get_devices},
         directory::{get_public_rooms, get_public_rooms_filtered},
         filter::{create_filter::Request as FilterUploadRequest, FilterDefinition},
-        media::create_content,
+        media::{create_content, get_content, get_content_thumbnail},
         membership::{
             ban_user, forget_room, get_member_events,
             invite_user::{self, InvitationRecipient},
             join_room_by_id, join_room_by_id_or_alias, kick_user, leave_room, Invite3pid,
         },
         message::{get_message_events, send_message_event},
-        profile::{get_avatar_url, get_display_name, set_avatar_url, set_display_name},
+        profile::{
+            get_avatar_url, get_display_name, get_profile, set_avatar_url, set_display_name,
+        },
         read_marker::set_read_marker,
         receipt::create_receipt,
         room::create_room,
-        session::login,
+        session::{login, logout, logout_all},
         sync::sync_events,
         typing::create_typing_event::{
             Request as TypingRequest, Response as TypingResponse, Typing,
@@ -101,9 +113,10 @@ use matrix_sdk_common::{
     },
     identifiers::{DeviceIdBox, EventId, RoomId, RoomIdOrAliasId, ServerName, UserId},
     instant::{Duration, Instant},
+    locks::RwLock,
     presence::PresenceState,
     uuid::Uuid,
-    FromHttpResponseError, UInt,
+    AsyncTraitDeps, FromHttpResponseError, UInt,
 };
 
 #[cfg(feature = "encryption")]
@@ -114,14 +127,27 @@ use matrix_sdk_common::{
             Request as RumaToDeviceRequest, Response as ToDeviceResponse,
         },
     },
+    events::{room::encrypted::EncryptedEventContent, AnySyncRoomEvent, SyncMessageEvent},
     locks::Mutex,
+    Raw,
 };
 
 use crate::{
-    http_client::{client_with_config, HttpClient, HttpSend},
-    Error, OutgoingRequest, Result,
+    device_display_name,
+    health::HealthTracker,
+    http_client::{
+        client_with_config, HttpClient, HttpSend, NoopSigner, PendingRequests, ResponseRewrite,
+        DEFAULT_REQUEST_TIMEOUT, DEFAULT_SESSION_LOCK_TIMEOUT,
+    },
+    profile_cache::{ProfileCache, DEFAULT_PROFILE_CACHE_TTL},
+    Error, HomeserverHealth, OutgoingRequest, PendingRequest, QueuedRequest,
+    RegistrationRequirements, RequestInterceptor, RequestSigner, RequestTiming, ResponseRewriter,
+    Result, RetryHandler, SupportInfo, TokenRefreshHandler,
 };
 
+#[cfg(feature = "metrics")]
+use crate::{metrics::Metrics, BandwidthUsage, EndpointCategory};
+
 #[cfg(feature = "encryption")]
 use crate::{
     device::{Device, UserDevices},
@@ -131,6 +157,44 @@ use crate::{
 };
 
 const DEFAULT_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the support information of the homeserver is cached.
+const SUPPORT_INFO_CACHE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// Append the given path prefix to the path of the homeserver URL.
+fn join_base_path(homeserver: &Url, base_path: &str) -> Url {
+    let base_path = base_path.trim_matches('/');
+
+    if base_path.is_empty() {
+        return homeserver.clone();
+    }
+
+    let mut url = homeserver.clone();
+    let path = format!("{}/{}", url.path().trim_end_matches('/'), base_path);
+    url.set_path(&path);
+
+    url
+}
+
+/// Split a `mxc://` URI into the server name and the media id.
+fn parse_mxc_uri(uri: &str) -> Result<(&ServerName, &str)> {
+    let invalid = || Error::InvalidMxcUri(uri.to_owned());
+
+    let (server_name, media_id) = uri
+        .strip_prefix("mxc://")
+        .and_then(|rest| {
+            let mut parts = rest.splitn(2, '/');
+            Some((parts.next()?, parts.next()?))
+        })
+        .ok_or_else(invalid)?;
+
+    if media_id.is_empty() || media_id.contains('/') {
+        return Err(invalid());
+    }
+
+    let server_name: &ServerName = server_name.try_into().map_err(|_| invalid())?;
+
+    Ok((server_name, media_id))
+}
 
 /// An async/await enabled Matrix client.
 ///
@@ -143,6 +207,15 @@ pub struct Client {
     http_client: HttpClient,
     /// User session data.
     pub(crate) base_client: BaseClient,
+    /// The custom generator for transaction ids, if one was configured.
+    transaction_id_generator: Option<Arc<dyn TransactionIdGenerator>>,
+    /// The cached support information of the homeserver and the time it was
+    /// fetched.
+    support_info: Arc<RwLock<Option<(Instant, Option<SupportInfo>)>>>,
+    /// The cached profiles of other users.
+    profile_cache: ProfileCache,
+    /// The display name new devices get if the login doesn't set one.
+    default_device_name: Option<String>,
     /// Locks making sure we only have one group session sharing request in
     /// flight per room.
     #[cfg(feature = "encryption")]
@@ -159,6 +232,302 @@ impl Debug for Client {
     }
 }
 
+/// A generator for the transaction ids of requests that need one, e.g. when
+/// sending a message event.
+///
+/// By default a random UUID is used for every request. Appservices and bridges
+/// that need deterministic or externally coordinated ids can provide their own
+/// implementation using [`ClientConfig::transaction_id_generator`].
+///
+/// An id is only generated if the caller doesn't provide one. To retry a
+/// request the caller should reuse the id of the first attempt, the homeserver
+/// will then deduplicate the request.
+///
+/// [`ClientConfig::transaction_id_generator`]: struct.ClientConfig.html#method.transaction_id_generator
+pub trait TransactionIdGenerator: AsyncTraitDeps {
+    /// Generate a new transaction id, it needs to be unique for the access
+    /// token of the client.
+    fn generate(&self) -> String;
+}
+
+/// Configuration for a single request.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::{
+///     instant::{Duration, Instant},
+///     RequestConfig,
+/// };
+///
+/// // The request, including the time spent waiting for the homeserver, needs
+/// // to finish within the next five seconds.
+/// let request_config = RequestConfig::new().deadline(Instant::now() + Duration::from_secs(5));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RequestConfig {
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) disable_timeout: bool,
+    pub(crate) headers: HeaderMap,
+    pub(crate) skip_rate_limit_retry: bool,
+}
+
+impl RequestConfig {
+    /// Create a new default `RequestConfig`.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set a deadline for the request.
+    ///
+    /// The deadline bounds the total time spent on the request, if no response
+    /// was received before the deadline passes the request fails with an
+    /// [`Error::DeadlineExceeded`](enum.Error.html#variant.DeadlineExceeded).
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set a timeout for a single attempt of the request, e.g. a larger one
+    /// for a long polling request.
+    ///
+    /// This replaces the timeout configured with
+    /// [`ClientConfig::request_timeout`] and applies on top of the one
+    /// configured with [`ClientConfig::timeout`]. If no response was received
+    /// within the timeout the request fails with an
+    /// [`Error::RequestTimeout`](enum.Error.html#variant.RequestTimeout), which
+    /// isn't retried.
+    ///
+    /// [`ClientConfig::request_timeout`]: struct.ClientConfig.html#method.request_timeout
+    /// [`ClientConfig::timeout`]: struct.ClientConfig.html#method.timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Don't apply the default request timeout to this request, e.g. for an
+    /// initial sync that can take arbitrarily long on large accounts.
+    pub(crate) fn without_timeout(mut self) -> Self {
+        self.disable_timeout = true;
+        self
+    }
+
+    /// Add a custom header to the request, e.g. a tenant identifier a proxy
+    /// in front of the homeserver needs.
+    ///
+    /// The header replaces a header with the same name the request would
+    /// have had otherwise, except for the `Authorization` header which is
+    /// never replaced.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::RequestConfig;
+    /// use http::{header::HeaderName, HeaderValue};
+    ///
+    /// let request_config = RequestConfig::new().header(
+    ///     HeaderName::from_static("x-tenant-id"),
+    ///     HeaderValue::from_static("example"),
+    /// );
+    /// ```
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    /// Set if the request should wait and retry when the homeserver is rate
+    /// limiting us or is temporarily unavailable. The default is to retry.
+    ///
+    /// This takes precedence over the [`RetryConfig`] of the client, if it's
+    /// disabled a `429 Too Many Requests` or `503 Service Unavailable`
+    /// response is returned as an error right away, with the time the
+    /// homeserver asked us to wait available from [`Error::retry_after`].
+    /// Other failures are still retried according to the `RetryConfig`.
+    ///
+    /// This is useful for interactive actions where the user would rather
+    /// retry manually than wait.
+    ///
+    /// [`RetryConfig`]: struct.RetryConfig.html
+    /// [`Error::retry_after`]: enum.Error.html#method.retry_after
+    pub fn retry_on_rate_limit(mut self, retry: bool) -> Self {
+        self.skip_rate_limit_retry = !retry;
+        self
+    }
+}
+
+/// Policy for retrying requests that failed because the homeserver is rate
+/// limiting us or temporarily unavailable.
+///
+/// Requests wait for the time the homeserver asked for in the
+/// `retry_after_ms` field of the error or in the `Retry-After` header, if it
+/// didn't ask for a specific time the wait time grows exponentially with
+/// every attempt.
+///
+/// `GET` requests are retried on any server error or connection failure,
+/// other requests only on `429 Too Many Requests` and `503 Service
+/// Unavailable` responses so they can't end up being processed twice. Use
+/// [`retryable_paths`](#method.retryable_paths) to choose the endpoints that
+/// are safe to retry yourself.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::{instant::Duration, ClientConfig, RetryConfig};
+///
+/// let retry_config = RetryConfig::new()
+///     .max_retries(5)
+///     .max_total_wait(Duration::from_secs(120));
+///
+/// let client_config = ClientConfig::new().retry_config(retry_config);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RetryConfig {
+    pub(crate) max_retries: u32,
+    pub(crate) max_total_wait: Duration,
+    pub(crate) initial_delay: Duration,
+    pub(crate) max_delay: Duration,
+    pub(crate) retryable_paths: Option<Vec<String>>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            max_total_wait: Duration::from_secs(60),
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            retryable_paths: None,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Create a new default `RetryConfig`.
+    ///
+    /// By default a request is retried up to 3 times and waits at most 60
+    /// seconds in total.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set how often a request is retried at most.
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Set how long a request waits for retries in total at most.
+    ///
+    /// If the next retry would exceed this, the last error is returned
+    /// instead. A deadline set with [`RequestConfig::deadline`] is respected
+    /// the same way.
+    ///
+    /// [`RequestConfig::deadline`]: struct.RequestConfig.html#method.deadline
+    pub fn max_total_wait(mut self, max_total_wait: Duration) -> Self {
+        self.max_total_wait = max_total_wait;
+        self
+    }
+
+    /// Set the time to wait before the first retry if the homeserver didn't
+    /// ask for a specific time, the time doubles on every further retry.
+    pub fn initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// Set the ceiling for the exponentially growing wait time.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Set the endpoints that are retried on server errors and connection
+    /// failures, no matter the method of the request.
+    ///
+    /// A request is retried if its path, without the
+    /// [`base_path`](struct.ClientConfig.html#method.base_path), is one of the
+    /// given paths or lies below one of them, e.g.
+    /// `/_matrix/client/r0/keys/query`. Paths are compared segment by segment,
+    /// so `/_matrix/client/r0/keys` doesn't match `/_matrix/client/r0/keys_x`.
+    /// This replaces the default of
+    /// retrying `GET` requests, so a `GET` request to an endpoint that isn't
+    /// in the list isn't retried either. Rate limited and unavailable
+    /// responses are still retried for every endpoint.
+    pub fn retryable_paths<I, S>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.retryable_paths = Some(paths.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Check if server errors and connection failures of a request with the
+    /// given method and path, relative to the base path, are retried.
+    pub(crate) fn is_retryable(&self, method: &http::Method, path: &str) -> bool {
+        match &self.retryable_paths {
+            Some(paths) => paths.iter().any(|p| {
+                let p = p.trim_end_matches('/');
+
+                match path.strip_prefix(p) {
+                    Some(rest) => rest.is_empty() || rest.starts_with('/'),
+                    None => false,
+                }
+            }),
+            None => method == http::Method::GET,
+        }
+    }
+
+    /// Get the time to wait before the given retry attempt, starting at 0.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial_delay
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        // Wait between half and the full delay so clients that got rate
+        // limited at the same time don't all retry at the same time.
+        let jitter = (Uuid::new_v4().as_u128() % 1000) as u32;
+
+        delay / 2 + delay / 2000 * jitter
+    }
+}
+
+/// Policy deciding which redirects the client follows.
+///
+/// The access token is never forwarded to a different origin, no matter
+/// which policy is used, and redirects that downgrade the scheme from `https`
+/// to `http` are always refused.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::{ClientConfig, RedirectPolicy};
+///
+/// let client_config = ClientConfig::new().redirect_policy(RedirectPolicy::SameOrigin);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Don't follow any redirects, the redirect response is returned as is.
+    None,
+    /// Only follow redirects that stay on the same host and port, other
+    /// redirects fail the request.
+    SameOrigin,
+    /// Follow redirects to other hosts as well, e.g. to a CDN serving media.
+    /// The `Authorization` header is stripped from the redirected request.
+    ///
+    /// This is the default.
+    CrossOrigin,
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::CrossOrigin
+    }
+}
+
 /// Configuration for the creation of the `Client`.
 ///
 /// When setting the `StateStore` it is up to the user to open/connect
@@ -179,11 +548,46 @@ impl Debug for Client {
 pub struct ClientConfig {
     #[cfg(not(target_arch = "wasm32"))]
     pub(crate) proxy: Option<reqwest::Proxy>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) root_certificates: Vec<reqwest::Certificate>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) identity: Option<reqwest::Identity>,
+    #[cfg(all(feature = "rustls-tls", not(target_arch = "wasm32")))]
+    pub(crate) tls_hostname: Option<webpki::DNSName>,
+    #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+    pub(crate) gzip: bool,
+    #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+    pub(crate) max_decompressed_size: Option<usize>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) connect_timeout: Option<Duration>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) pool_idle_timeout: Option<Duration>,
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) pool_max_idle_per_host: Option<usize>,
     pub(crate) user_agent: Option<HeaderValue>,
     pub(crate) disable_ssl_verification: bool,
     pub(crate) base_config: BaseClientConfig,
     pub(crate) timeout: Option<Duration>,
     pub(crate) client: Option<Arc<dyn HttpSend>>,
+    pub(crate) media_timeout: Option<Duration>,
+    pub(crate) media_client: Option<Arc<dyn HttpSend>>,
+    pub(crate) session_lock_timeout: Option<Duration>,
+    pub(crate) request_timeout: Option<Duration>,
+    pub(crate) base_path: Option<String>,
+    pub(crate) retry_config: Option<RetryConfig>,
+    pub(crate) retry_handler: Option<Arc<dyn RetryHandler>>,
+    pub(crate) max_redirects: Option<usize>,
+    pub(crate) redirect_policy: RedirectPolicy,
+    pub(crate) profile_cache_ttl: Option<Duration>,
+    pub(crate) device_name_template: Option<String>,
+    pub(crate) transaction_id_generator: Option<Arc<dyn TransactionIdGenerator>>,
+    pub(crate) request_signer: Option<Arc<dyn RequestSigner>>,
+    pub(crate) response_rewrite: Option<Arc<ResponseRewrite>>,
+    pub(crate) interceptors: Vec<Arc<dyn RequestInterceptor>>,
+    pub(crate) capture_invalid_responses: bool,
+    pub(crate) token_refresh_handler: Option<Arc<dyn TokenRefreshHandler>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) endpoint_categories: Vec<(String, EndpointCategory)>,
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -192,10 +596,40 @@ impl Debug for ClientConfig {
         let mut res = fmt.debug_struct("ClientConfig");
 
         #[cfg(not(target_arch = "wasm32"))]
-        let res = res.field("proxy", &self.proxy);
+        let res = res
+            .field("proxy", &self.proxy)
+            .field("root_certificates", &self.root_certificates)
+            .field("identity", &self.identity)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("pool_idle_timeout", &self.pool_idle_timeout)
+            .field("pool_max_idle_per_host", &self.pool_max_idle_per_host);
+
+        #[cfg(all(feature = "rustls-tls", not(target_arch = "wasm32")))]
+        let res = res.field("tls_hostname", &self.tls_hostname);
+
+        #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+        let res = res
+            .field("gzip", &self.gzip)
+            .field("max_decompressed_size", &self.max_decompressed_size);
 
         res.field("user_agent", &self.user_agent)
             .field("disable_ssl_verification", &self.disable_ssl_verification)
+            .field("media_timeout", &self.media_timeout)
+            .field("session_lock_timeout", &self.session_lock_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("base_path", &self.base_path)
+            .field("retry_config", &self.retry_config)
+            .field("retry_handler", &self.retry_handler)
+            .field("max_redirects", &self.max_redirects)
+            .field("redirect_policy", &self.redirect_policy)
+            .field("profile_cache_ttl", &self.profile_cache_ttl)
+            .field("device_name_template", &self.device_name_template)
+            .field("transaction_id_generator", &self.transaction_id_generator)
+            .field("request_signer", &self.request_signer)
+            .field("response_rewrite", &self.response_rewrite)
+            .field("interceptors", &self.interceptors)
+            .field("capture_invalid_responses", &self.capture_invalid_responses)
+            .field("token_refresh_handler", &self.token_refresh_handler)
             .finish()
     }
 }
@@ -229,6 +663,169 @@ impl ClientConfig {
         Ok(self)
     }
 
+    /// Set the proxy through which all the HTTP requests should go and the
+    /// credentials used to authenticate to it using basic auth.
+    ///
+    /// The credentials are kept in the config, they are never logged and
+    /// aren't part of its `Debug` output.
+    ///
+    /// # Arguments
+    ///
+    /// * `proxy` - The HTTP URL of the proxy.
+    ///
+    /// * `username` - The username used to authenticate to the proxy.
+    ///
+    /// * `password` - The password used to authenticate to the proxy.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new()
+    ///     .proxy_with_auth("http://localhost:8080", "user", "secret")
+    ///     .unwrap();
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn proxy_with_auth(mut self, proxy: &str, username: &str, password: &str) -> Result<Self> {
+        self.proxy = Some(reqwest::Proxy::all(proxy)?.basic_auth(username, password));
+        Ok(self)
+    }
+
+    /// Add a certificate to the root certificates the certificate of the
+    /// server is checked against.
+    ///
+    /// This allows homeservers that use a certificate signed by a private
+    /// CA, or a self-signed certificate, without disabling SSL verification
+    /// completely. Can be called multiple times to add multiple certificates.
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate` - The root certificate that should be trusted.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use matrix_sdk::{reqwest::Certificate, ClientConfig};
+    ///
+    /// let pem = std::fs::read("my_ca.pem").unwrap();
+    /// let client_config = ClientConfig::new()
+    ///     .add_root_certificate(Certificate::from_pem(&pem).unwrap());
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn add_root_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.root_certificates.push(certificate);
+        self
+    }
+
+    /// Set the client certificate that is presented to the server.
+    ///
+    /// This is needed if the homeserver, or a reverse proxy in front of it,
+    /// requires mutual TLS authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `identity` - The certificate and private key of the client. With the
+    /// `native-tls` feature the identity needs to be created from a PKCS #12
+    /// archive, with the `rustls-tls` feature from PEM.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use matrix_sdk::{reqwest::Identity, ClientConfig};
+    ///
+    /// let archive = std::fs::read("client.p12").unwrap();
+    /// let client_config = ClientConfig::new()
+    ///     .identity(Identity::from_pkcs12_der(&archive, "password").unwrap());
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn identity(mut self, identity: reqwest::Identity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Set the hostname the TLS certificate of the server is checked against.
+    ///
+    /// By default the certificate needs to be valid for the host of the URL
+    /// that is connected to. This allows connecting to a homeserver using an
+    /// IP address or a different internal name while still verifying its
+    /// certificate. Requests to a server whose certificate isn't valid for
+    /// the hostname fail with [`Error::HostnameMismatch`].
+    ///
+    /// Only the default root certificates are trusted if this is set, it
+    /// can't be combined with certificates added with
+    /// [`add_root_certificate`] or a client [`identity`]. Creating a client
+    /// with such a config fails with an [`Error::UnsupportedTlsConfig`].
+    ///
+    /// # Arguments
+    ///
+    /// * `hostname` - The name the certificate needs to be valid for.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new()
+    ///     .tls_hostname("matrix.example.org")
+    ///     .unwrap();
+    /// ```
+    ///
+    /// [`Error::HostnameMismatch`]: enum.Error.html#variant.HostnameMismatch
+    /// [`Error::UnsupportedTlsConfig`]: enum.Error.html#variant.UnsupportedTlsConfig
+    /// [`add_root_certificate`]: #method.add_root_certificate
+    /// [`identity`]: #method.identity
+    #[cfg(all(feature = "rustls-tls", not(target_arch = "wasm32")))]
+    pub fn tls_hostname(mut self, hostname: &str) -> Result<Self> {
+        self.tls_hostname = Some(
+            crate::tls::parse_hostname(hostname)
+                .ok_or_else(|| Error::InvalidHostname(hostname.to_owned()))?,
+        );
+        Ok(self)
+    }
+
+    /// Ask the homeserver to gzip compress its responses. The default is to
+    /// request uncompressed responses.
+    ///
+    /// JSON compresses well, this cuts down the bandwidth that large
+    /// responses, e.g. the ones of an initial `/sync` or of `/messages`,
+    /// need considerably, which is especially noticeable on mobile
+    /// connections. Compressed responses are transparently decompressed
+    /// before they are handed to Ruma.
+    ///
+    /// This is not used if a custom [`client`](#method.client) is set. In the
+    /// browser the compression is negotiated by the browser itself.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new().gzip(true);
+    /// ```
+    #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+    #[cfg_attr(feature = "docs", doc(cfg(gzip)))]
+    pub fn gzip(mut self, enable: bool) -> Self {
+        self.gzip = enable;
+        self
+    }
+
+    /// Set the maximal size in bytes a response body may have after it was
+    /// decompressed. The default is 256 MiB.
+    ///
+    /// A small compressed response can decompress to a huge body, this limit
+    /// stops reading the response before it exhausts our memory and fails the
+    /// request with [`Error::DecompressionLimitExceeded`]. It only applies
+    /// if [`gzip`](#method.gzip) is enabled, and not to media downloads.
+    ///
+    /// [`Error::DecompressionLimitExceeded`]: enum.Error.html#variant.DecompressionLimitExceeded
+    #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+    #[cfg_attr(feature = "docs", doc(cfg(gzip)))]
+    pub fn max_decompressed_size(mut self, size: usize) -> Self {
+        self.max_decompressed_size = Some(size);
+        self
+    }
+
     /// Disable SSL verification for the HTTP requests.
     pub fn disable_ssl_verification(mut self) -> Self {
         self.disable_ssl_verification = true;
@@ -279,52 +876,377 @@ impl ClientConfig {
     }
 
     /// Set a timeout duration for all HTTP requests. The default is no timeout.
+    ///
+    /// This applies to long polling syncs and media transfers as well, the
+    /// [`request_timeout`](#method.request_timeout) is usually a better fit.
     pub fn timeout(mut self, timeout: Duration) -> Self {
         self.timeout = Some(timeout);
         self
     }
 
-    /// Specify a client to handle sending requests and receiving responses.
-    ///
-    /// Any type that implements the `HttpSend` trait can be used to send/receive
-    /// `http` types.
-    pub fn client(mut self, client: Arc<dyn HttpSend>) -> Self {
-        self.client = Some(client);
+    /// Set the default timeout for a single attempt of an API request, if no
+    /// response was received within it the request fails with an
+    /// [`Error::RequestTimeout`]. The default is 60 seconds.
+    ///
+    /// Syncs get the time the homeserver is allowed to wait for new events on
+    /// top of this timeout. Syncs without such a time, e.g. the initial sync,
+    /// aren't bound by it since the homeserver might need a lot longer to
+    /// assemble the response for a large account. Media transfers aren't
+    /// bound by it either, see
+    /// [`media_timeout`](#method.media_timeout) for those. A single request
+    /// can override it with [`RequestConfig::timeout`].
+    ///
+    /// [`Error::RequestTimeout`]: enum.Error.html#variant.RequestTimeout
+    /// [`RequestConfig::timeout`]: struct.RequestConfig.html#method.timeout
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
         self
     }
-}
-
-#[derive(Debug, Default, Clone)]
-/// Settings for a sync call.
-pub struct SyncSettings<'a> {
-    pub(crate) filter: Option<sync_events::Filter<'a>>,
-    pub(crate) timeout: Option<Duration>,
-    pub(crate) token: Option<String>,
-    pub(crate) full_state: bool,
-}
 
-impl<'a> SyncSettings<'a> {
-    /// Create new default sync settings.
-    pub fn new() -> Self {
-        Default::default()
+    /// Set a timeout for establishing a connection to the homeserver,
+    /// independent of the timeout of the whole request. The default is no
+    /// timeout.
+    ///
+    /// This is not used if a custom [`client`](#method.client) is set.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
     }
 
-    /// Set the sync token.
+    /// Set how long idle connections are kept in the connection pool before
+    /// they are closed. The default is 90 seconds.
     ///
-    /// # Arguments
+    /// A long-running sync loop keeps its connection busy, but connections
+    /// used for other requests, e.g. sending messages, are reused only while
+    /// they are in the pool. Raising the timeout avoids repeated TLS
+    /// handshakes if requests are sent infrequently.
     ///
-    /// * `token` - The sync token that should be used for the sync call.
-    pub fn token(mut self, token: impl Into<String>) -> Self {
-        self.token = Some(token.into());
+    /// This is not used if a custom [`client`](#method.client) is set.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = Some(timeout);
         self
     }
 
-    /// Set the maximum time the server can wait, in milliseconds, before
-    /// responding to the sync request.
-    ///
-    /// # Arguments
+    /// Set the maximal number of idle connections per host that are kept in
+    /// the connection pool. The default is no limit.
     ///
-    /// * `timeout` - The time the server is allowed to wait.
+    /// This is not used if a custom [`client`](#method.client) is set.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn pool_max_idle_per_host(mut self, max: usize) -> Self {
+        self.pool_max_idle_per_host = Some(max);
+        self
+    }
+
+    /// Specify a client to handle sending requests and receiving responses.
+    ///
+    /// Any type that implements the `HttpSend` trait can be used to send/receive
+    /// `http` types.
+    pub fn client(mut self, client: Arc<dyn HttpSend>) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Set a timeout duration for media transfers, e.g. uploads. The default
+    /// is to use the same timeout as for all the other requests.
+    ///
+    /// Media transfers can take a lot longer than normal API calls, setting a
+    /// media timeout makes the client use a separate HTTP client for them so
+    /// the API calls can keep a short timeout.
+    ///
+    /// This is not used if a custom [`media_client`](#method.media_client) is
+    /// set.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new()
+    ///     .timeout(Duration::from_secs(30))
+    ///     .media_timeout(Duration::from_secs(10 * 60));
+    /// ```
+    pub fn media_timeout(mut self, timeout: Duration) -> Self {
+        self.media_timeout = Some(timeout);
+        self
+    }
+
+    /// Specify a client to handle media transfers, e.g. uploads.
+    ///
+    /// By default media transfers use the same client as all the other
+    /// requests.
+    pub fn media_client(mut self, client: Arc<dyn HttpSend>) -> Self {
+        self.media_client = Some(client);
+        self
+    }
+
+    /// Set how long a request waits to get hold of the session before it fails
+    /// with an [`Error::SessionLockTimeout`]. The default is 30 seconds.
+    ///
+    /// [`Error::SessionLockTimeout`]: enum.Error.html#variant.SessionLockTimeout
+    pub fn session_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.session_lock_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the template for the display name of new devices that log in
+    /// without an explicit display name.
+    ///
+    /// See [`device_display_name`] for the supported placeholders. By default
+    /// no display name is set and the homeserver picks one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new().device_name_template("My App on {os} ({hostname})");
+    /// ```
+    ///
+    /// [`device_display_name`]: fn.device_display_name.html
+    pub fn device_name_template(mut self, template: impl Into<String>) -> Self {
+        self.device_name_template = Some(template.into());
+        self
+    }
+
+    /// Set how long the profiles fetched with [`Client::get_profile`] are
+    /// cached. The default is 60 seconds.
+    ///
+    /// [`Client::get_profile`]: struct.Client.html#method.get_profile
+    pub fn profile_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.profile_cache_ttl = Some(ttl);
+        self
+    }
+
+    /// Set a path prefix under which the Matrix API of the homeserver is
+    /// served.
+    ///
+    /// This is needed if the homeserver is reverse-proxied under a sub-path,
+    /// e.g. `https://example.org/matrix/_matrix/client/...`. The prefix is
+    /// inserted between the homeserver URL and the endpoint path of every
+    /// request, the URL returned by [`Client::homeserver`] is left untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_path` - The path prefix, e.g. `/matrix`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::ClientConfig;
+    ///
+    /// let client_config = ClientConfig::new().base_path("/matrix");
+    /// ```
+    ///
+    /// [`Client::homeserver`]: struct.Client.html#method.homeserver
+    pub fn base_path(mut self, base_path: &str) -> Self {
+        self.base_path = Some(base_path.to_owned());
+        self
+    }
+
+    /// Retry requests that failed because the homeserver is rate limiting us
+    /// or temporarily unavailable.
+    ///
+    /// By default requests aren't retried, the error is returned right away.
+    pub fn retry_config(mut self, retry_config: RetryConfig) -> Self {
+        self.retry_config = Some(retry_config);
+        self
+    }
+
+    /// Set the handler that gets notified every time a request is retried,
+    /// e.g. to make retry storms visible in logs or dashboards.
+    ///
+    /// The handler is only called if retrying was enabled with
+    /// [`retry_config`](#method.retry_config).
+    pub fn retry_handler(mut self, handler: Arc<dyn RetryHandler>) -> Self {
+        self.retry_handler = Some(handler);
+        self
+    }
+
+    /// Set the maximum number of redirects that will be followed for a single
+    /// request. The default is 10.
+    ///
+    /// The access token is never forwarded if a redirect leads to a different
+    /// host or port, redirects that downgrade the scheme from `https` to
+    /// `http` are refused.
+    ///
+    /// This is not used if a custom [`client`](#method.client) is set.
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = Some(max_redirects);
+        self
+    }
+
+    /// Set which redirects the client follows. The default is to follow
+    /// redirects to other hosts without forwarding the access token, see
+    /// [`RedirectPolicy`] for the alternatives.
+    ///
+    /// This is not used if a custom [`client`](#method.client) is set.
+    ///
+    /// [`RedirectPolicy`]: enum.RedirectPolicy.html
+    pub fn redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.redirect_policy = policy;
+        self
+    }
+
+    /// Set a custom generator for the transaction ids the client uses.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use std::sync::{
+    ///     atomic::{AtomicU64, Ordering},
+    ///     Arc,
+    /// };
+    /// use matrix_sdk::{ClientConfig, TransactionIdGenerator};
+    ///
+    /// #[derive(Debug, Default)]
+    /// struct Counter(AtomicU64);
+    ///
+    /// impl TransactionIdGenerator for Counter {
+    ///     fn generate(&self) -> String {
+    ///         format!("bridge-{}", self.0.fetch_add(1, Ordering::SeqCst))
+    ///     }
+    /// }
+    ///
+    /// let client_config =
+    ///     ClientConfig::new().transaction_id_generator(Arc::new(Counter::default()));
+    /// ```
+    pub fn transaction_id_generator(mut self, generator: Arc<dyn TransactionIdGenerator>) -> Self {
+        self.transaction_id_generator = Some(generator);
+        self
+    }
+
+    /// Set a signer that adds proof headers, e.g. a `DPoP` header, to every
+    /// request the client sends out.
+    ///
+    /// By default requests are only authenticated using the access token.
+    pub fn request_signer(mut self, signer: Arc<dyn RequestSigner>) -> Self {
+        self.request_signer = Some(signer);
+        self
+    }
+
+    /// Set a hook that rewrites the bodies of responses before they are
+    /// deserialized, e.g. to work around a buggy homeserver version.
+    ///
+    /// The rewriter runs on the hot path of every matching request and should
+    /// be cheap.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoints` - Path fragments of the endpoints whose responses should
+    /// be rewritten, e.g. `/devices`. The rewriter never sees the responses of
+    /// other endpoints.
+    ///
+    /// * `rewriter` - The rewriter the response bodies are passed to.
+    pub fn response_rewriter(
+        mut self,
+        endpoints: Vec<String>,
+        rewriter: Arc<dyn ResponseRewriter>,
+    ) -> Self {
+        self.response_rewrite = Some(Arc::new(ResponseRewrite {
+            endpoints,
+            rewriter,
+        }));
+        self
+    }
+
+    /// Add a hook that observes every request the client sends out and its
+    /// response.
+    ///
+    /// Interceptors can be used for logging or latency tracking, or to add
+    /// headers to requests. They run in the order they were added.
+    pub fn add_interceptor(mut self, interceptor: Arc<dyn RequestInterceptor>) -> Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// Keep the raw body of responses that can't be deserialized around in
+    /// the returned [`Error::ResponseDeserialization`]. The default is to
+    /// discard it.
+    ///
+    /// This helps with debugging homeservers that send slightly off JSON, but
+    /// every response body needs to be copied before it's deserialized, it
+    /// shouldn't be enabled in production.
+    ///
+    /// [`Error::ResponseDeserialization`]: enum.Error.html#variant.ResponseDeserialization
+    pub fn capture_invalid_responses(mut self, capture: bool) -> Self {
+        self.capture_invalid_responses = capture;
+        self
+    }
+
+    /// Set the handler that gets notified when the access token of the
+    /// session was refreshed.
+    ///
+    /// If the session contains a refresh token, requests that fail because
+    /// the access token expired, i.e. with a `M_UNKNOWN_TOKEN` error that has
+    /// `soft_logout` set, are retried once after the token was refreshed. The
+    /// handler should persist the refreshed session, otherwise restoring the
+    /// old one will fail.
+    pub fn token_refresh_handler(mut self, handler: Arc<dyn TokenRefreshHandler>) -> Self {
+        self.token_refresh_handler = Some(handler);
+        self
+    }
+
+    /// Put the endpoints whose path contains the given fragment into the
+    /// given category when the bandwidth usage is aggregated.
+    ///
+    /// Custom categories take precedence over the built-in ones, the first
+    /// matching fragment wins.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use matrix_sdk::{ClientConfig, EndpointCategory};
+    ///
+    /// let client_config = ClientConfig::new()
+    ///     .endpoint_category("/messages", EndpointCategory::Custom("history".to_owned()));
+    /// ```
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+    pub fn endpoint_category(
+        mut self,
+        path_fragment: impl Into<String>,
+        category: EndpointCategory,
+    ) -> Self {
+        self.endpoint_categories
+            .push((path_fragment.into(), category));
+        self
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+/// Settings for a sync call.
+pub struct SyncSettings<'a> {
+    pub(crate) filter: Option<sync_events::Filter<'a>>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) token: Option<String>,
+    pub(crate) full_state: bool,
+}
+
+impl<'a> SyncSettings<'a> {
+    /// Create new default sync settings.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Set the sync token.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - The sync token that should be used for the sync call.
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Set the maximum time the server can wait, in milliseconds, before
+    /// responding to the sync request.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - The time the server is allowed to wait.
     pub fn timeout(mut self, timeout: Duration) -> Self {
         self.timeout = Some(timeout);
         self
@@ -382,25 +1304,77 @@ impl Client {
             panic!("Error parsing homeserver url")
         };
 
-        let client = if let Some(client) = config.client {
+        let (client, default_media_client): (Arc<dyn HttpSend>, Arc<dyn HttpSend>) =
+            if let Some(client) = config.client.clone() {
+                (client.clone(), client)
+            } else {
+                let client = client_with_config(&config, config.timeout)?;
+                // Media downloads can legitimately be a lot bigger than API
+                // responses, don't limit them.
+                let media_client = client.clone().without_body_limit();
+                (Arc::new(client), Arc::new(media_client))
+            };
+
+        let media_client: Arc<dyn HttpSend> = if let Some(client) = config.media_client.clone() {
             client
+        } else if let Some(timeout) = config.media_timeout {
+            Arc::new(client_with_config(&config, Some(timeout))?.without_body_limit())
         } else {
-            Arc::new(client_with_config(&config)?)
+            default_media_client
         };
 
         let base_client = BaseClient::new_with_config(config.base_config)?;
         let session = base_client.session().clone();
 
+        let api_base = match config.base_path.as_deref() {
+            Some(base_path) => Arc::new(join_base_path(&homeserver, base_path)),
+            None => homeserver.clone(),
+        };
+
         let http_client = HttpClient {
-            homeserver: homeserver.clone(),
+            homeserver: api_base,
             inner: client,
+            media: media_client,
             session,
+            session_lock_timeout: config
+                .session_lock_timeout
+                .unwrap_or(DEFAULT_SESSION_LOCK_TIMEOUT),
+            request_timeout: config.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            clock_skew: Arc::new(RwLock::new(None)),
+            pending_requests: PendingRequests::default(),
+            last_timing: Arc::new(SyncMutex::new(None)),
+            signer: config
+                .request_signer
+                .clone()
+                .unwrap_or_else(|| Arc::new(NoopSigner)),
+            signing_nonce: Arc::new(SyncMutex::new(None)),
+            health: HealthTracker::default(),
+            response_rewrite: config.response_rewrite.clone(),
+            interceptors: config.interceptors.clone(),
+            capture_invalid_responses: config.capture_invalid_responses,
+            refresh_lock: Arc::new(matrix_sdk_common::locks::Mutex::new(())),
+            token_refresh_handler: config.token_refresh_handler.clone(),
+            retry: config.retry_config.clone(),
+            retry_handler: config.retry_handler.clone(),
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(config.endpoint_categories.clone()),
         };
 
         Ok(Self {
             homeserver,
             http_client,
             base_client,
+            transaction_id_generator: config.transaction_id_generator,
+            support_info: Arc::new(RwLock::new(None)),
+            profile_cache: ProfileCache::new(
+                config
+                    .profile_cache_ttl
+                    .unwrap_or(DEFAULT_PROFILE_CACHE_TTL),
+            ),
+            default_device_name: config
+                .device_name_template
+                .as_deref()
+                .map(device_display_name),
             #[cfg(feature = "encryption")]
             group_session_locks: DashMap::new(),
             #[cfg(feature = "encryption")]
@@ -413,11 +1387,109 @@ impl Client {
         self.base_client.logged_in().await
     }
 
+    /// Subscribe to changes of the session.
+    ///
+    /// The returned stream yields the current session state right away and
+    /// then a new item every time the client logs in, restores a session or
+    /// logs out, `None` means that the client isn't logged in. The items never
+    /// contain the access token.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, StreamExt};
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let mut changes = client.session_changes().await;
+    ///
+    /// while let Some(session) = changes.next().await {
+    ///     match session {
+    ///         Some(session) => println!("Logged in as {}", session.user_id),
+    ///         None => println!("Logged out"),
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn session_changes(&self) -> UnboundedReceiver<Option<SessionInfo>> {
+        self.base_client.session_changes().await
+    }
+
     /// The Homeserver of the client.
     pub fn homeserver(&self) -> &Url {
         &self.homeserver
     }
 
+    /// Get the support information the homeserver publishes in its
+    /// `/.well-known/matrix/support` document.
+    ///
+    /// Returns `None` if the homeserver doesn't publish the document or if it
+    /// isn't a valid support document, e.g. because a web server answers
+    /// with an HTML page instead. The information is cached for an hour.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// if let Some(support) = client.support_info().await.unwrap() {
+    ///     for contact in support.contacts {
+    ///         println!("{}: {:?}", contact.role, contact.email_address);
+    ///     }
+    /// }
+    /// # });
+    /// ```
+    pub async fn support_info(&self) -> Result<Option<SupportInfo>> {
+        if let Some((fetched, info)) = &*self.support_info.read().await {
+            if fetched.elapsed() < SUPPORT_INFO_CACHE_DURATION {
+                return Ok(info.clone());
+            }
+        }
+
+        let response = self
+            .http_client
+            .get_document("/.well-known/matrix/support")
+            .await?;
+
+        let info = if response.status().is_success() {
+            match serde_json::from_slice(response.body()) {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    warn!(
+                        "The homeserver published an invalid support document: {}",
+                        e
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        *self.support_info.write().await = Some((Instant::now(), info.clone()));
+
+        Ok(info)
+    }
+
+    /// Get the difference between the local clock and the clock of the
+    /// homeserver.
+    ///
+    /// The difference is measured using the `Date` header of the last response
+    /// that contained one. A large difference usually means that the clock of
+    /// the device is wrong, which can explain otherwise puzzling errors.
+    ///
+    /// Returns `None` if no response with a `Date` header was received yet.
+    /// The clock skew isn't measured on WASM.
+    pub async fn clock_skew(&self) -> Option<Duration> {
+        self.http_client.clock_skew().await
+    }
+
     /// Get the user id of the current owner of the client.
     pub async fn user_id(&self) -> Option<UserId> {
         let session = self.base_client.session().read().await;
@@ -430,8 +1502,55 @@ impl Client {
         session.as_ref().map(|s| s.device_id.clone())
     }
 
+    /// Get the profile of the given user.
+    ///
+    /// Profiles are cached for a short time, see
+    /// [`ClientConfig::profile_cache_ttl`], and concurrent calls for the same
+    /// user share a single request. This makes it cheap to call for every
+    /// member of a room, e.g. when rendering a member list.
+    ///
+    /// Call [`invalidate_profile`] when the profile of a user changes, e.g.
+    /// on a membership event with a new display name or avatar.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::{Client, identifiers::user_id};
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// # let client = Client::new(homeserver).unwrap();
+    /// let alice = user_id!("@alice:example.org");
+    /// let profile = client.get_profile(&alice).await.unwrap();
+    ///
+    /// println!("Alice's display name is {:?}", profile.displayname);
+    /// # })
+    /// ```
+    ///
+    /// [`ClientConfig::profile_cache_ttl`]: struct.ClientConfig.html#method.profile_cache_ttl
+    /// [`invalidate_profile`]: #method.invalidate_profile
+    pub async fn get_profile(&self, user_id: &UserId) -> Result<get_profile::Response> {
+        self.profile_cache
+            .get_or_fetch(user_id, || {
+                self.http_client.send(get_profile::Request::new(user_id))
+            })
+            .await
+    }
+
+    /// Remove the cached profile of the given user, the next call to
+    /// [`get_profile`] fetches it again.
+    ///
+    /// [`get_profile`]: #method.get_profile
+    pub fn invalidate_profile(&self, user_id: &UserId) {
+        self.profile_cache.invalidate(user_id);
+    }
+
     /// Fetches the display name of the owner of the client.
     ///
+    /// Returns `None` if no display name is set, including the case where the
+    /// homeserver responds with a 404 because the profile doesn't exist, see
+    /// [`send_optional`](#method.send_optional).
+    ///
     /// # Example
     /// ```no_run
     /// # use futures::executor::block_on;
@@ -451,8 +1570,8 @@ impl Client {
     pub async fn display_name(&self) -> Result<Option<String>> {
         let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
         let request = get_display_name::Request::new(&user_id);
-        let response = self.send(request).await?;
-        Ok(response.displayname)
+        let response = self.send_optional(request).await?;
+        Ok(response.and_then(|r| r.displayname))
     }
 
     /// Sets the display name of the owner of the client.
@@ -480,6 +1599,9 @@ impl Client {
 
     /// Gets the mxc avatar url of the owner of the client, if set.
     ///
+    /// Like [`display_name`](#method.display_name), a 404 response from the
+    /// homeserver is treated as no avatar being set.
+    ///
     /// # Example
     /// ```no_run
     /// # use futures::executor::block_on;
@@ -499,8 +1621,8 @@ impl Client {
     pub async fn avatar_url(&self) -> Result<Option<String>> {
         let user_id = self.user_id().await.ok_or(Error::AuthenticationRequired)?;
         let request = get_avatar_url::Request::new(&user_id);
-        let response = self.send(request).await?;
-        Ok(response.avatar_url)
+        let response = self.send_optional(request).await?;
+        Ok(response.and_then(|r| r.avatar_url))
     }
 
     /// Get a reference to the store.
@@ -629,6 +1751,10 @@ impl Client {
     ///     device_id from a previous login call. Note that this should be done
     ///     only if the client also holds the encryption keys for this device.
     ///
+    /// * `initial_device_display_name` - The display name of the device if a
+    ///     new one is created. If not given the name is composed from the
+    ///     [`device_name_template`], if one is configured.
+    ///
     /// # Example
     /// ```no_run
     /// # use std::convert::TryFrom;
@@ -651,6 +1777,7 @@ impl Client {
     /// ```
     ///
     /// [`restore_login`]: #method.restore_login
+    /// [`device_name_template`]: struct.ClientConfig.html#method.device_name_template
     #[instrument(skip(password))]
     pub async fn login(
         &self,
@@ -667,7 +1794,8 @@ impl Client {
                 login::LoginInfo::Password { password },
             ), {
                 device_id: device_id.map(|d| d.into()),
-                initial_device_display_name,
+                initial_device_display_name: initial_device_display_name
+                    .or_else(|| self.default_device_name.as_deref()),
             }
         );
 
@@ -695,20 +1823,112 @@ impl Client {
         Ok(self.base_client.restore_login(session).await?)
     }
 
-    /// Register a user to the server.
+    /// Log out of the current session.
     ///
-    /// # Arguments
+    /// This invalidates the access token on the homeserver and afterwards
+    /// clears the session of the client, the client will be in a logged out
+    /// state even if the homeserver didn't know about the access token
+    /// anymore.
     ///
-    /// * `registration` - The easiest way to create this request is using the `register::Request`
-    /// itself.
+    /// Data that was persisted in the stores, e.g. the encryption keys, will
+    /// not be removed, [`logout_and_wipe`] removes the encryption keys as
+    /// well.
     ///
+    /// [`logout_and_wipe`]: #method.logout_and_wipe
     ///
-    /// # Examples
+    /// # Example
     /// ```no_run
-    /// # use std::convert::TryFrom;
+    /// # use futures::executor::block_on;
     /// # use matrix_sdk::Client;
-    /// # use matrix_sdk::api::r0::account::register::{Request as RegistrationRequest, RegistrationKind};
-    /// # use matrix_sdk::api::r0::uiaa::AuthData;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # block_on(async {
+    /// let client = Client::new(homeserver).unwrap();
+    /// client.login("example", "wordpass", None, None).await.unwrap();
+    ///
+    /// client.logout().await.expect("Can't log out");
+    /// assert!(!client.logged_in().await);
+    /// # })
+    /// ```
+    pub async fn logout(&self) -> Result<()> {
+        let request = logout::Request::new();
+        let response = self.send(request).await.map(|_| ());
+
+        self.finish_logout(response).await
+    }
+
+    /// Log out of all the sessions of the current user.
+    ///
+    /// This invalidates all the access tokens of the user, including the one
+    /// of this client, on the homeserver. The session of this client will be
+    /// cleared the same way [`logout`] does.
+    ///
+    /// [`logout`]: #method.logout
+    pub async fn logout_all(&self) -> Result<()> {
+        let request = logout_all::Request::new();
+        let response = self.send(request).await.map(|_| ());
+
+        self.finish_logout(response).await
+    }
+
+    /// Log out of the current session and wipe the crypto store.
+    ///
+    /// This logs out the same way [`logout`] does and then removes all the
+    /// data of the crypto store, e.g. the encryption keys of the device. This
+    /// should be used if the device won't log in again, the encrypted history
+    /// that was only readable by this device can't be decrypted anymore.
+    ///
+    /// The crypto store isn't touched if logging out failed.
+    ///
+    /// [`logout`]: #method.logout
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn logout_and_wipe(&self) -> Result<()> {
+        let olm = self.base_client.olm_machine().await;
+
+        self.logout().await?;
+
+        if let Some(olm) = olm {
+            olm.clear_store().await?;
+        }
+
+        Ok(())
+    }
+
+    async fn finish_logout(&self, response: Result<()>) -> Result<()> {
+        if let Err(e) = response {
+            let invalidated = matches!(e, Error::SoftLogout { .. })
+                || matches!(
+                    e.client_api_error_kind(),
+                    Some(ErrorKind::UnknownToken { .. })
+                );
+
+            if invalidated {
+                info!("The access token was already invalidated, clearing the session");
+            } else {
+                return Err(e);
+            }
+        }
+
+        self.base_client.clear_session().await;
+
+        Ok(())
+    }
+
+    /// Register a user to the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `registration` - The easiest way to create this request is using the `register::Request`
+    /// itself.
+    ///
+    ///
+    /// # Examples
+    /// ```no_run
+    /// # use std::convert::TryFrom;
+    /// # use matrix_sdk::Client;
+    /// # use matrix_sdk::api::r0::account::register::{Request as RegistrationRequest, RegistrationKind};
+    /// # use matrix_sdk::api::r0::uiaa::AuthData;
     /// # use matrix_sdk::identifiers::DeviceId;
     /// # use matrix_sdk_common::assign;
     /// # use futures::executor::block_on;
@@ -736,6 +1956,70 @@ impl Client {
         self.send(request).await
     }
 
+    /// Find out what the homeserver requires to register a new account.
+    ///
+    /// This probes the homeserver with an empty registration request, the
+    /// homeserver answers it with the UIAA flows that can be used to
+    /// register. This allows showing the right registration form, e.g. one
+    /// asking for a registration token, before the user fills it out.
+    ///
+    /// **Note**: A homeserver that allows registration without any
+    /// authentication and without a username or password accepts the probe
+    /// and registers an account with a generated username. The probe asks the
+    /// homeserver not to log the account in, so no device or access token is
+    /// created, but the account itself stays around. This is reported as
+    /// [`RegistrationRequirements::AccountCreated`] with the user id of the
+    /// account. Only probe homeservers the user actually wants to register
+    /// on.
+    ///
+    /// [`RegistrationRequirements::AccountCreated`]: enum.RegistrationRequirements.html#variant.AccountCreated
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use futures::executor::block_on;
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let requirements = client.registration_requirements().await.unwrap();
+    ///
+    /// if requirements.is_disabled() {
+    ///     println!("The homeserver doesn't allow registration");
+    /// } else if requirements.requires_registration_token() {
+    ///     println!("Please enter your registration token");
+    /// }
+    /// # });
+    /// ```
+    pub async fn registration_requirements(&self) -> Result<RegistrationRequirements> {
+        let request = assign!(register::Request::new(), { inhibit_login: true });
+
+        match self.send(request).await {
+            // The homeserver registered an account without requiring any
+            // authentication, the caller needs to know that it exists now.
+            Ok(response) => {
+                warn!(
+                    "The registration probe registered the account {}",
+                    response.user_id
+                );
+
+                Ok(RegistrationRequirements::AccountCreated {
+                    user_id: response.user_id,
+                })
+            }
+            Err(e) => {
+                if let Some(info) = e.uiaa_response() {
+                    Ok(RegistrationRequirements::from_uiaa_info(info))
+                } else if let Some(ErrorKind::Forbidden) = e.client_api_error_kind() {
+                    Ok(RegistrationRequirements::Disabled)
+                } else {
+                    Err(e)
+                }
+            }
+        }
+    }
+
     /// Get or upload a sync filter.
     pub async fn get_or_upload_filter(
         &self,
@@ -1207,7 +2491,8 @@ impl Client {
     ///
     /// * `txn_id` - A unique `Uuid` that can be attached to a `MessageEvent`
     /// held in its unsigned field as `transaction_id`. If not given one is
-    /// created for the message.
+    /// created for the message using the configured
+    /// [`TransactionIdGenerator`](trait.TransactionIdGenerator.html).
     ///
     /// # Example
     /// ```no_run
@@ -1257,7 +2542,9 @@ impl Client {
             content.into()
         };
 
-        let txn_id = txn_id.unwrap_or_else(Uuid::new_v4).to_string();
+        let txn_id = txn_id
+            .map(|t| t.to_string())
+            .unwrap_or_else(|| self.transaction_id());
         let request = send_message_event::Request::new(&room_id, &txn_id, &content);
 
         let response = self.send(request).await?;
@@ -1454,6 +2741,273 @@ impl Client {
         self.http_client.upload(request).await
     }
 
+    /// Upload media that is produced incrementally, e.g. a live recording.
+    ///
+    /// The total length doesn't need to be known up front, the content is
+    /// sent as it's produced by the stream using chunked transfer encoding.
+    /// If the stream yields an error the upload is aborted and the error is
+    /// returned.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `stream` - A stream producing the chunks of the media.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, stream};
+    /// # use matrix_sdk::{Client, bytes::Bytes};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let chunks = vec![Ok(Bytes::from("first chunk")), Ok(Bytes::from("second chunk"))];
+    ///
+    /// let response = client
+    ///     .upload_stream(&mime::APPLICATION_OCTET_STREAM, stream::iter(chunks))
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// println!("Recording URI: {}", response.content_uri);
+    /// # });
+    /// ```
+    pub async fn upload_stream(
+        &self,
+        content_type: &Mime,
+        stream: impl Stream<Item = IoResult<Bytes>> + Send + Sync + 'static,
+    ) -> Result<create_content::Response> {
+        let request = assign!(create_content::Request::new(Vec::new()), {
+            content_type: Some(content_type.essence_str()),
+        });
+
+        self.http_client
+            .upload_stream(request, Box::pin(stream))
+            .await
+    }
+
+    /// Upload media that is produced by a stream and report the progress of
+    /// the upload.
+    ///
+    /// This works like [`upload_stream`], the `progress` callback is called
+    /// every time a chunk of the stream was handed to the HTTP client.
+    ///
+    /// # Arguments
+    ///
+    /// * `content_type` - The type of the media, this will be used as the
+    /// content-type header.
+    ///
+    /// * `stream` - A stream producing the chunks of the media.
+    ///
+    /// * `total` - The size of the media in bytes, if it's known.
+    ///
+    /// * `progress` - Callback that receives the number of bytes that were
+    /// sent so far and the `total`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use futures::{executor::block_on, stream};
+    /// # use matrix_sdk::{Client, bytes::Bytes};
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let chunks = vec![Ok(Bytes::from("first chunk")), Ok(Bytes::from("second chunk"))];
+    ///
+    /// let response = client
+    ///     .upload_stream_with_progress(
+    ///         &mime::APPLICATION_OCTET_STREAM,
+    ///         stream::iter(chunks),
+    ///         Some(23),
+    ///         |sent, total| println!("Uploaded {} of {:?} bytes", sent, total),
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// println!("Media URI: {}", response.content_uri);
+    /// # });
+    /// ```
+    ///
+    /// [`upload_stream`]: #method.upload_stream
+    pub async fn upload_stream_with_progress(
+        &self,
+        content_type: &Mime,
+        stream: impl Stream<Item = IoResult<Bytes>> + Send + Sync + 'static,
+        total: Option<u64>,
+        mut progress: impl FnMut(u64, Option<u64>) + Send + Sync + 'static,
+    ) -> Result<create_content::Response> {
+        let mut sent = 0;
+
+        let stream = stream.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                sent += chunk.len() as u64;
+                progress(sent, total);
+            }
+        });
+
+        self.upload_stream(content_type, stream).await
+    }
+
+    /// Download the media with the given `mxc://` URI.
+    ///
+    /// The response contains the raw content of the media and its content
+    /// type, if the homeserver knows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The `mxc://` URI of the media, e.g. the `content_uri` of an
+    /// upload response.
+    ///
+    /// * `allow_remote` - Allow the homeserver to fetch the media from a
+    /// remote server if it isn't stored locally. If this is `false` remote
+    /// media results in an [`Error::MediaNotFound`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let response = client
+    ///     .download("mxc://example.com/AQwafuaFswefuhsfAFAgsw", true)
+    ///     .await
+    ///     .unwrap();
+    ///
+    /// println!("Got {} bytes of {:?}", response.file.len(), response.content_type);
+    /// # });
+    /// ```
+    ///
+    /// [`Error::MediaNotFound`]: enum.Error.html#variant.MediaNotFound
+    pub async fn download(&self, uri: &str, allow_remote: bool) -> Result<get_content::Response> {
+        let (server_name, media_id) = parse_mxc_uri(uri)?;
+
+        let request = assign!(get_content::Request::new(media_id, server_name), {
+            allow_remote,
+        });
+
+        self.http_client.download(request, uri).await
+    }
+
+    /// Download the media with the given `mxc://` URI and write it to
+    /// `writer`, returns the number of bytes that were written.
+    ///
+    /// Unlike [`download`](#method.download) the media is requested in chunks
+    /// using HTTP range requests. If a chunk fails because of a connection
+    /// problem, a timeout or a server error it's requested again, so the
+    /// download continues where it left off instead of starting from
+    /// scratch. If the homeserver asked us to wait before trying again, e.g.
+    /// with a `Retry-After` header, the chunk is only requested again once
+    /// that time passed. Other errors fail the download right away.
+    /// Homeservers that don't support range requests send the whole media at
+    /// once.
+    ///
+    /// The length of the downloaded media is checked against the length the
+    /// homeserver announced, a mismatch results in an
+    /// [`Error::InconsistentDownload`].
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The `mxc://` URI of the media.
+    ///
+    /// * `allow_remote` - Allow the homeserver to fetch the media from a
+    /// remote server if it isn't stored locally.
+    ///
+    /// * `writer` - The writer the media is written to, e.g. a file.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let mut file = std::fs::File::create("/tmp/video.mp4").unwrap();
+    ///
+    /// let size = client
+    ///     .download_to_writer("mxc://example.com/AQwafuaFswefuhsfAFAgsw", true, &mut file)
+    ///     .await
+    ///     .unwrap();
+    /// # });
+    /// ```
+    ///
+    /// [`Error::InconsistentDownload`]: enum.Error.html#variant.InconsistentDownload
+    pub async fn download_to_writer(
+        &self,
+        uri: &str,
+        allow_remote: bool,
+        writer: &mut impl Write,
+    ) -> Result<u64> {
+        let (server_name, media_id) = parse_mxc_uri(uri)?;
+
+        let request = assign!(get_content::Request::new(media_id, server_name), {
+            allow_remote,
+        });
+
+        self.http_client
+            .download_to_writer(request, uri, writer)
+            .await
+    }
+
+    /// Download a thumbnail of the media with the given `mxc://` URI.
+    ///
+    /// The homeserver picks the closest thumbnail size it supports, the
+    /// thumbnail might be larger than requested.
+    ///
+    /// # Arguments
+    ///
+    /// * `uri` - The `mxc://` URI of the media.
+    ///
+    /// * `width` - The desired width of the thumbnail.
+    ///
+    /// * `height` - The desired height of the thumbnail.
+    ///
+    /// * `method` - How the media should be resized, cropped to the size or
+    /// scaled to fit into it.
+    ///
+    /// * `allow_remote` - Allow the homeserver to fetch the media from a
+    /// remote server if it isn't stored locally.
+    pub async fn thumbnail(
+        &self,
+        uri: &str,
+        width: u32,
+        height: u32,
+        method: get_content_thumbnail::Method,
+        allow_remote: bool,
+    ) -> Result<get_content_thumbnail::Response> {
+        let (server_name, media_id) = parse_mxc_uri(uri)?;
+
+        let request = assign!(
+            get_content_thumbnail::Request::new(
+                media_id,
+                server_name,
+                UInt::from(width),
+                UInt::from(height),
+            ),
+            {
+                method: Some(method),
+                allow_remote,
+            }
+        );
+
+        self.http_client.download(request, uri).await
+    }
+
+    /// Get a new transaction id from the configured generator.
+    fn transaction_id(&self) -> String {
+        match &self.transaction_id_generator {
+            Some(generator) => generator.generate(),
+            None => Uuid::new_v4().to_string(),
+        }
+    }
+
     /// Send an arbitrary request to the server, without updating client state.
     ///
     /// **Warning:** Because this method *does not* update the client state, it is
@@ -1499,34 +3053,339 @@ impl Client {
         self.http_client.send(request).await
     }
 
-    #[cfg(feature = "encryption")]
-    pub(crate) async fn send_to_device(
+    /// Send an arbitrary request to the server using the given request
+    /// configuration, without updating client state.
+    ///
+    /// The same warning as for [`send`](#method.send) applies.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A filled out and valid request for the endpoint to be hit
+    ///
+    /// * `config` - The configuration that should be used for this request,
+    /// e.g. the deadline until which a response needs to be received.
+    pub async fn send_with_config<Request>(
         &self,
-        request: &ToDeviceRequest,
-    ) -> Result<ToDeviceResponse> {
-        let txn_id_string = request.txn_id_string();
-        let request = RumaToDeviceRequest::new(
-            request.event_type.clone(),
-            &txn_id_string,
-            request.messages.clone(),
-        );
-
-        self.send(request).await
+        request: Request,
+        config: RequestConfig,
+    ) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest + Debug,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.http_client.send_with_config(request, config).await
     }
 
-    /// Get information of all our own devices.
+    /// Send an arbitrary request to the server, without updating client
+    /// state, and get a handle that can be used to cancel it.
     ///
-    /// # Examples
+    /// The same warning as for [`send`](#method.send) applies.
+    ///
+    /// Once [`AbortHandle::abort`] is called the in-flight request is dropped
+    /// and the returned future resolves to an [`Error::Cancelled`] error,
+    /// this can be used to stop a long-polling request, e.g. a sync, when the
+    /// application shuts down.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A filled out and valid request for the endpoint to be hit
+    ///
+    /// # Example
     ///
     /// ```no_run
-    /// # use matrix_sdk::{Client, SyncSettings};
+    /// # use matrix_sdk::{Client, Error};
     /// # use futures::executor::block_on;
     /// # use url::Url;
-    /// # use std::convert::TryFrom;
     /// # block_on(async {
     /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
-    /// # let mut client = Client::new(homeserver).unwrap();
-    /// let response = client.devices().await.expect("Can't get devices from server");
+    /// # let client = Client::new(homeserver).unwrap();
+    /// use matrix_sdk::api::r0::sync::sync_events;
+    ///
+    /// let (response, handle) = client.send_cancellable(sync_events::Request::new());
+    ///
+    /// // E.g. when the application shuts down.
+    /// handle.abort();
+    ///
+    /// assert!(matches!(response.await, Err(Error::Cancelled)));
+    /// # })
+    /// ```
+    ///
+    /// [`AbortHandle::abort`]: https://docs.rs/futures/0.3/futures/future/struct.AbortHandle.html#method.abort
+    /// [`Error::Cancelled`]: enum.Error.html#variant.Cancelled
+    pub fn send_cancellable<'a, Request>(
+        &'a self,
+        request: Request,
+    ) -> (
+        impl Future<Output = Result<Request::IncomingResponse>> + 'a,
+        AbortHandle,
+    )
+    where
+        Request: OutgoingRequest + Debug + 'a,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let (response, handle) = abortable(self.http_client.send(request));
+        let response = async move { response.await.unwrap_or_else(|_| Err(Error::Cancelled)) };
+
+        (response, handle)
+    }
+
+    /// Send an arbitrary request to the server, treating a 404 response as the
+    /// requested resource not being available.
+    ///
+    /// This is useful for optional endpoints, e.g. for feature detection,
+    /// where a 404 is an expected answer rather than an error. All other
+    /// errors are returned like [`send`](#method.send) returns them.
+    ///
+    /// The [`display_name`](#method.display_name) and
+    /// [`avatar_url`](#method.avatar_url) methods use this to treat a missing
+    /// profile as an unset display name or avatar.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A filled out and valid request for the endpoint to be hit
+    pub async fn send_optional<Request>(
+        &self,
+        request: Request,
+    ) -> Result<Option<Request::IncomingResponse>>
+    where
+        Request: OutgoingRequest + Debug,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.http_client.send_optional(request).await
+    }
+
+    /// Send an arbitrary request to a different homeserver, without updating
+    /// client state.
+    ///
+    /// The request uses the same HTTP client as all the other requests, but
+    /// the access token of this client is never sent along since the other
+    /// homeserver won't know about it. Requests for endpoints that need
+    /// authentication fail with an [`Error::AuthenticationNotForwarded`]
+    /// error.
+    ///
+    /// The request isn't signed, isn't passed to the request interceptors and
+    /// isn't retried. Its response doesn't count towards the metrics, health,
+    /// timing and clock skew of our own homeserver.
+    ///
+    /// # Arguments
+    ///
+    /// * `base_url` - The URL of the homeserver the request should be sent to.
+    ///
+    /// * `request` - A filled out and valid request for the endpoint to be hit
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{api::unversioned::get_supported_versions, Client};
+    /// # use url::Url;
+    /// # use futures::executor::block_on;
+    /// # let homeserver = Url::parse("http://example.com").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// # block_on(async {
+    /// let other_server = Url::parse("https://matrix.example.org").unwrap();
+    /// let request = get_supported_versions::Request::new();
+    ///
+    /// let response = client.send_to(&other_server, request).await.unwrap();
+    /// println!("Supported versions: {:?}", response.versions);
+    /// # });
+    /// ```
+    ///
+    /// [`Error::AuthenticationNotForwarded`]: enum.Error.html#variant.AuthenticationNotForwarded
+    pub async fn send_to<Request>(
+        &self,
+        base_url: &Url,
+        request: Request,
+    ) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest + Debug,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.http_client.send_to(base_url, request).await
+    }
+
+    /// Send a previously queued request to the server.
+    ///
+    /// The request is sent to the homeserver of this client and, if the
+    /// request needs it, authenticated with the current access token. The raw
+    /// response is returned, it's up to the caller to deserialize it.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The queued request that should be sent out.
+    pub async fn send_raw(&self, request: &QueuedRequest) -> Result<http::Response<Vec<u8>>> {
+        self.http_client.send_raw(request).await
+    }
+
+    /// Get a list of the requests that are currently waiting for a response
+    /// from the homeserver.
+    pub fn pending_requests(&self) -> Vec<PendingRequest> {
+        self.http_client.pending_requests.list()
+    }
+
+    /// Get the timing of the last request that received a response from the
+    /// homeserver.
+    ///
+    /// Besides the round trip measured by the client this contains the time
+    /// the homeserver reported to have spent on the request, if it sent a
+    /// `Server-Timing` header.
+    pub fn last_request_timing(&self) -> Option<RequestTiming> {
+        *self.http_client.last_timing.lock().unwrap()
+    }
+
+    /// Get the quality of the connection to the homeserver.
+    ///
+    /// This is a coarse version of the
+    /// [`homeserver_health_score`](#method.homeserver_health_score), meant to
+    /// be shown as a status badge.
+    pub fn homeserver_health(&self) -> HomeserverHealth {
+        self.homeserver_health_score().into()
+    }
+
+    /// Get a score between 0 and 100 describing the quality of the connection
+    /// to the homeserver, higher is better.
+    ///
+    /// The score is computed from exponential moving averages over the
+    /// outcomes of recent requests, the most recent request has a weight of
+    /// 20%:
+    ///
+    /// * The error rate contributes 60% of the score. Requests that failed
+    /// without a response or with a server error count as errors.
+    /// * The latency contributes 25% of the score. Latencies up to half a
+    /// second count fully, the contribution drops linearly to nothing at five
+    /// seconds. Syncs are long-polling and don't count towards the latency.
+    /// * The rate of rate-limited requests contributes 15% of the score.
+    ///
+    /// A client that didn't send any requests yet has a score of 100. The
+    /// score belongs to the homeserver of this client, a client for a
+    /// different homeserver starts from scratch.
+    pub fn homeserver_health_score(&self) -> u8 {
+        self.http_client.health.score()
+    }
+
+    /// Render the metrics of the requests this client sent out in the
+    /// Prometheus text exposition format.
+    ///
+    /// The returned text can be served from a `/metrics` endpoint of the
+    /// application.
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+    pub fn metrics_text(&self) -> String {
+        self.http_client.metrics.render()
+    }
+
+    /// Get the bandwidth the requests of this client used so far, aggregated
+    /// by endpoint category.
+    ///
+    /// Custom categories can be configured using
+    /// [`ClientConfig::endpoint_category`].
+    ///
+    /// [`ClientConfig::endpoint_category`]: struct.ClientConfig.html#method.endpoint_category
+    #[cfg(feature = "metrics")]
+    #[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+    pub fn bandwidth_by_category(&self) -> BTreeMap<EndpointCategory, BandwidthUsage> {
+        self.http_client.metrics.bandwidth()
+    }
+
+    /// Cancel the pending request with the given id.
+    ///
+    /// The cancelled request will fail with an [`Error::Cancelled`] error, the
+    /// ids of the pending requests can be found using
+    /// [`pending_requests`](#method.pending_requests).
+    ///
+    /// Returns true if the request was found, false if the request already
+    /// finished or if no request with the given id exists.
+    ///
+    /// [`Error::Cancelled`]: enum.Error.html#variant.Cancelled
+    pub fn cancel_request(&self, request_id: &Uuid) -> bool {
+        self.http_client.pending_requests.cancel(request_id)
+    }
+
+    /// Send multiple requests to the server concurrently, without updating
+    /// client state.
+    ///
+    /// At most `max_concurrent` requests will be in flight at the same time,
+    /// they share the connection pool of the client. The same warning as for
+    /// [`send`](#method.send) applies.
+    ///
+    /// Returns the results in the same order as the requests were given, a
+    /// failing request doesn't abort the other ones.
+    ///
+    /// # Arguments
+    ///
+    /// * `requests` - The requests that should be sent.
+    ///
+    /// * `max_concurrent` - The maximal number of requests that will be sent
+    /// out at the same time, a value of 0 is treated as 1.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::Client;
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let client = Client::new(homeserver).unwrap();
+    /// use matrix_sdk::api::r0::profile::get_display_name;
+    /// use matrix_sdk::identifiers::user_id;
+    ///
+    /// let alice = user_id!("@alice:example.org");
+    /// let bob = user_id!("@bob:example.org");
+    ///
+    /// let requests = vec![
+    ///     get_display_name::Request::new(&alice),
+    ///     get_display_name::Request::new(&bob),
+    /// ];
+    ///
+    /// for response in client.send_batch(requests, 4).await {
+    ///     println!("{:?}", response.map(|r| r.displayname));
+    /// }
+    /// # })
+    /// ```
+    pub async fn send_batch<Request>(
+        &self,
+        requests: impl IntoIterator<Item = Request>,
+        max_concurrent: usize,
+    ) -> Vec<Result<Request::IncomingResponse>>
+    where
+        Request: OutgoingRequest + Debug,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        stream::iter(requests)
+            .map(|request| self.http_client.send(request))
+            .buffered(max_concurrent.max(1))
+            .collect()
+            .await
+    }
+
+    #[cfg(feature = "encryption")]
+    pub(crate) async fn send_to_device(
+        &self,
+        request: &ToDeviceRequest,
+    ) -> Result<ToDeviceResponse> {
+        let txn_id_string = request.txn_id_string();
+        let request = RumaToDeviceRequest::new(
+            request.event_type.clone(),
+            &txn_id_string,
+            request.messages.clone(),
+        );
+
+        self.send(request).await
+    }
+
+    /// Get information of all our own devices.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # use matrix_sdk::{Client, SyncSettings};
+    /// # use futures::executor::block_on;
+    /// # use url::Url;
+    /// # use std::convert::TryFrom;
+    /// # block_on(async {
+    /// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+    /// # let mut client = Client::new(homeserver).unwrap();
+    /// let response = client.devices().await.expect("Can't get devices from server");
     ///
     /// for device in response.devices {
     ///     println!(
@@ -1637,7 +3496,17 @@ impl Client {
             timeout: sync_settings.timeout,
         });
 
-        let response = self.send(request).await?;
+        // The homeserver holds on to the request until new events arrive or
+        // the sync timeout passes. Without a sync timeout it answers right
+        // away, but assembling an initial sync can take a long time.
+        let request_config = match sync_settings.timeout {
+            Some(timeout) => {
+                RequestConfig::new().timeout(self.http_client.request_timeout + timeout)
+            }
+            None => RequestConfig::new().without_timeout(),
+        };
+
+        let response = self.send_with_config(request, request_config).await?;
 
         Ok(self.base_client.receive_sync_response(response).await?)
     }
@@ -1768,6 +3637,12 @@ impl Client {
                             }
                         }
                         OutgoingRequests::ToDeviceRequest(request) => {
+                            if let Err(e) =
+                                self.base_client.record_to_device_request(&request).await
+                            {
+                                warn!("Error while recording a to-device request {:?}", e);
+                            }
+
                             // TODO remove this unwrap
                             if let Ok(resp) = self.send_to_device(&request).await {
                                 self.base_client
@@ -1864,6 +3739,7 @@ impl Client {
         let mut requests = self.base_client.share_group_session(room_id).await?;
 
         for request in requests.drain(..) {
+            self.base_client.record_to_device_request(&request).await?;
             let response = self.send_to_device(&request).await?;
 
             self.base_client
@@ -1960,6 +3836,37 @@ impl Client {
             })
     }
 
+    /// Decrypt an encrypted event from the timeline of a room.
+    ///
+    /// If the event can't be decrypted an [`Error::Decryption`] is returned,
+    /// [`Error::is_missing_key`] tells if that's because the room key for the
+    /// event didn't arrive yet. A request for the missing key is queued in
+    /// that case.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event that should be decrypted.
+    ///
+    /// * `room_id` - The id of the room the event was sent to.
+    ///
+    /// [`Error::Decryption`]: enum.Error.html#variant.Decryption
+    /// [`Error::is_missing_key`]: enum.Error.html#method.is_missing_key
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn decrypt_room_event(
+        &self,
+        event: &SyncMessageEvent<EncryptedEventContent>,
+        room_id: &RoomId,
+    ) -> Result<Raw<AnySyncRoomEvent>> {
+        let olm = self
+            .base_client
+            .olm_machine()
+            .await
+            .ok_or(Error::AuthenticationRequired)?;
+
+        Ok(olm.decrypt_room_event(event, room_id).await?)
+    }
+
     /// Get a specific device of a user.
     ///
     /// # Arguments
@@ -2279,17 +4186,25 @@ mod test {
     use crate::ClientConfig;
 
     use super::{
-        get_public_rooms, get_public_rooms_filtered, register::RegistrationKind, Client,
-        Invite3pid, Session, SyncSettings, Url,
+        get_devices, get_display_name, get_public_rooms, get_public_rooms_filtered, join_base_path,
+        logout, register::RegistrationKind, Client, Error, HomeserverHealth, HttpSend, Invite3pid,
+        QueuedRequest, RedirectPolicy, RegistrationRequirements, RequestConfig, RequestInterceptor,
+        RequestSigner, ResponseRewriter, RetryConfig, RetryHandler, Session, SyncSettings,
+        TokenRefreshHandler, TransactionIdGenerator, Url,
     };
+    use bytes::Bytes;
+    use futures::{stream, StreamExt};
     use matrix_sdk_base::RoomMember;
     use matrix_sdk_common::{
         api::r0::{
             account::register::Request as RegistrationRequest,
             directory::get_public_rooms_filtered::Request as PublicRoomsFilterRequest,
-            typing::create_typing_event::Typing, uiaa::AuthData,
+            media::get_content_thumbnail,
+            typing::create_typing_event::Typing,
+            uiaa::{AuthData, UiaaInfo},
         },
-        assign,
+        api::unversioned::get_supported_versions,
+        assign, async_trait,
         directory::Filter,
         events::{room::message::MessageEventContent, AnyMessageEventContent},
         identifiers::{event_id, room_id, user_id},
@@ -2299,51 +4214,1982 @@ mod test {
     use mockito::{mock, Matcher};
     use serde_json::json;
 
-    use std::{collections::BTreeMap, convert::TryInto, io::Cursor, str::FromStr, time::Duration};
+    use std::{
+        collections::BTreeMap,
+        convert::{TryFrom, TryInto},
+        io::Cursor,
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    async fn logged_in_client() -> Client {
+        logged_in_client_with_config(ClientConfig::new()).await
+    }
+
+    async fn logged_in_client_with_config(config: ClientConfig) -> Client {
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
+        };
+        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
+        let client = Client::new_with_config(homeserver, config).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        client
+    }
+
+    /// A `HttpSend` implementation whose requests never receive a response.
+    #[derive(Debug)]
+    struct NeverResponds;
+
+    #[async_trait]
+    impl HttpSend for NeverResponds {
+        async fn send_request(
+            &self,
+            _: http::Request<Vec<u8>>,
+        ) -> crate::Result<http::Response<Vec<u8>>> {
+            futures::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn login() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+
+        let _m = mock("POST", "/_matrix/client/r0/login")
+            .with_status(200)
+            .with_body(test_json::LOGIN.to_string())
+            .create();
+
+        let client = Client::new(homeserver).unwrap();
+
+        client
+            .login("example", "wordpass", None, None)
+            .await
+            .unwrap();
+
+        let logged_in = client.logged_in().await;
+        assert!(logged_in, "Client should be logged in");
+    }
+
+    #[tokio::test]
+    async fn login_default_device_name() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let template = "Test client on {os}";
+        let device_name = crate::device_display_name(template);
+        assert!(!device_name.contains('{'));
+
+        let _m = mock("POST", "/_matrix/client/r0/login")
+            .match_body(Matcher::PartialJson(
+                json!({ "initial_device_display_name": device_name }),
+            ))
+            .with_status(200)
+            .with_body(test_json::LOGIN.to_string())
+            .create();
+
+        let config = ClientConfig::new().device_name_template(template);
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        client
+            .login("example", "wordpass", None, None)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn logout() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", "/_matrix/client/r0/logout")
+            .with_status(200)
+            .with_body(test_json::LOGOUT.to_string())
+            .match_header("authorization", "Bearer 1234")
+            .create();
+
+        client.logout().await.unwrap();
+        assert!(!client.logged_in().await, "Client should be logged out");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn logout_and_wipe() {
+        use matrix_sdk_base::crypto::store::{CryptoStore, MemoryStore};
+
+        let store = MemoryStore::new();
+        let mut config = ClientConfig::new();
+        config.base_config = config.base_config.crypto_store(Box::new(store.clone()));
+        let client = logged_in_client_with_config(config).await;
+
+        assert!(store.load_account().await.unwrap().is_some());
+
+        let _m = mock("POST", "/_matrix/client/r0/logout")
+            .with_status(200)
+            .with_body(test_json::LOGOUT.to_string())
+            .create();
+
+        client.logout_and_wipe().await.unwrap();
+        assert!(!client.logged_in().await, "Client should be logged out");
+        assert!(store.load_account().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn logout_with_unknown_token() {
+        let client = logged_in_client().await;
+
+        let _m = mock("POST", "/_matrix/client/r0/logout/all")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Invalid macaroon passed.",
+                })
+                .to_string(),
+            )
+            .create();
+
+        client.logout_all().await.unwrap();
+        assert!(!client.logged_in().await, "Client should be logged out");
+    }
+
+    #[tokio::test]
+    async fn session_changes() {
+        let client = logged_in_client().await;
+        let mut changes = client.session_changes().await;
+
+        let session = changes.next().await.unwrap().unwrap();
+        assert_eq!(session.user_id, user_id!("@example:localhost"));
+        assert_eq!(&*session.device_id, "DEVICEID");
+
+        let _m = mock("POST", "/_matrix/client/r0/logout")
+            .with_status(200)
+            .with_body(test_json::LOGOUT.to_string())
+            .create();
+
+        client.logout().await.unwrap();
+        assert!(changes.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn consent_not_given() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(403)
+            .with_body(
+                json!({
+                    "errcode": "M_CONSENT_NOT_GIVEN",
+                    "error": "You must review and agree to our terms and conditions.",
+                    "consent_uri": "https://example.org/_matrix/consent?v=1.0",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+
+        assert_eq!(
+            error.consent_uri().map(|u| u.as_str()),
+            Some("https://example.org/_matrix/consent?v=1.0")
+        );
+    }
+
+    #[tokio::test]
+    async fn capture_invalid_responses() {
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(json!({ "devices": "not a list" }).to_string())
+            .create();
+
+        let client = logged_in_client().await;
+
+        assert!(matches!(
+            client.devices().await,
+            Err(Error::RumaResponse(_))
+        ));
+
+        let config = ClientConfig::new().capture_invalid_responses(true);
+        let client = logged_in_client_with_config(config).await;
+
+        match client.devices().await {
+            Err(Error::ResponseDeserialization { status, body, .. }) => {
+                assert_eq!(status, http::StatusCode::OK);
+                assert_eq!(body, br#"{"devices":"not a list"}"#.to_vec());
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn soft_logout() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token has expired",
+                    "soft_logout": true
+                })
+                .to_string(),
+            )
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+
+        assert!(
+            matches!(&error, Error::SoftLogout { message } if message == "Access token has expired")
+        );
+        assert_eq!(error.status_code(), Some(http::StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn resource_limit_exceeded() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(403)
+            .with_body(
+                json!({
+                    "errcode": "M_RESOURCE_LIMIT_EXCEEDED",
+                    "error": "Monthly Active User limit exceeded",
+                    "limit_type": "monthly_active_user",
+                    "admin_contact": "mailto:admin@example.org",
+                })
+                .to_string(),
+            )
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+        let info = error.resource_limit_info().unwrap();
+
+        assert_eq!(info.limit_type.as_deref(), Some("monthly_active_user"));
+        assert_eq!(info.admin_contact, "mailto:admin@example.org");
+        assert_eq!(info.message, "Monthly Active User limit exceeded");
+    }
+
+    #[tokio::test]
+    async fn send_batch() {
+        let client = logged_in_client().await;
+
+        let _alice = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*alice.*/displayname".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "displayname": "Alice" }).to_string())
+        .create();
+
+        let _bob = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*bob.*/displayname".to_string()),
+        )
+        .with_status(404)
+        .with_body(json!({ "errcode": "M_NOT_FOUND", "error": "Profile not found" }).to_string())
+        .create();
+
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+
+        let responses = client
+            .send_batch(
+                vec![
+                    get_display_name::Request::new(&alice),
+                    get_display_name::Request::new(&bob),
+                    get_display_name::Request::new(&alice),
+                ],
+                2,
+            )
+            .await;
+
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[0].as_ref().unwrap().displayname.as_deref(),
+            Some("Alice")
+        );
+        assert!(responses[1].is_err());
+        assert!(responses[2].is_ok());
+    }
+
+    #[tokio::test]
+    async fn cross_origin_redirect_strips_token() {
+        let client = logged_in_client().await;
+
+        // The mock server listens on 127.0.0.1, redirecting to localhost
+        // changes the host while still reaching the same server.
+        let redirect_target = format!(
+            "{}/redirected/devices",
+            mockito::server_url().replace("127.0.0.1", "localhost")
+        );
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(302)
+            .with_header("location", &redirect_target)
+            .create();
+
+        let _redirected = mock("GET", "/redirected/devices")
+            .match_header("authorization", Matcher::Missing)
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        assert!(client.devices().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn same_origin_redirect_policy() {
+        let config = ClientConfig::new().redirect_policy(RedirectPolicy::SameOrigin);
+        let client = logged_in_client_with_config(config).await;
+
+        let redirect_target = format!(
+            "{}/redirected/devices",
+            mockito::server_url().replace("127.0.0.1", "localhost")
+        );
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(302)
+            .with_header("location", &redirect_target)
+            .create();
+
+        let redirected = mock("GET", "/redirected/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .expect(0)
+            .create();
+
+        assert!(matches!(client.devices().await, Err(Error::Reqwest(_))));
+        redirected.assert();
+    }
+
+    #[cfg(feature = "rustls-tls")]
+    #[tokio::test]
+    async fn tls_hostname_with_root_certificate() {
+        const CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBejCCASGgAwIBAgIUD+AB0qL0+OfQtoSxUrcFppnOtlgwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTYxMDAwNTlaGA8yMTI2MDkyMjEw
+MDA1OVowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABG8Pmrhqb/5FblliNOgeC0Z8JoPa8bvXQ+mBrCFsu2hm5P73spiWDmdeixHl
+/qokWy1ibJVbUib/tALghFPmAsejUzBRMB0GA1UdDgQWBBQ2JKfH6H1u+w6y44BV
+qjlp3MrGyjAfBgNVHSMEGDAWgBQ2JKfH6H1u+w6y44BVqjlp3MrGyjAPBgNVHRMB
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0cAMEQCIBPptko6I+Lw+vPjON+WIUxgbyri
+i+gqfNRFZFtsspnrAiBeGt6xRrsBfwx6gJiiDwh9wshR53f/XIKyx5AvPWCA8g==
+-----END CERTIFICATE-----";
+
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let certificate = reqwest::Certificate::from_pem(CA.as_bytes()).unwrap();
+        let config = ClientConfig::new()
+            .tls_hostname("matrix.example.org")
+            .unwrap()
+            .add_root_certificate(certificate);
+
+        // The additional root certificate would be ignored, refuse to create
+        // the client instead.
+        assert!(matches!(
+            Client::new_with_config(homeserver, config),
+            Err(Error::UnsupportedTlsConfig)
+        ));
+    }
+
+    #[tokio::test]
+    async fn upgrading_redirect_is_followed() {
+        let client = logged_in_client().await;
+
+        let redirect_target = format!(
+            "{}/redirected/devices",
+            mockito::server_url().replace("http://", "https://")
+        );
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(302)
+            .with_header("location", &redirect_target)
+            .create();
+
+        // The mock server doesn't speak TLS, so following the redirect fails
+        // while connecting instead of being refused by the redirect policy.
+        match client.devices().await {
+            Err(Error::Reqwest(e)) => {
+                assert!(!e.is_redirect());
+                assert_eq!(e.url().map(|u| u.scheme()), Some("https"));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[test]
+    fn downgrading_redirect_is_refused() {
+        use crate::http_client::is_downgrade;
+
+        let secure = Url::parse("https://example.org:8448/_matrix/client/r0/devices").unwrap();
+        let insecure = Url::parse("http://example.org:8448/_matrix/client/r0/devices").unwrap();
+
+        // Same host and port, only the scheme changes.
+        assert!(is_downgrade(&secure, &insecure));
+        assert!(!is_downgrade(&insecure, &secure));
+        assert!(!is_downgrade(&secure, &secure));
+    }
+
+    #[tokio::test]
+    async fn max_redirects() {
+        let config = ClientConfig::new().max_redirects(1);
+        let client = logged_in_client_with_config(config).await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(302)
+            .with_header(
+                "location",
+                &format!("{}/first/devices", mockito::server_url()),
+            )
+            .create();
+
+        let _first = mock("GET", "/first/devices")
+            .with_status(302)
+            .with_header(
+                "location",
+                &format!("{}/second/devices", mockito::server_url()),
+            )
+            .create();
+
+        let second = mock("GET", "/second/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .expect(0)
+            .create();
+
+        match client.devices().await {
+            Err(Error::Reqwest(e)) => assert!(e.is_redirect()),
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        second.assert();
+    }
+
+    #[tokio::test]
+    async fn support_info() {
+        let client = logged_in_client().await;
+
+        let m = mock("GET", "/.well-known/matrix/support")
+            .with_status(200)
+            .with_body(
+                json!({
+                    "contacts": [{
+                        "matrix_id": "@admin:localhost",
+                        "email_address": "admin@example.org",
+                        "role": "admin",
+                    }],
+                    "support_page": "https://example.org/support",
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let info = client.support_info().await.unwrap().unwrap();
+
+        assert_eq!(info.contacts.len(), 1);
+        assert_eq!(
+            info.contacts[0].matrix_id,
+            Some(user_id!("@admin:localhost"))
+        );
+        assert_eq!(info.contacts[0].role, "admin");
+        assert_eq!(
+            info.support_page.as_ref().map(|u| u.as_str()),
+            Some("https://example.org/support")
+        );
+
+        // The second call is answered from the cache.
+        assert_eq!(client.support_info().await.unwrap(), Some(info));
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn invalid_support_info() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/.well-known/matrix/support")
+            .with_status(200)
+            .with_header("content-type", "text/html")
+            .with_body("<html><body>Welcome to nginx!</body></html>")
+            .create();
+
+        assert_eq!(client.support_info().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn clock_skew() {
+        let client = logged_in_client().await;
+        assert!(client.clock_skew().await.is_none());
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_header("date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+
+        let skew = client.clock_skew().await.unwrap();
+        assert!(skew > Duration::from_secs(60 * 60 * 24 * 365));
+    }
+
+    #[tokio::test]
+    async fn send_optional() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*/displayname".to_string()),
+        )
+        .with_status(404)
+        .with_body(json!({ "errcode": "M_NOT_FOUND", "error": "Profile not found" }).to_string())
+        .create();
+
+        let user_id = user_id!("@example:localhost");
+        let response = client
+            .send_optional(get_display_name::Request::new(&user_id))
+            .await
+            .unwrap();
+
+        assert!(response.is_none());
+        assert!(client.display_name().await.unwrap().is_none());
+        assert!(client
+            .send(get_display_name::Request::new(&user_id))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn send_to_other_homeserver() {
+        let client = logged_in_client().await;
+        let other_server = Url::parse(&format!("{}/other", mockito::server_url())).unwrap();
+
+        let m = mock("GET", "/other/_matrix/client/versions")
+            .match_header("authorization", Matcher::Missing)
+            .with_status(200)
+            .with_body(json!({ "versions": ["r0.6.0"] }).to_string())
+            .create();
+
+        let response = client
+            .send_to(&other_server, get_supported_versions::Request::new())
+            .await
+            .unwrap();
+
+        m.assert();
+        assert_eq!(response.versions, vec!["r0.6.0".to_owned()]);
+
+        let response = client
+            .send_to(&other_server, get_devices::Request::new())
+            .await;
+        assert!(matches!(response, Err(Error::AuthenticationNotForwarded)));
+    }
+
+    #[tokio::test]
+    async fn send_to_keeps_homeserver_state() {
+        let client = logged_in_client().await;
+        let other_server = Url::parse(&format!("{}/other", mockito::server_url())).unwrap();
+
+        let _m = mock("GET", "/other/_matrix/client/versions")
+            .with_status(500)
+            .with_header("date", "Sun, 06 Nov 1994 08:49:37 GMT")
+            .with_body(json!({ "errcode": "M_UNKNOWN", "error": "Oops" }).to_string())
+            .create();
+
+        for _ in 0..3 {
+            client
+                .send_to(&other_server, get_supported_versions::Request::new())
+                .await
+                .unwrap_err();
+        }
+
+        assert!(client.clock_skew().await.is_none());
+        assert_eq!(client.homeserver_health_score(), 100);
+        assert_eq!(client.homeserver_health(), HomeserverHealth::Good);
+    }
+
+    #[tokio::test]
+    async fn request_signer() {
+        #[derive(Debug)]
+        struct Signer;
+
+        #[async_trait]
+        impl RequestSigner for Signer {
+            async fn sign_request(
+                &self,
+                request: &mut http::Request<Vec<u8>>,
+                nonce: Option<&str>,
+            ) -> crate::Result<()> {
+                let proof = format!(
+                    "{} {} {}",
+                    request.method(),
+                    request.uri().path(),
+                    nonce.unwrap_or("none")
+                );
+                request
+                    .headers_mut()
+                    .insert("dpop", http::HeaderValue::from_str(&proof).unwrap());
+
+                Ok(())
+            }
+        }
+
+        let config = ClientConfig::new().request_signer(Arc::new(Signer));
+        let client = logged_in_client_with_config(config).await;
+
+        let first = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("dpop", "GET /_matrix/client/r0/devices none")
+            .with_status(200)
+            .with_header("dpop-nonce", "server-nonce")
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        let second = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("dpop", "GET /_matrix/client/r0/devices server-nonce")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+        client.devices().await.unwrap();
+
+        first.assert();
+        second.assert();
+    }
+
+    #[tokio::test]
+    async fn get_profile() {
+        let client = logged_in_client().await;
+        let alice = user_id!("@alice:example.org");
+
+        let m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*alice.*".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "displayname": "Alice" }).to_string())
+        .expect(2)
+        .create();
+
+        // Concurrent lookups share a single request.
+        let (first, second) =
+            futures::join!(client.get_profile(&alice), client.get_profile(&alice));
+        assert_eq!(first.unwrap().displayname.as_deref(), Some("Alice"));
+        assert_eq!(second.unwrap().displayname.as_deref(), Some("Alice"));
+
+        // Cached.
+        client.get_profile(&alice).await.unwrap();
+
+        client.invalidate_profile(&alice);
+        client.get_profile(&alice).await.unwrap();
+
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn response_rewriter() {
+        #[derive(Debug)]
+        struct AddMissingDevices;
+
+        impl ResponseRewriter for AddMissingDevices {
+            fn rewrite_response(&self, path: &str, status: http::StatusCode, body: &mut Vec<u8>) {
+                assert!(path.ends_with("/devices"));
+
+                if status.is_success() && body.as_slice() == b"{}" {
+                    *body = br#"{"devices":[]}"#.to_vec();
+                }
+            }
+        }
+
+        let config = ClientConfig::new()
+            .response_rewriter(vec!["/devices".to_owned()], Arc::new(AddMissingDevices));
+        let client = logged_in_client_with_config(config).await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+
+        assert!(client.devices().await.unwrap().devices.is_empty());
+
+        // Responses of other endpoints are left alone.
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/client/r0/profile/.*/displayname".to_string()),
+        )
+        .with_status(200)
+        .with_body(json!({ "displayname": "Alice" }).to_string())
+        .create();
+
+        client.display_name().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn request_interceptor() {
+        #[derive(Debug, Default)]
+        struct Recorder(std::sync::Mutex<Vec<Option<http::StatusCode>>>);
+
+        impl RequestInterceptor for Recorder {
+            fn on_request(&self, request: &mut http::Request<Vec<u8>>) {
+                request
+                    .headers_mut()
+                    .insert("x-correlation-id", "1234".parse().unwrap());
+            }
+
+            fn on_response(
+                &self,
+                request: &crate::PendingRequest,
+                response: std::result::Result<&http::Response<Vec<u8>>, &Error>,
+            ) {
+                assert!(request.path.ends_with("/devices"));
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(response.ok().map(|r| r.status()));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let config = ClientConfig::new().add_interceptor(recorder.clone());
+        let client = logged_in_client_with_config(config).await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("x-correlation-id", "1234")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+
+        let config = ClientConfig::new()
+            .client(Arc::new(NeverResponds))
+            .add_interceptor(recorder.clone());
+        let client = logged_in_client_with_config(config).await;
+        let request_config = RequestConfig::new().timeout(Duration::from_millis(50));
+
+        client
+            .send_with_config(get_devices::Request::new(), request_config)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![Some(http::StatusCode::OK), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_access_token() {
+        #[derive(Debug, Default)]
+        struct Saved(std::sync::Mutex<Option<Session>>);
+
+        impl TokenRefreshHandler for Saved {
+            fn on_token_refresh(&self, session: &Session) {
+                *self.0.lock().unwrap() = Some(session.clone());
+            }
+        }
+
+        let saved = Arc::new(Saved::default());
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new().token_refresh_handler(saved.clone());
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        client
+            .restore_login(Session {
+                access_token: "1234".to_owned(),
+                user_id: user_id!("@example:localhost"),
+                device_id: "DEVICEID".into(),
+                refresh_token: Some("refresh".to_owned()),
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+
+        let _expired = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("authorization", "Bearer 1234")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token has expired",
+                    "soft_logout": true
+                })
+                .to_string(),
+            )
+            .create();
+
+        let refresh = mock(
+            "POST",
+            "/_matrix/client/unstable/org.matrix.msc2918.refresh",
+        )
+        .match_body(Matcher::Json(json!({ "refresh_token": "refresh" })))
+        .with_status(200)
+        .with_body(
+            json!({
+                "access_token": "5678",
+                "refresh_token": "refresh2",
+                "expires_in_ms": 60000
+            })
+            .to_string(),
+        )
+        .expect(1)
+        .create();
+
+        let _refreshed = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("authorization", "Bearer 5678")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+        refresh.assert();
+
+        let session = saved.0.lock().unwrap().clone().unwrap();
+        assert_eq!(session.access_token, "5678");
+        assert_eq!(session.refresh_token.as_deref(), Some("refresh2"));
+        assert!(session.expires_at.is_some());
+
+        // Subsequent requests use the new token right away.
+        client.devices().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_keeps_the_soft_logout() {
+        #[derive(Debug, Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+
+        impl RequestInterceptor for Recorder {
+            fn on_request(&self, request: &mut http::Request<Vec<u8>>) {
+                self.0.lock().unwrap().push(request.uri().path().to_owned());
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let config = ClientConfig::new()
+            .base_path("/matrix")
+            .add_interceptor(recorder.clone());
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        client
+            .restore_login(Session {
+                access_token: "1234".to_owned(),
+                user_id: user_id!("@example:localhost"),
+                device_id: "DEVICEID".into(),
+                refresh_token: Some("refresh".to_owned()),
+                expires_at: None,
+            })
+            .await
+            .unwrap();
+
+        let _expired = mock("GET", "/matrix/_matrix/client/r0/devices")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "errcode": "M_UNKNOWN_TOKEN",
+                    "error": "Access token has expired",
+                    "soft_logout": true
+                })
+                .to_string(),
+            )
+            .create();
+
+        // The homeserver answers with something that isn't a valid refresh
+        // response.
+        let refresh = mock(
+            "POST",
+            "/matrix/_matrix/client/unstable/org.matrix.msc2918.refresh",
+        )
+        .with_status(200)
+        .with_body("not json")
+        .expect(1)
+        .create();
+
+        assert!(matches!(
+            client.devices().await,
+            Err(Error::SoftLogout { .. })
+        ));
+        refresh.assert();
+
+        // Only the request itself went through the interceptors.
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["/matrix/_matrix/client/r0/devices".to_owned()]
+        );
+    }
+
+    #[test]
+    fn redact_sensitive() {
+        use crate::http_client::RedactSensitive;
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri("https://example.org/_matrix/client/r0/login?access_token=secret&foo=bar")
+            .header(http::header::AUTHORIZATION, "Bearer secret")
+            .body(
+                json!({
+                    "type": "m.login.password",
+                    "identifier": { "type": "m.id.user", "user": "example" },
+                    "password": "secret",
+                })
+                .to_string()
+                .into_bytes(),
+            )
+            .unwrap();
+
+        let logged = request.redact_sensitive();
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains("access_token=***"));
+        assert!(logged.contains("foo=bar"));
+        assert!(logged.contains("m.login.password"));
+
+        let response = http::Response::builder()
+            .body(
+                json!({
+                    "user_id": "@example:example.org",
+                    "access_token": "secret",
+                    "device_id": "DEVICEID",
+                })
+                .to_string()
+                .into_bytes(),
+            )
+            .unwrap();
+
+        let logged = response.redact_sensitive();
+        assert!(!logged.contains("secret"));
+        assert!(logged.contains("DEVICEID"));
+    }
+
+    #[tokio::test]
+    async fn queued_request() {
+        let client = logged_in_client().await;
+
+        let request = QueuedRequest::new(get_devices::Request::new()).unwrap();
+        let serialized = serde_json::to_string(&request).unwrap();
+
+        assert!(request.authenticated);
+        assert!(!serialized.contains("placeholder"));
+        assert!(request
+            .headers
+            .iter()
+            .all(|(name, _)| !name.eq_ignore_ascii_case("authorization")));
+
+        let deserialized: QueuedRequest = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(request, deserialized);
+        assert_eq!(
+            request.content_hash(),
+            QueuedRequest::new(get_devices::Request::new())
+                .unwrap()
+                .content_hash()
+        );
+
+        let user_id = user_id!("@example:localhost");
+        let other_request = QueuedRequest::new(get_display_name::Request::new(&user_id)).unwrap();
+        assert_ne!(request.content_hash(), other_request.content_hash());
+
+        let m = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("authorization", "Bearer 1234")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        let response = client.send_raw(&deserialized).await.unwrap();
+        let response = get_devices::Response::try_from(response).unwrap();
+
+        m.assert();
+        assert_eq!(response.devices.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn request_deadline() {
+        let config = ClientConfig::default().client(Arc::new(NeverResponds));
+        let client = logged_in_client_with_config(config).await;
+
+        let request_config =
+            RequestConfig::new().deadline(Instant::now() + Duration::from_millis(50));
+        let response = client
+            .send_with_config(get_devices::Request::new(), request_config)
+            .await;
+
+        assert!(matches!(response, Err(Error::DeadlineExceeded)));
+        assert!(client.pending_requests().is_empty());
+
+        let request_config = RequestConfig::new().deadline(Instant::now());
+        let response = client
+            .send_with_config(get_devices::Request::new(), request_config)
+            .await;
+
+        assert!(matches!(response, Err(Error::DeadlineExceeded)));
+    }
+
+    #[tokio::test]
+    async fn error_status_code() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(404)
+            .with_body(json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }).to_string())
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+        assert_eq!(error.status_code(), Some(http::StatusCode::NOT_FOUND));
+
+        let error = Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk"));
+        assert_eq!(error.status_code(), None);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_error() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(429)
+            .with_body(
+                json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                    "retry_after_ms": 2000
+                })
+                .to_string(),
+            )
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+        assert!(error.is_rate_limited());
+        assert_eq!(error.retry_after(), Some(Duration::from_millis(2000)));
+
+        let error = Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk"));
+        assert!(!error.is_rate_limited());
+        assert_eq!(error.retry_after(), None);
+    }
+
+    #[tokio::test]
+    async fn service_unavailable_error() {
+        let client = logged_in_client().await;
+
+        let m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(503)
+            .with_header("retry-after", "120")
+            .with_body("<html>Down for maintenance</html>")
+            .create();
+
+        let error = client.devices().await.unwrap_err();
+        assert!(matches!(error, Error::ServiceUnavailable { .. }));
+        assert_eq!(
+            error.status_code(),
+            Some(http::StatusCode::SERVICE_UNAVAILABLE)
+        );
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(120)));
+        drop(m);
+
+        let date = httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(120));
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(503)
+            .with_header("retry-after", &date)
+            .create();
+
+        let retry_after = client.devices().await.unwrap_err().retry_after().unwrap();
+        assert!(retry_after > Duration::from_secs(100));
+        assert!(retry_after <= Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        let config = ClientConfig::default().client(Arc::new(NeverResponds));
+        let client = logged_in_client_with_config(config).await;
+
+        let request_config = RequestConfig::new().timeout(Duration::from_millis(50));
+        let response = client
+            .send_with_config(get_devices::Request::new(), request_config)
+            .await;
+
+        assert!(matches!(
+            response,
+            Err(Error::RequestTimeout(t)) if t == Duration::from_millis(50)
+        ));
+        assert!(client.pending_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn network_error_classification() {
+        // Nothing listens on port 1.
+        let error: Error = reqwest::Client::new()
+            .get("http://127.0.0.1:1/")
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert!(error.is_connection_error());
+        assert!(!error.is_timeout());
+
+        // The connection is accepted by the OS but never answered.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let error: Error = reqwest::Client::builder()
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .get(&url)
+            .send()
+            .await
+            .unwrap_err()
+            .into();
+        assert!(error.is_timeout());
+        assert!(!error.is_connection_error());
+
+        assert!(Error::RequestTimeout(Duration::from_millis(50)).is_timeout());
+        assert!(Error::DeadlineExceeded.is_timeout());
+
+        let error = Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk"));
+        assert!(!error.is_timeout());
+        assert!(!error.is_connection_error());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[tokio::test]
+    async fn decrypt_room_event_missing_key() {
+        let client = logged_in_client().await;
+        let room_id = room_id!("!test:localhost");
+
+        let event = serde_json::from_value(json!({
+            "content": {
+                "algorithm": "m.megolm.v1.aes-sha2",
+                "ciphertext": "AwgAEnACgAkLmt6qF84IK++J7UDH2Za1YVchHyprqTqsg",
+                "device_id": "SOMEDEVICE",
+                "sender_key": "IlRMeOPX2e0MurIyfWEucYBRVOEEUMrOHqn/8mLqMjA",
+                "session_id": "X3lUlvLELLYxeTx4yOVu6UDpasGEVO0Jbu+QFnm0cKQ"
+            },
+            "event_id": "$143273582443PhrSn:example.org",
+            "origin_server_ts": 1432735824653u64,
+            "sender": "@example:example.org",
+            "type": "m.room.encrypted"
+        }))
+        .unwrap();
+
+        let error = client
+            .decrypt_room_event(&event, &room_id)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, Error::Decryption(_)));
+        assert!(error.is_missing_key());
+
+        let error = Error::from(std::io::Error::new(std::io::ErrorKind::Other, "disk"));
+        assert!(!error.is_missing_key());
+    }
+
+    #[tokio::test]
+    async fn default_request_timeout() {
+        let config = ClientConfig::default()
+            .client(Arc::new(NeverResponds))
+            .request_timeout(Duration::from_millis(50));
+        let client = logged_in_client_with_config(config).await;
+
+        let response = client.devices().await;
+        assert!(matches!(
+            response,
+            Err(Error::RequestTimeout(t)) if t == Duration::from_millis(50)
+        ));
+
+        // Syncs get to wait for the sync timeout on top.
+        let sync_settings = SyncSettings::new().timeout(Duration::from_millis(100));
+        let response = client.sync_once(sync_settings).await;
+        assert!(matches!(
+            response,
+            Err(Error::RequestTimeout(t)) if t == Duration::from_millis(150)
+        ));
+    }
+
+    #[tokio::test]
+    async fn initial_sync_outlives_request_timeout() {
+        /// A `HttpSend` implementation that takes its time to answer.
+        #[derive(Debug)]
+        struct Slow;
+
+        #[async_trait]
+        impl HttpSend for Slow {
+            async fn send_request(
+                &self,
+                _: http::Request<Vec<u8>>,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                futures_timer::Delay::new(Duration::from_millis(200)).await;
+                Ok(http::Response::new(
+                    test_json::SYNC.to_string().into_bytes(),
+                ))
+            }
+        }
+
+        let config = ClientConfig::default()
+            .client(Arc::new(Slow))
+            .request_timeout(Duration::from_millis(50));
+        let client = logged_in_client_with_config(config).await;
+
+        // An initial sync has no sync timeout and isn't cut off.
+        client.sync_once(SyncSettings::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout_passed_to_http_send() {
+        /// A `HttpSend` implementation that records the timeouts it gets.
+        #[derive(Debug, Default)]
+        struct TimeoutRecorder(std::sync::Mutex<Vec<Option<Duration>>>);
+
+        #[async_trait]
+        impl HttpSend for TimeoutRecorder {
+            async fn send_request(
+                &self,
+                _: http::Request<Vec<u8>>,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                self.0.lock().unwrap().push(None);
+                Ok(http::Response::new(
+                    test_json::DEVICES.to_string().into_bytes(),
+                ))
+            }
+
+            async fn send_request_with_timeout(
+                &self,
+                _: http::Request<Vec<u8>>,
+                timeout: Duration,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                self.0.lock().unwrap().push(Some(timeout));
+                Ok(http::Response::new(
+                    test_json::DEVICES.to_string().into_bytes(),
+                ))
+            }
+        }
+
+        let recorder = Arc::new(TimeoutRecorder::default());
+        let config = ClientConfig::default()
+            .client(recorder.clone())
+            .request_timeout(Duration::from_secs(5));
+        let client = logged_in_client_with_config(config).await;
+
+        client.devices().await.unwrap();
+        client
+            .send_with_config(
+                get_devices::Request::new(),
+                RequestConfig::new().timeout(Duration::from_secs(10)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![Some(Duration::from_secs(5)), Some(Duration::from_secs(10))]
+        );
+    }
+
+    #[tokio::test]
+    async fn http_send_enforces_timeout() {
+        /// A `HttpSend` implementation that claims to enforce the timeout but
+        /// answers only after it passed.
+        #[derive(Debug)]
+        struct Slow;
+
+        #[async_trait]
+        impl HttpSend for Slow {
+            async fn send_request(
+                &self,
+                _: http::Request<Vec<u8>>,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                unreachable!("the request has a timeout")
+            }
+
+            async fn send_request_with_timeout(
+                &self,
+                _: http::Request<Vec<u8>>,
+                timeout: Duration,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                futures_timer::Delay::new(timeout * 4).await;
+                Ok(http::Response::new(
+                    test_json::DEVICES.to_string().into_bytes(),
+                ))
+            }
+
+            fn enforces_timeout(&self) -> bool {
+                true
+            }
+        }
+
+        let config = ClientConfig::default()
+            .client(Arc::new(Slow))
+            .request_timeout(Duration::from_millis(50));
+        let client = logged_in_client_with_config(config).await;
+
+        // The client leaves enforcing the timeout to the implementation.
+        client.devices().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reqwest_request_timeout() {
+        // A server that accepts connections but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let homeserver = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+
+        let config = ClientConfig::new()
+            .timeout(Duration::from_secs(10))
+            .request_timeout(Duration::from_millis(100));
+        let client = Client::new_with_config(homeserver, config).unwrap();
+
+        let started = Instant::now();
+
+        assert!(matches!(
+            client.send(get_supported_versions::Request::new()).await,
+            Err(Error::RequestTimeout(t)) if t == Duration::from_millis(100)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn session_lock_timeout() {
+        let config = ClientConfig::new().session_lock_timeout(Duration::from_millis(50));
+        let client = logged_in_client_with_config(config).await;
+
+        let session = client.base_client.session().clone();
+        let _guard = session.write().await;
+
+        let response = client.devices().await;
+
+        assert!(matches!(response, Err(Error::SessionLockTimeout(_))));
+    }
+
+    #[tokio::test]
+    async fn cancel_pending_request() {
+        let config = ClientConfig::default().client(Arc::new(NeverResponds));
+        let client = logged_in_client_with_config(config).await;
+
+        let task = tokio::spawn({
+            let client = client.clone();
+            async move { client.devices().await }
+        });
+
+        let pending = loop {
+            if let Some(request) = client.pending_requests().pop() {
+                break request;
+            }
+            tokio::task::yield_now().await;
+        };
+
+        assert_eq!(pending.method, http::Method::GET);
+        assert_eq!(pending.path, "/_matrix/client/r0/devices");
+
+        assert!(client.cancel_request(&pending.id));
+        assert!(matches!(task.await.unwrap(), Err(Error::Cancelled)));
+        assert!(client.pending_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cancel_request_during_retry_backoff() {
+        let config = ClientConfig::new().retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_secs(10))
+                .max_total_wait(Duration::from_secs(60)),
+        );
+        let client = logged_in_client_with_config(config).await;
+
+        let failed = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(500)
+            .expect(1)
+            .create();
+
+        let task = tokio::spawn({
+            let client = client.clone();
+            async move { client.devices().await }
+        });
+
+        // Give the first attempt time to fail, the request is then waiting
+        // for its retry.
+        futures_timer::Delay::new(Duration::from_millis(200)).await;
+        failed.assert();
+
+        let pending = client.pending_requests();
+        assert_eq!(pending.len(), 1);
+
+        assert!(client.cancel_request(&pending[0].id));
+        assert!(matches!(task.await.unwrap(), Err(Error::Cancelled)));
+        assert!(client.pending_requests().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn metrics_text() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+
+        let text = client.metrics_text();
+
+        assert!(text.contains("# TYPE matrix_sdk_requests_total counter"));
+        assert!(text.contains("matrix_sdk_requests_total{method=\"GET\",status=\"200\"} 1"));
+        assert!(text.contains("matrix_sdk_request_duration_seconds_count{method=\"GET\"} 1"));
+        assert!(text
+            .contains("matrix_sdk_request_duration_seconds_bucket{method=\"GET\",le=\"+Inf\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn request_config_headers() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .match_header("authorization", "Bearer 1234")
+            .match_header("x-tenant-id", "example")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        let request_config = RequestConfig::new()
+            .header(
+                http::header::HeaderName::from_static("x-tenant-id"),
+                http::HeaderValue::from_static("example"),
+            )
+            .header(
+                http::header::AUTHORIZATION,
+                http::HeaderValue::from_static("Bearer 5678"),
+            );
+
+        client
+            .send_with_config(get_devices::Request::new(), request_config)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_cancellable() {
+        let config = ClientConfig::default().client(Arc::new(NeverResponds));
+        let client = logged_in_client_with_config(config).await;
+
+        let (response, handle) = client.send_cancellable(get_devices::Request::new());
+        let task = tokio::spawn({
+            let client = client.clone();
+            async move {
+                while client.pending_requests().is_empty() {
+                    tokio::task::yield_now().await;
+                }
+
+                handle.abort();
+            }
+        });
+
+        assert!(matches!(response.await, Err(Error::Cancelled)));
+        task.await.unwrap();
+        assert!(client.pending_requests().is_empty());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn bandwidth_by_category() {
+        use crate::EndpointCategory;
+
+        let category = EndpointCategory::Custom("devices".to_owned());
+        let config = ClientConfig::new().endpoint_category("/devices", category.clone());
+        let client = logged_in_client_with_config(config).await;
+
+        let body = test_json::DEVICES.to_string();
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(&body)
+            .create();
+
+        client.devices().await.unwrap();
+
+        let usage = client.bandwidth_by_category();
+        let devices = usage.get(&category).unwrap();
+
+        assert_eq!(devices.requests, 1);
+        assert_eq!(devices.bytes_sent, 0);
+        assert_eq!(devices.bytes_received, body.len() as u64);
+        assert!(client
+            .metrics_text()
+            .contains("matrix_sdk_bytes_received_total{category=\"devices\"}"));
+    }
+
+    #[tokio::test]
+    async fn homeserver_health() {
+        let client = logged_in_client().await;
+        assert_eq!(client.homeserver_health_score(), 100);
+        assert_eq!(client.homeserver_health(), HomeserverHealth::Good);
+
+        let m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(500)
+            .with_body(json!({ "errcode": "M_UNKNOWN", "error": "Oops" }).to_string())
+            .create();
+
+        for _ in 0..3 {
+            client.devices().await.unwrap_err();
+        }
+
+        let degraded = client.homeserver_health_score();
+        assert_eq!(client.homeserver_health(), HomeserverHealth::Degraded);
+
+        drop(m);
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+        assert!(client.homeserver_health_score() > degraded);
+    }
+
+    #[tokio::test]
+    async fn server_timing() {
+        let client = logged_in_client().await;
+
+        let m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_header(
+                "server-timing",
+                "db;dur=53.2, total;dur=123.4;desc=\"Total\"",
+            )
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+
+        let timing = client.last_request_timing().unwrap();
+        assert_eq!(timing.server, Some(Duration::from_secs_f64(0.1234)));
+        assert!(timing.network().is_some());
+
+        drop(m);
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
+
+        let timing = client.last_request_timing().unwrap();
+        assert_eq!(timing.server, None);
+        assert_eq!(timing.network(), None);
+    }
+
+    #[tokio::test]
+    async fn empty_response_body() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .create();
+
+        let response = client.devices().await;
+
+        assert!(matches!(
+            response,
+            Err(Error::EmptyResponseBody { status }) if status == http::StatusCode::OK
+        ));
+    }
+
+    #[tokio::test]
+    async fn binary_response_body() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(&[0xff, 0xfe, 0x00, 0x80])
+            .create();
+
+        let response = client.devices().await;
+
+        assert!(matches!(
+            response,
+            Err(Error::InvalidResponseBody { status, .. }) if status == http::StatusCode::OK
+        ));
+    }
+
+    #[tokio::test]
+    async fn devices() {
+        let client = logged_in_client().await;
+
+        let _m = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        assert!(client.devices().await.is_ok());
+    }
+
+    #[test]
+    fn base_path_joining() {
+        let homeserver = Url::parse("https://example.org").unwrap();
+
+        assert_eq!(join_base_path(&homeserver, ""), homeserver);
+        assert_eq!(join_base_path(&homeserver, "/"), homeserver);
+        assert_eq!(
+            join_base_path(&homeserver, "/matrix").as_str(),
+            "https://example.org/matrix"
+        );
+        assert_eq!(
+            join_base_path(&homeserver, "matrix/"),
+            join_base_path(&homeserver, "/matrix")
+        );
+
+        let homeserver = Url::parse("https://example.org/proxy/").unwrap();
+        assert_eq!(
+            join_base_path(&homeserver, "/matrix").as_str(),
+            "https://example.org/proxy/matrix"
+        );
+    }
+
+    #[test]
+    fn proxy_with_auth() {
+        let config = ClientConfig::new()
+            .proxy_with_auth("http://localhost:8080", "user", "secret")
+            .unwrap();
+
+        assert!(config.proxy.is_some());
+        assert!(!format!("{:?}", config).contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn base_path() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
+        };
+
+        let config = ClientConfig::new().base_path("/matrix");
+        let client = Client::new_with_config(homeserver.clone(), config).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        let _m = mock("GET", "/matrix/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        assert!(client.devices().await.is_ok());
+        assert_eq!(client.homeserver(), &homeserver);
+    }
+
+    #[tokio::test]
+    async fn retry_rate_limited_request() {
+        let config = ClientConfig::new().retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_total_wait(Duration::from_secs(1)),
+        );
+        let client = logged_in_client_with_config(config).await;
+
+        {
+            let limited = mock("GET", "/_matrix/client/r0/devices")
+                .with_status(429)
+                .with_body(
+                    json!({
+                        "errcode": "M_LIMIT_EXCEEDED",
+                        "error": "Too many requests",
+                        "retry_after_ms": 10
+                    })
+                    .to_string(),
+                )
+                .expect(1)
+                .create();
+
+            let ok = mock("GET", "/_matrix/client/r0/devices")
+                .with_status(200)
+                .with_body(test_json::DEVICES.to_string())
+                .create();
+
+            client.devices().await.unwrap();
+
+            limited.assert();
+            ok.assert();
+        }
+
+        // Don't wait longer than the configured total wait time.
+        let limited = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(429)
+            .with_header("retry-after", "60")
+            .with_body(json!({ "errcode": "M_LIMIT_EXCEEDED", "error": "Slow down" }).to_string())
+            .expect(1)
+            .create();
+
+        let started = Instant::now();
+        assert!(client.devices().await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        limited.assert();
+    }
+
+    #[tokio::test]
+    async fn retried_room_send_keeps_transaction_id() {
+        let config = ClientConfig::new().retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_total_wait(Duration::from_secs(1)),
+        );
+        let client = logged_in_client_with_config(config).await;
+
+        let txn_id = matrix_sdk_common::uuid::Uuid::new_v4();
+        let path = format!(
+            r"^/_matrix/client/r0/rooms/.*/send/m.room.message/{}$",
+            txn_id
+        );
+
+        let limited = mock("PUT", Matcher::Regex(path.clone()))
+            .with_status(429)
+            .with_body(
+                json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                    "retry_after_ms": 10
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let ok = mock("PUT", Matcher::Regex(path))
+            .with_status(200)
+            .with_body(test_json::EVENT_ID.to_string())
+            .expect(1)
+            .create();
+
+        let room_id = room_id!("!testroom:example.org");
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+
+        client
+            .room_send(&room_id, content, Some(txn_id))
+            .await
+            .unwrap();
+
+        limited.assert();
+        ok.assert();
+    }
+
+    #[tokio::test]
+    async fn retryable_paths() {
+        let config = ClientConfig::new().retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_total_wait(Duration::from_secs(1))
+                .retryable_paths(vec!["/_matrix/client/r0/logout"]),
+        );
+        let client = logged_in_client_with_config(config).await;
+
+        // A POST request to an allowed endpoint is retried.
+        let failed = mock("POST", "/_matrix/client/r0/logout")
+            .with_status(500)
+            .expect(1)
+            .create();
+        let ok = mock("POST", "/_matrix/client/r0/logout")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+
+        client.send(logout::Request::new()).await.unwrap();
+
+        failed.assert();
+        ok.assert();
+
+        // A GET request to any other endpoint isn't.
+        let failed = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(500)
+            .expect(1)
+            .create();
+
+        assert!(client.devices().await.is_err());
+
+        failed.assert();
+    }
+
+    #[tokio::test]
+    async fn retryable_paths_with_base_path() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let session = Session {
+            access_token: "1234".to_owned(),
+            user_id: user_id!("@example:localhost"),
+            device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
+        };
+
+        let config = ClientConfig::new().base_path("/matrix").retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_total_wait(Duration::from_secs(1))
+                .retryable_paths(vec![
+                    "/_matrix/client/r0/logout",
+                    "/_matrix/client/r0/device",
+                ]),
+        );
+        let client = Client::new_with_config(homeserver, config).unwrap();
+        client.restore_login(session).await.unwrap();
+
+        // The allow-list is matched without the base path.
+        let failed = mock("POST", "/matrix/_matrix/client/r0/logout")
+            .with_status(500)
+            .expect(1)
+            .create();
+        let ok = mock("POST", "/matrix/_matrix/client/r0/logout")
+            .with_status(200)
+            .with_body("{}")
+            .create();
+
+        client.send(logout::Request::new()).await.unwrap();
+
+        failed.assert();
+        ok.assert();
+
+        // Only whole path segments match.
+        let failed = mock("GET", "/matrix/_matrix/client/r0/devices")
+            .with_status(500)
+            .expect(1)
+            .create();
+
+        assert!(client.devices().await.is_err());
+
+        failed.assert();
+    }
+
+    #[tokio::test]
+    async fn fail_fast_on_rate_limit() {
+        let config = ClientConfig::new().retry_config(
+            RetryConfig::new()
+                .initial_delay(Duration::from_millis(10))
+                .max_total_wait(Duration::from_secs(1)),
+        );
+        let client = logged_in_client_with_config(config).await;
+
+        let limited = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(429)
+            .with_body(
+                json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                    "retry_after_ms": 10
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let error = client
+            .send_with_config(
+                get_devices::Request::new(),
+                RequestConfig::new().retry_on_rate_limit(false),
+            )
+            .await
+            .unwrap_err();
+
+        assert!(error.is_rate_limited());
+        assert_eq!(error.retry_after(), Some(Duration::from_millis(10)));
+        limited.assert();
+    }
+
+    #[tokio::test]
+    async fn retry_handler() {
+        #[derive(Debug, Default)]
+        struct Recorder(std::sync::Mutex<Vec<crate::RetryInfo>>);
+
+        impl RetryHandler for Recorder {
+            fn on_retry(&self, retry: &crate::RetryInfo) {
+                self.0.lock().unwrap().push(retry.clone());
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let config = ClientConfig::new()
+            .retry_config(
+                RetryConfig::new()
+                    .initial_delay(Duration::from_millis(10))
+                    .max_total_wait(Duration::from_secs(1)),
+            )
+            .retry_handler(recorder.clone());
+        let client = logged_in_client_with_config(config).await;
+
+        let _limited = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(429)
+            .with_body(
+                json!({
+                    "errcode": "M_LIMIT_EXCEEDED",
+                    "error": "Too many requests",
+                    "retry_after_ms": 20
+                })
+                .to_string(),
+            )
+            .expect(1)
+            .create();
+
+        let _failed = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(500)
+            .with_body(json!({ "errcode": "M_UNKNOWN", "error": "Oops" }).to_string())
+            .expect(1)
+            .create();
+
+        let _ok = mock("GET", "/_matrix/client/r0/devices")
+            .with_status(200)
+            .with_body(test_json::DEVICES.to_string())
+            .create();
+
+        client.devices().await.unwrap();
 
-    async fn logged_in_client() -> Client {
-        let session = Session {
-            access_token: "1234".to_owned(),
-            user_id: user_id!("@example:localhost"),
-            device_id: "DEVICEID".into(),
-        };
-        let homeserver = url::Url::parse(&mockito::server_url()).unwrap();
-        let client = Client::new(homeserver).unwrap();
-        client.restore_login(session).await.unwrap();
+        let retries = recorder.0.lock().unwrap();
+        assert_eq!(retries.len(), 2);
 
-        client
+        assert_eq!(retries[0].attempt, 1);
+        assert_eq!(retries[0].reason, crate::RetryReason::RateLimited);
+        assert_eq!(retries[0].delay, Duration::from_millis(20));
+        assert_eq!(retries[0].path, "/_matrix/client/r0/devices");
+
+        assert_eq!(retries[1].attempt, 2);
+        assert_eq!(retries[1].reason, crate::RetryReason::Transient);
     }
 
+    #[cfg(feature = "gzip")]
     #[tokio::test]
-    async fn login() {
-        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+    async fn decompression_limit() {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+
+        fn gzip(body: &[u8]) -> Vec<u8> {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+            encoder.write_all(body).unwrap();
+            encoder.finish().unwrap()
+        }
 
-        let _m = mock("POST", "/_matrix/client/r0/login")
+        let config = ClientConfig::new()
+            .gzip(true)
+            .max_decompressed_size(64 * 1024);
+        let client = logged_in_client_with_config(config).await;
+
+        let ok = mock("GET", "/_matrix/client/r0/devices")
             .with_status(200)
-            .with_body(test_json::LOGIN.to_string())
+            .with_header("content-encoding", "gzip")
+            .with_body(gzip(test_json::DEVICES.to_string().as_bytes()))
+            .expect(1)
             .create();
 
-        let client = Client::new(homeserver).unwrap();
-
-        client
-            .login("example", "wordpass", None, None)
-            .await
-            .unwrap();
-
-        let logged_in = client.logged_in().await;
-        assert!(logged_in, "Client should be logged in");
-    }
+        client.devices().await.unwrap();
+        ok.assert();
 
-    #[tokio::test]
-    async fn devices() {
-        let client = logged_in_client().await;
+        // A few kilobytes on the wire, but 16 MiB once decompressed.
+        let bomb = gzip(&vec![b' '; 16 * 1024 * 1024]);
+        assert!(bomb.len() < 64 * 1024);
 
         let _m = mock("GET", "/_matrix/client/r0/devices")
             .with_status(200)
-            .with_body(test_json::DEVICES.to_string())
+            .with_header("content-encoding", "gzip")
+            .with_body(bomb)
             .create();
 
-        assert!(client.devices().await.is_ok());
+        assert!(matches!(
+            client.devices().await,
+            Err(Error::DecompressionLimitExceeded(limit)) if limit == 64 * 1024
+        ));
     }
 
     #[tokio::test]
@@ -2356,6 +6202,8 @@ mod test {
             access_token: "1234".to_owned(),
             user_id: user_id!("@example:localhost"),
             device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
         };
 
         let _m = mock(
@@ -2499,6 +6347,108 @@ mod test {
         }
     }
 
+    #[tokio::test]
+    async fn registration_requirements() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+
+        let _m = mock("POST", "/_matrix/client/r0/register")
+            .with_status(401)
+            .with_body(
+                json!({
+                    "flows": [
+                        { "stages": ["m.login.registration_token", "m.login.terms"] },
+                        { "stages": ["m.login.registration_token", "m.login.email.identity"] }
+                    ],
+                    "params": {},
+                    "session": "probe-session"
+                })
+                .to_string(),
+            )
+            .create();
+
+        let requirements = client.registration_requirements().await.unwrap();
+
+        assert!(!requirements.is_disabled());
+        assert!(requirements.requires_registration_token());
+        assert!(!requirements.requires_terms());
+        assert!(!requirements.requires_email());
+        assert!(!requirements.requires_recaptcha());
+
+        if let RegistrationRequirements::Enabled { flows, session } = requirements {
+            assert_eq!(flows.len(), 2);
+            assert_eq!(session.as_deref(), Some("probe-session"));
+        } else {
+            panic!("registration should be enabled");
+        }
+    }
+
+    #[test]
+    fn resume_uiaa_auth() {
+        let mut info: UiaaInfo = serde_json::from_value(json!({
+            "flows": [
+                { "stages": ["m.login.sso", "m.login.terms"] },
+                { "stages": ["m.login.password"] }
+            ],
+            "params": {},
+            "session": "session-id"
+        }))
+        .unwrap();
+
+        let auth = crate::resume_uiaa_auth(&info, &["m.login.sso"]).unwrap();
+        assert!(matches!(
+            auth,
+            AuthData::FallbackAcknowledgement {
+                session: "session-id"
+            }
+        ));
+
+        assert!(matches!(
+            crate::resume_uiaa_auth(&info, &["m.login.terms"]),
+            Err(Error::UiaaNoProgress)
+        ));
+        assert!(matches!(
+            crate::resume_uiaa_auth(&info, &[]),
+            Err(Error::UiaaNoProgress)
+        ));
+
+        info.completed = vec!["m.login.sso".to_owned()];
+        assert!(crate::resume_uiaa_auth(&info, &["m.login.terms"]).is_ok());
+
+        info.session = None;
+        assert!(matches!(
+            crate::resume_uiaa_auth(&info, &["m.login.terms"]),
+            Err(Error::UiaaSessionMissing)
+        ));
+    }
+
+    #[tokio::test]
+    async fn registration_requirements_without_auth() {
+        let homeserver = Url::from_str(&mockito::server_url()).unwrap();
+        let client = Client::new(homeserver).unwrap();
+
+        // The probe must not log in to an account it might have created.
+        let m = mock("POST", "/_matrix/client/r0/register")
+            .match_body(Matcher::PartialJson(json!({ "inhibit_login": true })))
+            .with_status(200)
+            .with_body(json!({ "user_id": "@generated:localhost" }).to_string())
+            .expect(1)
+            .create();
+
+        let requirements = client.registration_requirements().await.unwrap();
+
+        assert!(!requirements.is_disabled());
+        assert!(!requirements.requires_registration_token());
+        assert_eq!(
+            requirements,
+            RegistrationRequirements::AccountCreated {
+                user_id: user_id!("@generated:localhost")
+            }
+        );
+        assert!(!client.logged_in().await);
+        m.assert();
+    }
+
     #[tokio::test]
     async fn register_error() {
         let homeserver = Url::from_str(&mockito::server_url()).unwrap();
@@ -2838,6 +6788,39 @@ mod test {
         assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
     }
 
+    #[tokio::test]
+    async fn room_message_send_with_custom_transaction_id() {
+        #[derive(Debug)]
+        struct StaticId;
+
+        impl TransactionIdGenerator for StaticId {
+            fn generate(&self) -> String {
+                "bridge-txn-1".to_owned()
+            }
+        }
+
+        let config = ClientConfig::default().transaction_id_generator(Arc::new(StaticId));
+        let client = logged_in_client_with_config(config).await;
+
+        let _m = mock(
+            "PUT",
+            Matcher::Regex(
+                r"^/_matrix/client/r0/rooms/.*/send/m.room.message/bridge-txn-1".to_string(),
+            ),
+        )
+        .with_status(200)
+        .with_body(test_json::EVENT_ID.to_string())
+        .create();
+
+        let room_id = room_id!("!testroom:example.org");
+        let content =
+            AnyMessageEventContent::RoomMessage(MessageEventContent::text_plain("Hello world"));
+
+        let response = client.room_send(&room_id, content, None).await.unwrap();
+
+        assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
+    }
+
     #[tokio::test]
     async fn room_attachment_send() {
         let client = logged_in_client().await;
@@ -2877,6 +6860,178 @@ mod test {
         assert_eq!(event_id!("$h29iv0s8:example.com"), response.event_id)
     }
 
+    #[tokio::test]
+    async fn upload_uses_media_client() {
+        let config = ClientConfig::new()
+            .client(Arc::new(NeverResponds))
+            .media_client(Arc::new(reqwest::Client::new()));
+        let client = logged_in_client_with_config(config).await;
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .with_body(
+            json!({
+              "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+            })
+            .to_string(),
+        )
+        .create();
+
+        let mut media = Cursor::new("Hello world");
+        let response = client.upload(&mime::IMAGE_JPEG, &mut media).await.unwrap();
+
+        assert_eq!(
+            response.content_uri,
+            "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        );
+    }
+
+    #[tokio::test]
+    async fn download() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(
+                r"^/_matrix/media/r0/download/example.com/AQwafuaFswefuhsfAFAgsw\?allow_remote=false"
+                    .to_string(),
+            ),
+        )
+        .with_status(200)
+        .with_header("content-type", "image/jpeg")
+        .with_body("Hello world")
+        .create();
+
+        let response = client
+            .download("mxc://example.com/AQwafuaFswefuhsfAFAgsw", false)
+            .await
+            .unwrap();
+
+        assert_eq!(response.file, b"Hello world");
+        assert_eq!(response.content_type.as_deref(), Some("image/jpeg"));
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/thumbnail/example.com/missing".to_string()),
+        )
+        .with_status(404)
+        .with_body(json!({ "errcode": "M_NOT_FOUND", "error": "Not found" }).to_string())
+        .create();
+
+        assert!(matches!(
+            client
+                .thumbnail(
+                    "mxc://example.com/missing",
+                    32,
+                    32,
+                    get_content_thumbnail::Method::Crop,
+                    true
+                )
+                .await,
+            Err(Error::MediaNotFound(uri)) if uri == "mxc://example.com/missing"
+        ));
+
+        assert!(matches!(
+            client.download("https://example.com/media", true).await,
+            Err(Error::InvalidMxcUri(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn upload_stream() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()),
+        )
+        .with_status(200)
+        .match_header("authorization", "Bearer 1234")
+        .match_header("content-type", "application/octet-stream")
+        .match_body("first chunk, second chunk")
+        .with_body(
+            json!({
+              "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+            })
+            .to_string(),
+        )
+        .create();
+
+        let chunks = vec![
+            Ok(Bytes::from("first chunk, ")),
+            Ok(Bytes::from("second chunk")),
+        ];
+
+        let response = client
+            .upload_stream(&mime::APPLICATION_OCTET_STREAM, stream::iter(chunks))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            response.content_uri,
+            "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+        );
+
+        let chunks = vec![
+            Ok(Bytes::from("first chunk, ")),
+            Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "recording failed",
+            )),
+        ];
+
+        assert!(client
+            .upload_stream(&mime::APPLICATION_OCTET_STREAM, stream::iter(chunks))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn upload_stream_with_progress() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "POST",
+            Matcher::Regex(r"^/_matrix/media/r0/upload".to_string()),
+        )
+        .with_status(200)
+        .match_body("first chunk, second chunk")
+        .with_body(
+            json!({
+              "content_uri": "mxc://example.com/AQwafuaFswefuhsfAFAgsw"
+            })
+            .to_string(),
+        )
+        .create();
+
+        let chunks = vec![
+            Ok(Bytes::from("first chunk, ")),
+            Ok(Bytes::from("second chunk")),
+        ];
+
+        let updates = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorder = updates.clone();
+
+        client
+            .upload_stream_with_progress(
+                &mime::APPLICATION_OCTET_STREAM,
+                stream::iter(chunks),
+                Some(25),
+                move |sent, total| recorder.lock().unwrap().push((sent, total)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *updates.lock().unwrap(),
+            vec![(13, Some(25)), (25, Some(25))]
+        );
+    }
+
     #[tokio::test]
     async fn user_presence() {
         let client = logged_in_client().await;
@@ -3075,4 +7230,166 @@ mod test {
             }
         }
     }
+
+    #[tokio::test]
+    async fn download_to_writer_resumes() {
+        use crate::http_client::DOWNLOAD_CHUNK_SIZE;
+
+        let client = logged_in_client().await;
+        let path = Matcher::Regex(r"^/_matrix/media/r0/download/example.com/large".to_string());
+
+        let media: Vec<u8> = (0..DOWNLOAD_CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let chunk = DOWNLOAD_CHUNK_SIZE as usize;
+        let total = media.len();
+
+        let first = mock("GET", path.clone())
+            .match_header("range", format!("bytes=0-{}", chunk - 1).as_str())
+            .with_status(206)
+            .with_header("content-range", &format!("bytes 0-{}/{}", chunk - 1, total))
+            .with_body(&media[..chunk])
+            .expect(1)
+            .create();
+
+        // The connection breaks down, the download continues with the
+        // second chunk.
+        let failed = mock("GET", path.clone())
+            .match_header(
+                "range",
+                format!("bytes={}-{}", chunk, 2 * chunk - 1).as_str(),
+            )
+            .with_status(502)
+            .expect(1)
+            .create();
+
+        let second = mock("GET", path)
+            .match_header(
+                "range",
+                format!("bytes={}-{}", chunk, 2 * chunk - 1).as_str(),
+            )
+            .with_status(206)
+            .with_header(
+                "content-range",
+                &format!("bytes {}-{}/{}", chunk, total - 1, total),
+            )
+            .with_body(&media[chunk..])
+            .expect(1)
+            .create();
+
+        let mut file = Vec::new();
+        let written = client
+            .download_to_writer("mxc://example.com/large", true, &mut file)
+            .await
+            .unwrap();
+
+        assert_eq!(written, total as u64);
+        assert_eq!(file, media);
+
+        first.assert();
+        failed.assert();
+        second.assert();
+    }
+
+    #[tokio::test]
+    async fn download_to_writer_without_range_support() {
+        let client = logged_in_client().await;
+
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/download/example.com/small".to_string()),
+        )
+        .with_status(200)
+        .with_body("Hello world")
+        .create();
+
+        let mut file = Vec::new();
+        let written = client
+            .download_to_writer("mxc://example.com/small", true, &mut file)
+            .await
+            .unwrap();
+
+        assert_eq!(written, 11);
+        assert_eq!(file, b"Hello world");
+
+        // A partial response for a different range than requested is
+        // rejected.
+        let _m = mock(
+            "GET",
+            Matcher::Regex(r"^/_matrix/media/r0/download/example.com/wrong".to_string()),
+        )
+        .with_status(206)
+        .with_header("content-range", "bytes 5-10/11")
+        .with_body(" world")
+        .create();
+
+        assert!(matches!(
+            client
+                .download_to_writer("mxc://example.com/wrong", true, &mut Vec::new())
+                .await,
+            Err(Error::InconsistentDownload(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn download_to_writer_waits_while_unavailable() {
+        let client = logged_in_client().await;
+        let path = Matcher::Regex(r"^/_matrix/media/r0/download/example.com/busy".to_string());
+
+        let unavailable = mock("GET", path.clone())
+            .with_status(503)
+            .with_header("retry-after", "1")
+            .expect(1)
+            .create();
+        let ok = mock("GET", path)
+            .with_status(200)
+            .with_body("Hello world")
+            .expect(1)
+            .create();
+
+        let started = Instant::now();
+        let mut file = Vec::new();
+        client
+            .download_to_writer("mxc://example.com/busy", true, &mut file)
+            .await
+            .unwrap();
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(file, b"Hello world");
+
+        unavailable.assert();
+        ok.assert();
+    }
+
+    #[tokio::test]
+    async fn download_to_writer_gives_up_on_permanent_errors() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug, Default)]
+        struct Broken(AtomicUsize);
+
+        #[async_trait]
+        impl HttpSend for Broken {
+            async fn send_request(
+                &self,
+                _: http::Request<Vec<u8>>,
+            ) -> crate::Result<http::Response<Vec<u8>>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+
+                // An error that isn't a connection failure or a timeout.
+                let error = reqwest::Client::new().get("not a url").build().unwrap_err();
+                Err(Error::Reqwest(error))
+            }
+        }
+
+        let media = Arc::new(Broken::default());
+        let config = ClientConfig::new().media_client(media.clone());
+        let client = logged_in_client_with_config(config).await;
+
+        assert!(matches!(
+            client
+                .download_to_writer("mxc://example.com/broken", true, &mut Vec::new())
+                .await,
+            Err(Error::Reqwest(_))
+        ));
+        assert_eq!(media.0.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,82 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Suggestions for the display names of new devices.
+
+/// Get the name of the operating system we're running on.
+#[cfg(not(target_arch = "wasm32"))]
+fn os() -> &'static str {
+    match std::env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "macOS",
+        "windows" => "Windows",
+        "android" => "Android",
+        "ios" => "iOS",
+        "freebsd" => "FreeBSD",
+        other => other,
+    }
+}
+
+/// Get the name of the operating system we're running on.
+#[cfg(target_arch = "wasm32")]
+fn os() -> &'static str {
+    "Web"
+}
+
+/// Get the host name of the machine we're running on, if it's known.
+#[cfg(not(target_arch = "wasm32"))]
+fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .filter(|h| !h.is_empty())
+}
+
+/// Get the host name of the machine we're running on, if it's known.
+#[cfg(target_arch = "wasm32")]
+fn hostname() -> Option<String> {
+    None
+}
+
+/// Compose a display name for a new device from the given template.
+///
+/// The template can contain the following placeholders:
+///
+/// * `{os}` - The name of the operating system, e.g. `Linux`, or `Web` on
+/// WASM.
+///
+/// * `{hostname}` - The host name of the machine, if it can't be detected,
+/// e.g. on WASM, the placeholder and the separator in front of it are
+/// dropped.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk::device_display_name;
+///
+/// let name = device_display_name("My App on {os} ({hostname})");
+/// assert!(name.starts_with("My App on "));
+/// ```
+pub fn device_display_name(template: &str) -> String {
+    let name = template.replace("{os}", os());
+
+    match hostname() {
+        Some(hostname) => name.replace("{hostname}", &hostname),
+        None => name
+            .replace(" ({hostname})", "")
+            .replace(", {hostname}", "")
+            .replace(" {hostname}", "")
+            .replace("{hostname}", ""),
+    }
+}
@@ -14,9 +14,11 @@
 
 //! Error conditions.
 
+use http::{Error as HttpError, StatusCode};
 use matrix_sdk_base::{Error as MatrixError, StoreError};
 use matrix_sdk_common::{
     api::{
+        error::ErrorKind,
         r0::uiaa::{UiaaInfo, UiaaResponse as UiaaError},
         Error as RumaClientError,
     },
@@ -24,11 +26,12 @@ use matrix_sdk_common::{
 };
 use reqwest::Error as ReqwestError;
 use serde_json::Error as JsonError;
-use std::io::Error as IoError;
+use std::{io::Error as IoError, str::Utf8Error, time::Duration};
 use thiserror::Error;
+use url::Url;
 
 #[cfg(feature = "encryption")]
-use matrix_sdk_base::crypto::store::CryptoStoreError;
+use matrix_sdk_base::crypto::{store::CryptoStoreError, MegolmError};
 
 /// Result type of the rust-sdk.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -44,6 +47,11 @@ pub enum Error {
     #[error("the queried endpoint is not meant for clients")]
     NotClientRequest,
 
+    /// Queried endpoint requires authentication but the request was meant for
+    /// a different homeserver, our access token is never sent to one.
+    #[error("the queried endpoint needs an access token, which isn't sent to other homeservers")]
+    AuthenticationNotForwarded,
+
     /// An error at the HTTP layer.
     #[error(transparent)]
     Reqwest(#[from] ReqwestError),
@@ -64,6 +72,10 @@ pub enum Error {
     #[error("can't convert between ruma_client_api and hyper types.")]
     IntoHttp(RumaIntoHttpError),
 
+    /// An error building a HTTP request, e.g. from a malformed queued request.
+    #[error(transparent)]
+    Http(#[from] HttpError),
+
     /// An error occurred in the Matrix client library.
     #[error(transparent)]
     MatrixError(#[from] MatrixError),
@@ -73,6 +85,12 @@ pub enum Error {
     #[error(transparent)]
     CryptoStoreError(#[from] CryptoStoreError),
 
+    /// An event couldn't be decrypted, e.g. because the room key that is
+    /// needed to decrypt it is missing or the ciphertext is corrupt.
+    #[cfg(feature = "encryption")]
+    #[error(transparent)]
+    Decryption(#[from] MegolmError),
+
     /// An error occured in the state store.
     #[error(transparent)]
     StateStore(#[from] StoreError),
@@ -84,6 +102,163 @@ pub enum Error {
     /// represents an error with information about how to authenticate the user.
     #[error("User-Interactive Authentication required.")]
     UiaaError(RumaResponseError<UiaaError>),
+
+    /// An UIAA session should be resumed but the homeserver didn't start a
+    /// session.
+    #[error("the User-Interactive Authentication response doesn't contain a session")]
+    UiaaSessionMissing,
+
+    /// The stages that were completed out-of-band don't advance any of the
+    /// flows the homeserver offered.
+    #[error("the completed stages don't advance any of the offered authentication flows")]
+    UiaaNoProgress,
+
+    /// The request was cancelled before a response was received.
+    #[error("the request was cancelled")]
+    Cancelled,
+
+    /// The homeserver responded with an empty body where content was
+    /// expected.
+    #[error("the homeserver sent an empty response body (status {status})")]
+    EmptyResponseBody {
+        /// The status code of the response.
+        status: StatusCode,
+    },
+
+    /// The homeserver responded with a body that isn't valid UTF-8 where JSON
+    /// was expected.
+    #[error("the homeserver sent a response body that isn't valid UTF-8 (status {status})")]
+    InvalidResponseBody {
+        /// The status code of the response.
+        status: StatusCode,
+        /// The error that occurred while decoding the body.
+        source: Utf8Error,
+    },
+
+    /// The response of the homeserver couldn't be deserialized.
+    ///
+    /// This is only returned if capturing invalid responses was enabled with
+    /// [`ClientConfig::capture_invalid_responses`], otherwise the error of
+    /// the deserialization is returned as is.
+    ///
+    /// [`ClientConfig::capture_invalid_responses`]: struct.ClientConfig.html#method.capture_invalid_responses
+    #[error("can't deserialize the response of the homeserver (status {status})")]
+    ResponseDeserialization {
+        /// The status code of the response.
+        status: StatusCode,
+        /// The raw body of the response.
+        body: Vec<u8>,
+        /// The error that occurred while deserializing the response.
+        source: Box<Error>,
+    },
+
+    /// No response was received within the timeout of the request, see
+    /// [`RequestConfig::timeout`].
+    ///
+    /// [`RequestConfig::timeout`]: struct.RequestConfig.html#method.timeout
+    #[error("no response was received within {0:?}")]
+    RequestTimeout(Duration),
+
+    /// No response was received before the deadline of the request passed.
+    #[error("the deadline of the request was exceeded")]
+    DeadlineExceeded,
+
+    /// The session couldn't be accessed within the configured timeout, the
+    /// session lock is most likely held by a stuck task.
+    #[error("couldn't acquire the session lock within {0:?}")]
+    SessionLockTimeout(Duration),
+
+    /// The homeserver refused the request because the user didn't yet consent
+    /// to the terms of the homeserver.
+    ///
+    /// The user needs to visit the consent URI and accept the terms, after
+    /// that the request can be retried.
+    #[error("the user needs to consent to the homeserver terms: {message}")]
+    ConsentNotGiven {
+        /// The human readable error message the homeserver sent.
+        message: String,
+        /// The URI the user needs to visit to give consent.
+        consent_uri: Url,
+    },
+
+    /// The homeserver invalidated the access token without logging the device
+    /// out, i.e. it responded with a `M_UNKNOWN_TOKEN` error that has
+    /// `soft_logout` set.
+    ///
+    /// The user needs to log in again to get a new access token, unlike after
+    /// a hard logout the device and its encryption keys stay valid and the
+    /// local data doesn't need to be wiped.
+    #[error("the access token was invalidated, the user needs to log in again: {message}")]
+    SoftLogout {
+        /// The human readable error message the homeserver sent.
+        message: String,
+    },
+
+    /// The homeserver is temporarily unavailable, e.g. because it's being
+    /// restarted, and responded with a `503 Service Unavailable` that
+    /// contains a `Retry-After` header.
+    #[error("the homeserver is temporarily unavailable, retry after {retry_after:?}")]
+    ServiceUnavailable {
+        /// The time the homeserver asked us to wait before retrying.
+        retry_after: Duration,
+    },
+
+    /// The given URI isn't a valid `mxc://` URI.
+    #[error("invalid mxc URI: {0}")]
+    InvalidMxcUri(String),
+
+    /// The homeserver doesn't know the requested media, or it's a remote
+    /// media file and fetching remote media wasn't allowed.
+    #[error("the media {0} couldn't be found")]
+    MediaNotFound(String),
+
+    /// A download that was resumed with a range request doesn't fit together,
+    /// e.g. the homeserver sent a different part of the media than requested
+    /// or the downloaded media doesn't have the announced length.
+    #[error("the downloaded media is inconsistent: {0}")]
+    InconsistentDownload(String),
+
+    /// The homeserver refused the request because it exceeded one of its
+    /// resource limits, e.g. the number of monthly active users.
+    #[error("the homeserver exceeded a resource limit: {}", .0.message)]
+    ResourceLimitExceeded(ResourceLimitInfo),
+
+    /// The given hostname isn't a valid DNS name.
+    #[error("invalid hostname: {0}")]
+    InvalidHostname(String),
+
+    /// The certificate of the server isn't valid for the hostname that was
+    /// configured with [`ClientConfig::tls_hostname`].
+    ///
+    /// [`ClientConfig::tls_hostname`]: struct.ClientConfig.html#method.tls_hostname
+    #[error("the certificate of the server isn't valid for the expected hostname")]
+    HostnameMismatch,
+
+    /// A [`ClientConfig::tls_hostname`] was combined with additional root
+    /// certificates or a client identity, which isn't supported.
+    ///
+    /// [`ClientConfig::tls_hostname`]: struct.ClientConfig.html#method.tls_hostname
+    #[error("a TLS hostname can't be combined with root certificates or a client identity")]
+    UnsupportedTlsConfig,
+
+    /// The decompressed response body grew larger than the limit that was
+    /// configured with [`ClientConfig::max_decompressed_size`].
+    ///
+    /// [`ClientConfig::max_decompressed_size`]: struct.ClientConfig.html#method.max_decompressed_size
+    #[error("the decompressed response body exceeded the limit of {0} bytes")]
+    DecompressionLimitExceeded(usize),
+}
+
+/// Information about the resource limit a homeserver exceeded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceLimitInfo {
+    /// The human readable error message the homeserver sent.
+    pub message: String,
+    /// The kind of limit that was exceeded, e.g. `monthly_active_user`.
+    pub limit_type: Option<String>,
+    /// A URI the user can use to contact the administrator of the homeserver,
+    /// e.g. `mailto:admin@example.org`.
+    pub admin_contact: String,
 }
 
 impl Error {
@@ -108,6 +283,138 @@ impl Error {
             None
         }
     }
+
+    /// Get the URI the user needs to visit to consent to the terms of the
+    /// homeserver, if the request failed because consent wasn't given.
+    pub fn consent_uri(&self) -> Option<&Url> {
+        if let Error::ConsentNotGiven { consent_uri, .. } = self {
+            Some(consent_uri)
+        } else {
+            None
+        }
+    }
+
+    /// Get information about the exceeded resource limit, if the request
+    /// failed because the homeserver exceeded one of its resource limits.
+    ///
+    /// Clients should show this to the user instead of a generic error, the
+    /// user can't do anything about it besides contacting the administrator of
+    /// the homeserver.
+    pub fn resource_limit_info(&self) -> Option<&ResourceLimitInfo> {
+        if let Error::ResourceLimitExceeded(info) = self {
+            Some(info)
+        } else {
+            None
+        }
+    }
+
+    /// Get the HTTP status code of the response the homeserver sent, if this
+    /// error was caused by an error response.
+    ///
+    /// Returns `None` for errors that didn't come from the homeserver, e.g.
+    /// connection, serialization or IO failures.
+    pub fn status_code(&self) -> Option<StatusCode> {
+        match self {
+            Error::RumaResponse(RumaResponseError::Http(ServerError::Known(e))) => {
+                Some(e.status_code)
+            }
+            Error::UiaaError(RumaResponseError::Http(ServerError::Known(e))) => match e {
+                UiaaError::AuthResponse(_) => Some(StatusCode::UNAUTHORIZED),
+                UiaaError::MatrixError(e) => Some(e.status_code),
+            },
+            Error::Reqwest(e) => e.status(),
+            Error::EmptyResponseBody { status }
+            | Error::InvalidResponseBody { status, .. }
+            | Error::ResponseDeserialization { status, .. } => Some(*status),
+            Error::ConsentNotGiven { .. } => Some(StatusCode::FORBIDDEN),
+            Error::SoftLogout { .. } => Some(StatusCode::UNAUTHORIZED),
+            Error::ServiceUnavailable { .. } => Some(StatusCode::SERVICE_UNAVAILABLE),
+            Error::MediaNotFound(_) => Some(StatusCode::NOT_FOUND),
+            _ => None,
+        }
+    }
+
+    /// Did the homeserver reject the request because we sent too many
+    /// requests, i.e. with a `M_LIMIT_EXCEEDED` error.
+    pub fn is_rate_limited(&self) -> bool {
+        matches!(
+            self.client_api_error_kind(),
+            Some(ErrorKind::LimitExceeded { .. })
+        )
+    }
+
+    /// Get the time the homeserver asked us to wait before retrying a rate
+    /// limited request, or a request that failed because the homeserver was
+    /// temporarily unavailable.
+    ///
+    /// Returns `None` if the request wasn't rate limited or the homeserver
+    /// didn't tell us how long to wait.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if let Error::ServiceUnavailable { retry_after } = self {
+            return Some(*retry_after);
+        }
+
+        match self.client_api_error_kind()? {
+            ErrorKind::LimitExceeded { retry_after_ms } => *retry_after_ms,
+            _ => None,
+        }
+    }
+
+    /// Did the request fail because no response was received in time.
+    ///
+    /// This is the case for timeouts of the HTTP client, as well as for the
+    /// timeout and the deadline of the [`RequestConfig`].
+    ///
+    /// [`RequestConfig`]: struct.RequestConfig.html
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            Error::Reqwest(e) => e.is_timeout(),
+            Error::RequestTimeout(_) | Error::DeadlineExceeded => true,
+            _ => false,
+        }
+    }
+
+    /// Did the request fail because no connection to the homeserver could be
+    /// established, e.g. because the hostname couldn't be resolved or the
+    /// connection was refused.
+    ///
+    /// Errors like these are usually temporary, the request can be retried
+    /// with a backoff.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Reqwest(e) => e.is_connect(),
+            _ => false,
+        }
+    }
+
+    /// Did the decryption of an event fail because the room key that is
+    /// needed to decrypt it is missing.
+    ///
+    /// The key might still arrive, e.g. from the sender or by a key request,
+    /// clients can show the event as waiting for its key instead of as a
+    /// failure.
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub fn is_missing_key(&self) -> bool {
+        matches!(
+            self,
+            Error::Decryption(MegolmError::MissingSession)
+                | Error::MatrixError(MatrixError::MegolmError(MegolmError::MissingSession))
+        )
+    }
+
+    /// Get the kind of the Matrix error the homeserver responded with, if
+    /// this error contains one.
+    pub(crate) fn client_api_error_kind(&self) -> Option<&ErrorKind> {
+        match self {
+            Error::RumaResponse(RumaResponseError::Http(ServerError::Known(e))) => Some(&e.kind),
+            Error::UiaaError(RumaResponseError::Http(ServerError::Known(
+                UiaaError::MatrixError(e),
+            ))) => Some(&e.kind),
+            _ => None,
+        }
+    }
 }
 
 impl From<RumaResponseError<UiaaError>> for Error {
@@ -0,0 +1,148 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A health score for the connection to the homeserver, computed from the
+//! outcomes of recent requests.
+
+use std::sync::{Arc, Mutex};
+
+use http::StatusCode;
+use matrix_sdk_common::instant::Duration;
+
+use crate::Result;
+
+/// How much a single request moves the moving averages, higher values make
+/// the score react faster but also more jittery.
+const SMOOTHING_FACTOR: f64 = 0.2;
+
+/// Latencies up to this many seconds count as perfect.
+const GOOD_LATENCY: f64 = 0.5;
+/// Latencies from this many seconds on count as the worst possible latency.
+const BAD_LATENCY: f64 = 5.0;
+
+/// How much the error rate contributes to the score.
+const ERROR_WEIGHT: f64 = 0.6;
+/// How much the latency contributes to the score.
+const LATENCY_WEIGHT: f64 = 0.25;
+/// How much the rate of rate-limited requests contributes to the score.
+const RATE_LIMIT_WEIGHT: f64 = 0.15;
+
+/// The score from which on the connection is considered to be good.
+const GOOD_SCORE: u8 = 80;
+/// The score from which on the connection is considered to be degraded
+/// instead of poor.
+const DEGRADED_SCORE: u8 = 50;
+
+/// The quality of the connection to the homeserver.
+///
+/// See [`Client::homeserver_health_score`] for how the underlying score is
+/// computed.
+///
+/// [`Client::homeserver_health_score`]: struct.Client.html#method.homeserver_health_score
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HomeserverHealth {
+    /// Requests succeed quickly, the score is at least 80.
+    Good,
+    /// Some requests fail, are slow or get rate limited, the score is at
+    /// least 50.
+    Degraded,
+    /// Most requests fail or are very slow, the score is below 50.
+    Poor,
+}
+
+impl From<u8> for HomeserverHealth {
+    fn from(score: u8) -> Self {
+        if score >= GOOD_SCORE {
+            Self::Good
+        } else if score >= DEGRADED_SCORE {
+            Self::Degraded
+        } else {
+            Self::Poor
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Averages {
+    /// The average latency in seconds, `None` until the first latency was
+    /// measured.
+    latency: Option<f64>,
+    /// The share of requests that failed, between 0 and 1.
+    error_rate: f64,
+    /// The share of requests that got rate limited, between 0 and 1.
+    rate_limit_rate: f64,
+}
+
+/// Tracker for the exponential moving averages the health score is computed
+/// from.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HealthTracker {
+    averages: Arc<Mutex<Averages>>,
+}
+
+impl HealthTracker {
+    /// Record the outcome of a request.
+    ///
+    /// Requests that failed without a response or with a server error count
+    /// as errors, client errors besides rate limiting don't say anything
+    /// about the health of the homeserver and count as successes.
+    pub(crate) fn record(
+        &self,
+        path: &str,
+        response: &Result<http::Response<Vec<u8>>>,
+        latency: Duration,
+    ) {
+        let status = response.as_ref().ok().map(|r| r.status());
+
+        let failed = status.map_or(true, |s| s.is_server_error());
+        let rate_limited = status == Some(StatusCode::TOO_MANY_REQUESTS);
+
+        let mut averages = self.averages.lock().unwrap();
+
+        averages.error_rate = ewma(averages.error_rate, failed);
+        averages.rate_limit_rate = ewma(averages.rate_limit_rate, rate_limited);
+
+        // Syncs are long-polling, they take long on purpose.
+        if status.is_some() && !path.ends_with("/sync") {
+            let latency = latency.as_secs_f64();
+
+            averages.latency = Some(match averages.latency {
+                Some(average) => average + SMOOTHING_FACTOR * (latency - average),
+                None => latency,
+            });
+        }
+    }
+
+    /// Get the health score, between 0 and 100.
+    pub(crate) fn score(&self) -> u8 {
+        let averages = self.averages.lock().unwrap();
+
+        let latency_score = averages.latency.map_or(1.0, |latency| {
+            1.0 - ((latency - GOOD_LATENCY) / (BAD_LATENCY - GOOD_LATENCY))
+                .max(0.0)
+                .min(1.0)
+        });
+
+        let score = ERROR_WEIGHT * (1.0 - averages.error_rate)
+            + LATENCY_WEIGHT * latency_score
+            + RATE_LIMIT_WEIGHT * (1.0 - averages.rate_limit_rate);
+
+        (score * 100.0).round() as u8
+    }
+}
+
+fn ewma(average: f64, happened: bool) -> f64 {
+    let sample = if happened { 1.0 } else { 0.0 };
+    average + SMOOTHING_FACTOR * (sample - average)
+}
@@ -12,19 +12,86 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{convert::TryFrom, fmt::Debug, sync::Arc};
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::SystemTime;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt::Debug,
+    future::Future,
+    io::{Result as IoResult, Write},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as SyncMutex,
+    },
+};
 
-use http::{HeaderValue, Method as HttpMethod, Response as HttpResponse};
+use bytes::Bytes;
+use futures::{
+    future::{abortable, select, AbortHandle, Either},
+    stream::{Stream, StreamExt},
+};
+use futures_timer::Delay as sleep;
+use http::{
+    header::HeaderName, HeaderMap, HeaderValue, Method as HttpMethod, Response as HttpResponse,
+    StatusCode,
+};
 use reqwest::{Client, Response};
-use tracing::trace;
+use serde::Deserialize;
+use tracing::{debug, field, info_span, trace, warn};
+use tracing_futures::Instrument;
 use url::Url;
 
 use matrix_sdk_common::{
-    api::r0::media::create_content, async_trait, locks::RwLock, AsyncTraitDeps, AuthScheme,
-    FromHttpResponseError,
+    api::r0::media::create_content,
+    async_trait,
+    instant::{Duration, Instant},
+    locks::{Mutex, RwLock},
+    uuid::Uuid,
+    AsyncTraitDeps, AuthScheme, FromHttpResponseError, ServerError,
+};
+
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::RedirectPolicy;
+use crate::{
+    error::ResourceLimitInfo, health::HealthTracker, ClientConfig, Error, OutgoingRequest,
+    QueuedRequest, RequestConfig, Result, RetryConfig, Session,
 };
 
-use crate::{ClientConfig, Error, OutgoingRequest, Result, Session};
+/// The number of redirects that are followed if no limit is configured.
+#[cfg(not(target_arch = "wasm32"))]
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+
+/// The maximal size of a decompressed response body if none was configured.
+#[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 256 * 1024 * 1024;
+
+/// How long a request waits to get hold of the session if no timeout is
+/// configured.
+pub(crate) const DEFAULT_SESSION_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a single attempt of an API request may take if no timeout is
+/// configured.
+pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The endpoint used to exchange a refresh token for a new access token, as
+/// proposed in MSC2918.
+const REFRESH_PATH: &str = "/_matrix/client/unstable/org.matrix.msc2918.refresh";
+
+/// The difference between the local clock and the clock of the homeserver
+/// above which we warn about a wrong local clock.
+#[cfg(not(target_arch = "wasm32"))]
+const CLOCK_SKEW_WARNING_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+/// The number of bytes that are requested at once when media is downloaded in
+/// chunks.
+pub(crate) const DOWNLOAD_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// How often a chunk of a download is retried before the download fails.
+const DOWNLOAD_RETRIES: u32 = 3;
 
 /// Abstraction around the http layer. The allows implementors to use different
 /// http libraries.
@@ -75,13 +142,379 @@ pub trait HttpSend: AsyncTraitDeps {
         &self,
         request: http::Request<Vec<u8>>,
     ) -> Result<http::Response<Vec<u8>>>;
+
+    /// Send a request, giving up if no response was received within the
+    /// given `timeout`.
+    ///
+    /// The client calls this instead of [`send_request`](#tymethod.send_request)
+    /// if a timeout applies to the request. The default implementation ignores
+    /// the timeout, the client enforces it by dropping the request once it
+    /// passed. Implementations whose HTTP library supports timeouts natively
+    /// can override this together with
+    /// [`enforces_timeout`](#method.enforces_timeout) to let the library
+    /// enforce it, they should fail with an [`Error::RequestTimeout`] in that
+    /// case.
+    ///
+    /// [`Error::RequestTimeout`]: enum.Error.html#variant.RequestTimeout
+    async fn send_request_with_timeout(
+        &self,
+        request: http::Request<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let _ = timeout;
+        self.send_request(request).await
+    }
+
+    /// Does [`send_request_with_timeout`](#method.send_request_with_timeout)
+    /// enforce the timeout it gets itself.
+    ///
+    /// If this returns `true` the client doesn't enforce the timeout on top of
+    /// it. The default is `false`.
+    fn enforces_timeout(&self) -> bool {
+        false
+    }
+
+    /// Send a request whose body is produced incrementally by a stream.
+    ///
+    /// The default implementation collects the whole stream and sends the
+    /// request using [`send_request`](#tymethod.send_request), implementations
+    /// that support streaming bodies should override it.
+    ///
+    /// If the stream yields an error the request should be aborted and the
+    /// error returned.
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<BodyStream>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let (parts, mut stream) = request.into_parts();
+        let mut body = Vec::new();
+
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+
+        self.send_request(http::Request::from_parts(parts, body))
+            .await
+    }
+}
+
+/// A stream of chunks that make up the body of a request.
+pub type BodyStream = Pin<Box<dyn Stream<Item = IoResult<Bytes>> + Send + Sync>>;
+
+/// A signer that adds proof headers to outgoing requests.
+///
+/// This can be used to support sender-constrained access tokens, e.g. DPoP
+/// bound tokens, where every request needs to carry a proof derived from a
+/// private key and the details of the request.
+///
+/// The signer is called right before a request is sent out, after the access
+/// token was added to it.
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+pub trait RequestSigner: AsyncTraitDeps {
+    /// Sign the given request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that is about to be sent out. The method
+    /// and URL the proof needs to cover can be read from it and the proof
+    /// headers added to it.
+    ///
+    /// * `nonce` - The latest nonce the homeserver handed out in a
+    /// `DPoP-Nonce` response header, if it sent one.
+    async fn sign_request(
+        &self,
+        request: &mut http::Request<Vec<u8>>,
+        nonce: Option<&str>,
+    ) -> Result<()>;
+}
+
+/// The signer that is used if no signer is configured, it leaves requests
+/// untouched.
+#[derive(Debug)]
+pub(crate) struct NoopSigner;
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl RequestSigner for NoopSigner {
+    async fn sign_request(
+        &self,
+        _request: &mut http::Request<Vec<u8>>,
+        _nonce: Option<&str>,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Hook to rewrite the bodies of responses before they are deserialized.
+///
+/// This is an escape hatch to work around homeservers that send almost valid
+/// responses, e.g. a known buggy server version that omits a required field,
+/// without having to fork the SDK.
+///
+/// The rewriter only sees the responses of the endpoints it was registered
+/// for with [`ClientConfig::response_rewriter`]. It runs on the hot path of
+/// those requests and should be cheap, ideally it bails out early if the body
+/// doesn't need fixing.
+///
+/// [`ClientConfig::response_rewriter`]: struct.ClientConfig.html#method.response_rewriter
+pub trait ResponseRewriter: AsyncTraitDeps {
+    /// Rewrite the body of a response.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path of the endpoint the response belongs to.
+    ///
+    /// * `status` - The status code of the response.
+    ///
+    /// * `body` - The raw body of the response, it can be modified in place.
+    fn rewrite_response(&self, path: &str, status: StatusCode, body: &mut Vec<u8>);
+}
+
+/// Hook that gets notified when the access token of the session was
+/// refreshed.
+///
+/// The handler is registered with [`ClientConfig::token_refresh_handler`]
+/// and should persist the new session, the old access token and,
+/// depending on the homeserver, the old refresh token are no longer valid.
+///
+/// [`ClientConfig::token_refresh_handler`]: struct.ClientConfig.html#method.token_refresh_handler
+pub trait TokenRefreshHandler: AsyncTraitDeps {
+    /// Called after the access token was refreshed.
+    ///
+    /// # Arguments
+    ///
+    /// * `session` - The session containing the new access token.
+    fn on_token_refresh(&self, session: &Session);
+}
+
+/// Why a request is retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryReason {
+    /// The homeserver is rate limiting us or is temporarily unavailable, i.e.
+    /// it responded with `429 Too Many Requests` or `503 Service Unavailable`.
+    RateLimited,
+    /// The request failed in a way that is probably temporary, e.g. with
+    /// another server error or because no connection could be established.
+    Transient,
+}
+
+/// Details about a request that is about to be retried.
+#[derive(Clone, Debug)]
+pub struct RetryInfo {
+    /// The path of the retried request.
+    pub path: String,
+    /// The number of the retry, the first retry is attempt 1.
+    pub attempt: u32,
+    /// Why the request is retried.
+    pub reason: RetryReason,
+    /// How long the client waits before it sends the request again.
+    pub delay: Duration,
+}
+
+/// Hook that gets notified every time a request is retried.
+///
+/// The handler is registered with [`ClientConfig::retry_handler`], it's
+/// called right before the client starts waiting for the retry. It runs on
+/// the hot path of the request and should be cheap.
+///
+/// [`ClientConfig::retry_handler`]: struct.ClientConfig.html#method.retry_handler
+pub trait RetryHandler: AsyncTraitDeps {
+    /// Called before a request is retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry` - Details about the retry.
+    fn on_retry(&self, retry: &RetryInfo);
+}
+
+/// Hook that observes every request the client sends out and its outcome.
+///
+/// Interceptors are registered with [`ClientConfig::add_interceptor`] and run
+/// in the order they were added. Their hooks run on the hot path of every
+/// request and should be cheap.
+///
+/// [`ClientConfig::add_interceptor`]: struct.ClientConfig.html#method.add_interceptor
+pub trait RequestInterceptor: AsyncTraitDeps {
+    /// Called before a request is sent out.
+    ///
+    /// The request can be modified, e.g. to add a correlation header. This
+    /// runs before the request is signed, the access token is already part
+    /// of the request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request that is about to be sent out. The body of
+    /// requests with a streamed body, e.g. media uploads, is empty.
+    fn on_request(&self, request: &mut http::Request<Vec<u8>>) {
+        let _ = request;
+    }
+
+    /// Called once a request finished, also if it failed or was cancelled.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request the response belongs to, the time it was
+    /// sent out can be used to calculate the latency.
+    ///
+    /// * `response` - The response of the homeserver or the error that
+    /// occurred while sending the request.
+    fn on_response(
+        &self,
+        request: &PendingRequest,
+        response: std::result::Result<&http::Response<Vec<u8>>, &Error>,
+    ) {
+        let _ = (request, response);
+    }
+}
+
+/// A response rewriter together with the endpoints it was registered for.
+#[derive(Debug)]
+pub(crate) struct ResponseRewrite {
+    /// Path fragments of the endpoints whose responses are rewritten.
+    pub(crate) endpoints: Vec<String>,
+    pub(crate) rewriter: Arc<dyn ResponseRewriter>,
+}
+
+impl ResponseRewrite {
+    fn apply(&self, path: &str, response: &mut http::Response<Vec<u8>>) {
+        if self.endpoints.iter().any(|e| path.contains(e.as_str())) {
+            let status = response.status();
+            self.rewriter
+                .rewrite_response(path, status, response.body_mut());
+        }
+    }
+}
+
+/// A request that was sent out to the homeserver and didn't yet receive a
+/// response.
+#[derive(Clone, Debug)]
+pub struct PendingRequest {
+    /// The unique id of the request, it can be used to cancel the request.
+    pub id: Uuid,
+    /// The HTTP method of the request.
+    pub method: HttpMethod,
+    /// The path of the endpoint the request was sent to.
+    pub path: String,
+    /// The time the request was sent out.
+    pub started: Instant,
+}
+
+impl PendingRequest {
+    fn new<T>(request: &http::Request<T>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            method: request.method().clone(),
+            path: request.uri().path().to_owned(),
+            started: Instant::now(),
+        }
+    }
+
+    /// How long the request has been in flight.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Timing information about a request that received a response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RequestTiming {
+    /// The time between sending out the request and receiving the response,
+    /// as measured by the client.
+    pub round_trip: Duration,
+    /// The time the homeserver spent processing the request, as reported in
+    /// the `Server-Timing` header of the response.
+    ///
+    /// This is `None` if the homeserver didn't send the header.
+    pub server: Option<Duration>,
+}
+
+impl RequestTiming {
+    /// The part of the round trip that wasn't spent on the homeserver, i.e.
+    /// the network latency and the time spent in proxies.
+    pub fn network(&self) -> Option<Duration> {
+        self.server
+            .map(|server| self.round_trip.checked_sub(server).unwrap_or_default())
+    }
+}
+
+/// The registry of requests that are currently in flight.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PendingRequests {
+    requests: Arc<SyncMutex<BTreeMap<Uuid, (PendingRequest, AbortHandle)>>>,
+}
+
+impl PendingRequests {
+    /// Add a request to the registry, the request is removed once the returned
+    /// guard is dropped.
+    fn register(&self, request: PendingRequest, handle: AbortHandle) -> PendingRequestGuard {
+        let id = request.id;
+
+        self.requests.lock().unwrap().insert(id, (request, handle));
+
+        PendingRequestGuard {
+            requests: self.clone(),
+            id,
+        }
+    }
+
+    /// Get a list of all the requests that are currently in flight.
+    pub(crate) fn list(&self) -> Vec<PendingRequest> {
+        self.requests
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(r, _)| r.clone())
+            .collect()
+    }
+
+    /// Cancel the request with the given id.
+    ///
+    /// Returns true if the request was found, false otherwise.
+    pub(crate) fn cancel(&self, id: &Uuid) -> bool {
+        if let Some((_, handle)) = self.requests.lock().unwrap().get(id) {
+            handle.abort();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+struct PendingRequestGuard {
+    requests: PendingRequests,
+    id: Uuid,
+}
+
+impl Drop for PendingRequestGuard {
+    fn drop(&mut self) {
+        self.requests.requests.lock().unwrap().remove(&self.id);
+    }
 }
 
 #[derive(Clone, Debug)]
 pub(crate) struct HttpClient {
     pub(crate) inner: Arc<dyn HttpSend>,
+    pub(crate) media: Arc<dyn HttpSend>,
     pub(crate) homeserver: Arc<Url>,
     pub(crate) session: Arc<RwLock<Option<Session>>>,
+    pub(crate) session_lock_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+    pub(crate) clock_skew: Arc<RwLock<Option<Duration>>>,
+    pub(crate) pending_requests: PendingRequests,
+    pub(crate) last_timing: Arc<SyncMutex<Option<RequestTiming>>>,
+    pub(crate) signer: Arc<dyn RequestSigner>,
+    pub(crate) signing_nonce: Arc<SyncMutex<Option<String>>>,
+    pub(crate) health: HealthTracker,
+    pub(crate) response_rewrite: Option<Arc<ResponseRewrite>>,
+    pub(crate) interceptors: Vec<Arc<dyn RequestInterceptor>>,
+    pub(crate) capture_invalid_responses: bool,
+    pub(crate) refresh_lock: Arc<Mutex<()>>,
+    pub(crate) token_refresh_handler: Option<Arc<dyn TokenRefreshHandler>>,
+    pub(crate) retry: Option<RetryConfig>,
+    pub(crate) retry_handler: Option<Arc<dyn RetryHandler>>,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Metrics,
 }
 
 impl HttpClient {
@@ -90,26 +523,183 @@ impl HttpClient {
         request: Request,
         session: Arc<RwLock<Option<Session>>>,
         content_type: Option<HeaderValue>,
+        inner: &dyn HttpSend,
+        config: RequestConfig,
     ) -> Result<http::Response<Vec<u8>>> {
-        let mut request = {
-            let read_guard;
-            let access_token = match Request::METADATA.authentication {
-                AuthScheme::AccessToken => {
-                    read_guard = session.read().await;
-
-                    if let Some(session) = read_guard.as_ref() {
-                        Some(session.access_token.as_str())
-                    } else {
-                        return Err(Error::AuthenticationRequired);
-                    }
-                }
-                AuthScheme::None => None,
-                _ => return Err(Error::NotClientRequest),
+        let mut request = self
+            .build_http_request(request, session.clone(), content_type)
+            .await?;
+
+        for (name, value) in &config.headers {
+            // The access token of the session must not be replaced.
+            if name != http::header::AUTHORIZATION {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+
+        // Keep a copy of the request around in case the access token expired
+        // and the request needs to be sent again with a refreshed one.
+        let expired_token = bearer_token(&request).map(|t| t.to_owned());
+        let retry = match &expired_token {
+            Some(_) if self.can_refresh(&session).await => Some(clone_request(&request)),
+            _ => None,
+        };
+
+        let response = self.send_http_request(request, inner, config.clone()).await;
+
+        let (mut retry, expired_token) = match (retry, expired_token) {
+            (Some(retry), Some(token)) if matches!(response, Err(Error::SoftLogout { .. })) => {
+                (retry, token)
+            }
+            _ => return response,
+        };
+
+        // A failed refresh doesn't change the fact that the session was soft
+        // logged out, that's what the caller needs to know about.
+        let access_token = match self.refresh_access_token(&session, &expired_token).await {
+            Ok(Some(t)) => t,
+            Ok(None) => return response,
+            Err(e) => {
+                warn!("Couldn't refresh the access token: {}", e);
+                return response;
+            }
+        };
+
+        retry.headers_mut().insert(
+            http::header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", access_token))
+                .map_err(http::Error::from)?,
+        );
+
+        self.send_http_request(retry, inner, config).await
+    }
+
+    /// Check if the session contains a refresh token.
+    async fn can_refresh(&self, session: &RwLock<Option<Session>>) -> bool {
+        session
+            .read()
+            .await
+            .as_ref()
+            .map_or(false, |s| s.refresh_token.is_some())
+    }
+
+    /// Exchange the refresh token of the session for a new access token.
+    ///
+    /// Only one refresh is done at a time, requests that failed with the same
+    /// expired token while a refresh was in flight reuse its result.
+    ///
+    /// The refresh request goes straight to the HTTP client, interceptors,
+    /// retries and the response hooks never get to see the refresh token.
+    ///
+    /// Returns the new access token, or `None` if the token couldn't be
+    /// refreshed, e.g. because the refresh token was rejected as well.
+    async fn refresh_access_token(
+        &self,
+        session: &RwLock<Option<Session>>,
+        expired_token: &str,
+    ) -> Result<Option<String>> {
+        #[derive(Deserialize)]
+        struct RefreshResponse {
+            access_token: String,
+            refresh_token: Option<String>,
+            #[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+            expires_in_ms: Option<u64>,
+        }
+
+        let _guard = self.refresh_lock.lock().await;
+
+        let refresh_token = match session.read().await.as_ref() {
+            // Another request already refreshed the token.
+            Some(s) if s.access_token != expired_token => return Ok(Some(s.access_token.clone())),
+            Some(s) => match &s.refresh_token {
+                Some(t) => t.clone(),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        let mut request = http::Request::builder()
+            .method(HttpMethod::POST)
+            .uri(format!(
+                "{}{}",
+                self.homeserver.as_str().trim_end_matches('/'),
+                REFRESH_PATH
+            ))
+            .header(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            )
+            .body(serde_json::to_vec(
+                &serde_json::json!({ "refresh_token": refresh_token }),
+            )?)?;
+
+        self.sign_request(&mut request).await?;
+
+        let response =
+            send_with_timeout(&*self.inner, request, self.default_request_config().timeout).await?;
+
+        if !response.status().is_success() {
+            warn!(
+                "Couldn't refresh the access token, the homeserver responded with {}",
+                response.status()
+            );
+            return Ok(None);
+        }
+
+        let refreshed: RefreshResponse = serde_json::from_slice(response.body())?;
+
+        let session = {
+            let mut session = session.write().await;
+
+            // The client logged out while the token was being refreshed.
+            let session = match session.as_mut() {
+                Some(s) => s,
+                None => return Ok(None),
             };
 
-            request.try_into_http_request(&self.homeserver.to_string(), access_token)?
+            session.access_token = refreshed.access_token;
+
+            if let Some(refresh_token) = refreshed.refresh_token {
+                session.refresh_token = Some(refresh_token);
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                session.expires_at = refreshed
+                    .expires_in_ms
+                    .map(|ms| SystemTime::now() + Duration::from_millis(ms));
+            }
+
+            session.clone()
+        };
+
+        debug!("Refreshed the access token of {}", session.user_id);
+
+        if let Some(handler) = &self.token_refresh_handler {
+            handler.on_token_refresh(&session);
+        }
+
+        Ok(Some(session.access_token))
+    }
+
+    /// Convert the given request into a HTTP request for the homeserver,
+    /// authenticated with the access token of the session if the endpoint
+    /// requires it.
+    async fn build_http_request<Request: OutgoingRequest>(
+        &self,
+        request: Request,
+        session: Arc<RwLock<Option<Session>>>,
+        content_type: Option<HeaderValue>,
+    ) -> Result<http::Request<Vec<u8>>> {
+        let access_token = match Request::METADATA.authentication {
+            AuthScheme::AccessToken => Some(self.access_token(&session).await?),
+            AuthScheme::None => None,
+            _ => return Err(Error::NotClientRequest),
         };
 
+        let mut request =
+            request.try_into_http_request(&self.homeserver.to_string(), access_token.as_deref())?;
+
         if let HttpMethod::POST | HttpMethod::PUT | HttpMethod::DELETE = *request.method() {
             if let Some(content_type) = content_type {
                 request
@@ -118,103 +708,1563 @@ impl HttpClient {
             }
         }
 
-        self.inner.send_request(request).await
+        Ok(request)
     }
 
-    pub async fn upload(
-        &self,
-        request: create_content::Request<'_>,
-    ) -> Result<create_content::Response> {
-        let response = self
-            .send_request(request, self.session.clone(), None)
-            .await?;
-        Ok(create_content::Response::try_from(response)?)
+    /// The configuration for API requests that don't come with their own, it
+    /// applies the default request timeout.
+    fn default_request_config(&self) -> RequestConfig {
+        RequestConfig::new().timeout(self.request_timeout)
     }
 
-    pub async fn send<Request>(&self, request: Request) -> Result<Request::IncomingResponse>
-    where
-        Request: OutgoingRequest,
-        Error: From<FromHttpResponseError<Request::EndpointError>>,
-    {
-        let content_type = HeaderValue::from_static("application/json");
+    /// Send the given request inside of a tracing span recording its method,
+    /// path, status code and how long it took.
+    async fn send_http_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+        inner: &dyn HttpSend,
+        config: RequestConfig,
+    ) -> Result<http::Response<Vec<u8>>> {
+        // Only the path is recorded, the query string might contain an access
+        // token.
+        let span = info_span!(
+            "request",
+            method = %request.method(),
+            path = request.uri().path(),
+            status = field::Empty,
+            elapsed_ms = field::Empty,
+        );
+
+        let start = Instant::now();
         let response = self
-            .send_request(request, self.session.clone(), Some(content_type))
-            .await?;
+            .send_with_retries(request, inner, config)
+            .instrument(span.clone())
+            .await;
 
-        trace!("Got response: {:?}", response);
+        if let Ok(response) = &response {
+            span.record("status", &response.status().as_u16());
+        }
+        span.record("elapsed_ms", &(start.elapsed().as_millis() as u64));
 
-        Ok(Request::IncomingResponse::try_from(response)?)
+        // This is only checked once the retries are used up, the retry logic
+        // needs to see the response itself.
+        if let Some(error) = response.as_ref().ok().and_then(service_unavailable_error) {
+            return Err(error);
+        }
+
+        response
     }
-}
 
-/// Build a client with the specified configuration.
-pub(crate) fn client_with_config(config: &ClientConfig) -> Result<Client> {
-    let http_client = reqwest::Client::builder();
+    /// Send the given request, retrying it if a retry policy is configured
+    /// and the request failed in a retryable way.
+    ///
+    /// The request stays pending, and can be cancelled, for all of its
+    /// attempts including the waits between them.
+    async fn send_with_retries(
+        &self,
+        request: http::Request<Vec<u8>>,
+        inner: &dyn HttpSend,
+        config: RequestConfig,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let pending = PendingRequest::new(&request);
 
-    #[cfg(not(target_arch = "wasm32"))]
-    let http_client = {
-        let http_client = match config.timeout {
-            Some(x) => http_client.timeout(x),
-            None => http_client,
-        };
+        self.cancellable(pending, self.send_attempts(request, inner, config))
+            .await
+    }
 
-        let http_client = if config.disable_ssl_verification {
-            http_client.danger_accept_invalid_certs(true)
-        } else {
-            http_client
+    /// Send the attempts of the given request.
+    ///
+    /// No retry is attempted if waiting for it would exceed the deadline of
+    /// the request or the maximal total wait time of the policy, the last
+    /// response is returned instead.
+    async fn send_attempts(
+        &self,
+        request: http::Request<Vec<u8>>,
+        inner: &dyn HttpSend,
+        config: RequestConfig,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let retry = match &self.retry {
+            Some(retry) => retry,
+            None => {
+                return self
+                    .send_single_request(request, inner, config.timeout)
+                    .await
+            }
         };
 
-        let http_client = match &config.proxy {
-            Some(p) => http_client.proxy(p.clone()),
-            None => http_client,
-        };
+        let mut attempt = 0;
+        let mut total_wait = Duration::from_secs(0);
 
-        let mut headers = reqwest::header::HeaderMap::new();
+        loop {
+            let response = self
+                .send_single_request(clone_request(&request), inner, config.timeout)
+                .await;
 
-        let user_agent = match &config.user_agent {
-            Some(a) => a.clone(),
-            None => HeaderValue::from_str(&format!("matrix-rust-sdk {}", crate::VERSION)).unwrap(),
-        };
+            if attempt >= retry.max_retries
+                || !should_retry(retry, &request, self.api_path(&request), &response)
+            {
+                return response;
+            }
 
-        headers.insert(reqwest::header::USER_AGENT, user_agent);
+            if config.skip_rate_limit_retry && retry_reason(&response) == RetryReason::RateLimited {
+                return response;
+            }
 
-        http_client.default_headers(headers)
-    };
+            let delay = response
+                .as_ref()
+                .ok()
+                .and_then(retry_after)
+                .unwrap_or_else(|| retry.backoff(attempt));
 
-    #[cfg(target_arch = "wasm32")]
-    #[allow(unused)]
-    let _ = config;
+            if total_wait + delay > retry.max_total_wait
+                || config
+                    .deadline
+                    .map_or(false, |d| Instant::now() + delay >= d)
+            {
+                return response;
+            }
 
-    Ok(http_client.build()?)
-}
+            debug!(
+                "Retrying a request to {} in {:?}, attempt {} of {}",
+                request.uri().path(),
+                delay,
+                attempt + 1,
+                retry.max_retries
+            );
 
-async fn response_to_http_response(mut response: Response) -> Result<http::Response<Vec<u8>>> {
-    let status = response.status();
+            if let Some(handler) = &self.retry_handler {
+                handler.on_retry(&RetryInfo {
+                    path: request.uri().path().to_owned(),
+                    attempt: attempt + 1,
+                    reason: retry_reason(&response),
+                    delay,
+                });
+            }
 
-    let mut http_builder = HttpResponse::builder().status(status);
-    let headers = http_builder.headers_mut().unwrap();
+            sleep::new(delay).await;
 
-    for (k, v) in response.headers_mut().drain() {
-        if let Some(key) = k {
-            headers.insert(key, v);
+            total_wait += delay;
+            attempt += 1;
         }
     }
 
-    let body = response.bytes().await?.as_ref().to_owned();
+    /// Register the given request as pending while the `response` future
+    /// runs, failing with an [`Error::Cancelled`] if it gets cancelled.
+    async fn cancellable(
+        &self,
+        pending: PendingRequest,
+        response: impl Future<Output = Result<http::Response<Vec<u8>>>>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let intercepted = if self.interceptors.is_empty() {
+            None
+        } else {
+            Some(pending.clone())
+        };
 
-    Ok(http_builder.body(body).unwrap())
-}
+        let (response, handle) = abortable(response);
+        let _guard = self.pending_requests.register(pending, handle);
 
-#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+        match response.await {
+            Ok(response) => response,
+            Err(_) => {
+                if let Some(request) = &intercepted {
+                    self.intercept_response(request, Err(&Error::Cancelled));
+                }
+
+                Err(Error::Cancelled)
+            }
+        }
+    }
+
+    /// Get the path of the given request relative to the API base, i.e.
+    /// without the path of the homeserver URL and the configured base path.
+    fn api_path<'a>(&self, request: &'a http::Request<Vec<u8>>) -> &'a str {
+        let path = request.uri().path();
+        let base = self.homeserver.path().trim_end_matches('/');
+
+        match path.strip_prefix(base) {
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => path,
+        }
+    }
+
+    /// Send the given request once, failing with an [`Error::RequestTimeout`]
+    /// if no response was received within the `timeout`.
+    async fn send_single_request(
+        &self,
+        mut request: http::Request<Vec<u8>>,
+        inner: &dyn HttpSend,
+        timeout: Option<Duration>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        self.intercept_request(&mut request);
+        self.sign_request(&mut request).await?;
+
+        trace!("Sending request: {}", request.redact_sensitive());
+
+        let pending = PendingRequest::new(&request);
+        let bytes_sent = request.body().len();
+        let response = send_with_timeout(inner, request, timeout);
+
+        self.track_request(pending, response, || bytes_sent).await
+    }
+
+    /// Send a request whose body is produced by the given stream.
+    ///
+    /// The request is signed before the body is attached, request signers
+    /// only get to see the method, URL and headers of the request.
+    async fn send_streaming_request(
+        &self,
+        mut request: http::Request<Vec<u8>>,
+        body: BodyStream,
+        inner: &dyn HttpSend,
+    ) -> Result<http::Response<Vec<u8>>> {
+        self.intercept_request(&mut request);
+        self.sign_request(&mut request).await?;
+
+        let pending = PendingRequest::new(&request);
+
+        let bytes_sent = Arc::new(AtomicUsize::new(0));
+        let counter = bytes_sent.clone();
+        let body: BodyStream = Box::pin(body.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                counter.fetch_add(chunk.len(), Ordering::Relaxed);
+            }
+        }));
+
+        let request = request.map(|_| body);
+        let response = self.track_request(
+            pending.clone(),
+            inner.send_streaming_request(request),
+            || bytes_sent.load(Ordering::Relaxed),
+        );
+
+        self.cancellable(pending, response).await
+    }
+
+    fn intercept_request(&self, request: &mut http::Request<Vec<u8>>) {
+        for interceptor in &self.interceptors {
+            interceptor.on_request(request);
+        }
+    }
+
+    fn intercept_response(
+        &self,
+        request: &PendingRequest,
+        response: std::result::Result<&http::Response<Vec<u8>>, &Error>,
+    ) {
+        for interceptor in &self.interceptors {
+            interceptor.on_response(request, response);
+        }
+    }
+
+    async fn sign_request(&self, request: &mut http::Request<Vec<u8>>) -> Result<()> {
+        let nonce = self.signing_nonce.lock().unwrap().clone();
+        self.signer.sign_request(request, nonce.as_deref()).await
+    }
+
+    /// Wait for the response of a request that is being sent out.
+    ///
+    /// Once the response arrives it's recorded and checked for errors that
+    /// need special treatment.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    async fn track_request(
+        &self,
+        pending: PendingRequest,
+        response: impl Future<Output = Result<http::Response<Vec<u8>>>>,
+        bytes_sent: impl FnOnce() -> usize,
+    ) -> Result<http::Response<Vec<u8>>> {
+        #[cfg(feature = "metrics")]
+        let method = pending.method.clone();
+        let (path, started) = (pending.path.clone(), pending.started);
+
+        let response = response.await;
+
+        self.intercept_response(&pending, response.as_ref());
+
+        let timing = response.as_ref().ok().map(|r| RequestTiming {
+            round_trip: started.elapsed(),
+            server: server_timing(r),
+        });
+
+        #[cfg(feature = "metrics")]
+        self.metrics.record(
+            &method,
+            &response,
+            started.elapsed(),
+            timing.and_then(|t| t.server),
+        );
+
+        self.health.record(&path, &response, started.elapsed());
+
+        let mut response = response?;
+
+        #[cfg(feature = "metrics")]
+        self.metrics
+            .record_bandwidth(&path, bytes_sent(), response.body().len());
+
+        if let Some(rewrite) = &self.response_rewrite {
+            rewrite.apply(&path, &mut response);
+        }
+
+        *self.last_timing.lock().unwrap() = timing;
+
+        if let Some(nonce) = response
+            .headers()
+            .get("dpop-nonce")
+            .and_then(|n| n.to_str().ok())
+        {
+            *self.signing_nonce.lock().unwrap() = Some(nonce.to_owned());
+        }
+
+        #[cfg(not(target_arch = "wasm32"))]
+        self.update_clock_skew(&response).await;
+
+        if let Some(error) = consent_not_given_error(&response) {
+            return Err(error);
+        }
+
+        if let Some(error) = soft_logout_error(&response) {
+            return Err(error);
+        }
+
+        if let Some(error) = resource_limit_exceeded_error(&response) {
+            return Err(error);
+        }
+
+        Ok(response)
+    }
+
+    /// Get the access token of the session.
+    ///
+    /// Waits at most for the configured session lock timeout to get hold of
+    /// the session, a wedged lock would otherwise hang every request.
+    async fn access_token(&self, session: &RwLock<Option<Session>>) -> Result<String> {
+        let read = Box::pin(session.read());
+
+        match select(read, sleep::new(self.session_lock_timeout)).await {
+            Either::Left((session, _)) => session
+                .as_ref()
+                .map(|s| s.access_token.clone())
+                .ok_or(Error::AuthenticationRequired),
+            Either::Right(_) => {
+                let pending = self.pending_requests.list();
+
+                warn!(
+                    "Couldn't get hold of the session within {:?}, the lock is \
+                     probably held by a stuck task. Requests in flight: {:?}",
+                    self.session_lock_timeout, pending
+                );
+
+                Err(Error::SessionLockTimeout(self.session_lock_timeout))
+            }
+        }
+    }
+
+    /// Get the difference between the local clock and the clock of the
+    /// homeserver, as measured on the last response that contained a `Date`
+    /// header.
+    pub async fn clock_skew(&self) -> Option<Duration> {
+        *self.clock_skew.read().await
+    }
+
+    /// Compare the `Date` header of the response with the local clock.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn update_clock_skew(&self, response: &http::Response<Vec<u8>>) {
+        let server_time = match response
+            .headers()
+            .get(http::header::DATE)
+            .and_then(|d| d.to_str().ok())
+            .and_then(|d| httpdate::parse_http_date(d).ok())
+        {
+            Some(t) => t,
+            None => return,
+        };
+
+        let skew = match SystemTime::now().duration_since(server_time) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+
+        let previous = self.clock_skew.write().await.replace(skew);
+
+        // Only warn once until the clock gets fixed, not on every response.
+        if skew > CLOCK_SKEW_WARNING_THRESHOLD
+            && previous.map_or(true, |p| p <= CLOCK_SKEW_WARNING_THRESHOLD)
+        {
+            warn!(
+                "The local clock differs from the clock of the homeserver by {:?}",
+                skew
+            );
+        }
+    }
+
+    pub async fn send_raw(&self, request: &QueuedRequest) -> Result<http::Response<Vec<u8>>> {
+        let access_token = if request.authenticated {
+            Some(self.access_token(&self.session).await?)
+        } else {
+            None
+        };
+
+        let request = request.to_http_request(self.homeserver.as_str(), access_token.as_deref())?;
+
+        self.send_http_request(request, &*self.inner, self.default_request_config())
+            .await
+    }
+
+    /// Fetch a document the homeserver publishes outside of the client-server
+    /// API, e.g. one of its `.well-known` documents.
+    pub async fn get_document(&self, path: &str) -> Result<http::Response<Vec<u8>>> {
+        let mut url = (*self.homeserver).clone();
+        url.set_path(path);
+        url.set_query(None);
+
+        let request = http::Request::get(url.as_str()).body(Vec::new())?;
+
+        self.send_http_request(request, &*self.inner, self.default_request_config())
+            .await
+    }
+
+    pub async fn send_optional<Request>(
+        &self,
+        request: Request,
+    ) -> Result<Option<Request::IncomingResponse>>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let content_type = HeaderValue::from_static("application/json");
+        let response = self
+            .send_request(
+                request,
+                self.session.clone(),
+                Some(content_type),
+                &*self.inner,
+                self.default_request_config(),
+            )
+            .await?;
+
+        trace!("Got response: {}", response.redact_sensitive());
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        check_json_body(&response)?;
+
+        Ok(Some(self.parse_response(response)?))
+    }
+
+    pub async fn send_to<Request>(
+        &self,
+        base_url: &Url,
+        request: Request,
+    ) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        // The access token is only valid for our own homeserver, it must never
+        // be sent to a different one.
+        match Request::METADATA.authentication {
+            AuthScheme::None => (),
+            AuthScheme::AccessToken => return Err(Error::AuthenticationNotForwarded),
+            _ => return Err(Error::NotClientRequest),
+        }
+
+        let mut request = request.try_into_http_request(base_url.as_str(), None)?;
+
+        if let HttpMethod::POST | HttpMethod::PUT | HttpMethod::DELETE = *request.method() {
+            request.headers_mut().append(
+                http::header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            );
+        }
+
+        // Signers, interceptors and the state we track about responses all
+        // belong to our own homeserver, the request is sent out as is.
+        trace!("Sending request: {}", request.redact_sensitive());
+
+        let response =
+            send_with_timeout(&*self.inner, request, self.default_request_config().timeout).await?;
+
+        trace!("Got response: {}", response.redact_sensitive());
+        check_json_body(&response)?;
+
+        self.parse_response(response)
+    }
+
+    pub async fn upload(
+        &self,
+        request: create_content::Request<'_>,
+    ) -> Result<create_content::Response> {
+        let response = self
+            .send_request(
+                request,
+                self.session.clone(),
+                None,
+                &*self.media,
+                RequestConfig::default(),
+            )
+            .await?;
+        check_json_body(&response)?;
+
+        self.parse_response(response)
+    }
+
+    /// Fetch media content, e.g. with a `get_content` or a
+    /// `get_content_thumbnail` request.
+    ///
+    /// A `404` response results in an [`Error::MediaNotFound`] for the given
+    /// `uri` instead of a deserialization error.
+    pub async fn download<Request>(
+        &self,
+        request: Request,
+        uri: &str,
+    ) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let response = self
+            .send_request(
+                request,
+                self.session.clone(),
+                None,
+                &*self.media,
+                RequestConfig::default(),
+            )
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(Error::MediaNotFound(uri.to_owned()));
+        }
+
+        Ok(Request::IncomingResponse::try_from(response)?)
+    }
+
+    /// Deserialize the given response.
+    ///
+    /// If capturing invalid responses is enabled, the raw body of responses
+    /// that can't be deserialized is kept in the resulting
+    /// [`Error::ResponseDeserialization`].
+    fn parse_response<Response, EndpointError>(
+        &self,
+        response: http::Response<Vec<u8>>,
+    ) -> Result<Response>
+    where
+        Response: TryFrom<http::Response<Vec<u8>>, Error = FromHttpResponseError<EndpointError>>,
+        Error: From<FromHttpResponseError<EndpointError>>,
+    {
+        if !self.capture_invalid_responses {
+            return Ok(Response::try_from(response)?);
+        }
+
+        let (status, body) = (response.status(), response.body().clone());
+
+        let error = match Response::try_from(response) {
+            Ok(response) => return Ok(response),
+            Err(e) => e,
+        };
+
+        if matches!(
+            error,
+            FromHttpResponseError::Deserialization(_)
+                | FromHttpResponseError::Http(ServerError::Unknown(_))
+        ) {
+            Err(Error::ResponseDeserialization {
+                status,
+                body,
+                source: Box::new(error.into()),
+            })
+        } else {
+            Err(error.into())
+        }
+    }
+
+    pub async fn send<Request>(&self, request: Request) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        self.send_with_config(request, RequestConfig::default())
+            .await
+    }
+
+    pub async fn send_with_config<Request>(
+        &self,
+        request: Request,
+        mut config: RequestConfig,
+    ) -> Result<Request::IncomingResponse>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        if !config.disable_timeout {
+            config.timeout = config.timeout.or(Some(self.request_timeout));
+        }
+
+        let deadline = config.deadline;
+        let content_type = HeaderValue::from_static("application/json");
+        let response = self.send_request(
+            request,
+            self.session.clone(),
+            Some(content_type),
+            &*self.inner,
+            config,
+        );
+
+        let response = if let Some(deadline) = deadline {
+            // Don't bother sending out the request if the deadline already
+            // passed.
+            let remaining = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|d| *d > Duration::from_secs(0))
+                .ok_or(Error::DeadlineExceeded)?;
+
+            match select(Box::pin(response), sleep::new(remaining)).await {
+                Either::Left((response, _)) => response?,
+                Either::Right(_) => return Err(Error::DeadlineExceeded),
+            }
+        } else {
+            response.await?
+        };
+
+        trace!("Got response: {}", response.redact_sensitive());
+        check_json_body(&response)?;
+
+        self.parse_response(response)
+    }
+
+    /// Download media in chunks using range requests and write it to
+    /// `writer`, returns the number of bytes that were written.
+    ///
+    /// A chunk that fails because of a connection problem, a timeout or a
+    /// server error is requested again, the download continues where it left
+    /// off. If the server ignores the range and sends the whole media, the
+    /// part we already have is skipped.
+    pub async fn download_to_writer<Request>(
+        &self,
+        request: Request,
+        uri: &str,
+        writer: &mut impl Write,
+    ) -> Result<u64>
+    where
+        Request: OutgoingRequest,
+        Error: From<FromHttpResponseError<Request::EndpointError>>,
+    {
+        let request = self
+            .build_http_request(request, self.session.clone(), None)
+            .await?;
+
+        let mut written = 0;
+        let mut failures = 0;
+
+        let total = loop {
+            let mut chunk_request = clone_request(&request);
+            let range = format!("bytes={}-{}", written, written + DOWNLOAD_CHUNK_SIZE - 1);
+            chunk_request.headers_mut().insert(
+                http::header::RANGE,
+                HeaderValue::from_str(&range).expect("a byte range is a valid header value"),
+            );
+
+            let response = self
+                .send_http_request(chunk_request, &*self.media, RequestConfig::default())
+                .await;
+
+            // Only transient failures are worth resuming from, the time the
+            // homeserver asked us to wait is honoured.
+            let retry = match &response {
+                Ok(response) if response.status().is_server_error() => {
+                    Some(retry_after(response).unwrap_or_default())
+                }
+                Ok(_) => None,
+                Err(e @ Error::ServiceUnavailable { .. }) => e.retry_after(),
+                Err(e) if e.is_connection_error() || e.is_timeout() => Some(Duration::default()),
+                Err(_) => None,
+            };
+
+            if let (Some(delay), true) = (retry, failures < DOWNLOAD_RETRIES) {
+                failures += 1;
+                debug!(
+                    "Downloading {} failed after {} bytes, resuming in {:?}, attempt {} of {}",
+                    uri, written, delay, failures, DOWNLOAD_RETRIES
+                );
+                sleep::new(delay).await;
+                continue;
+            }
+
+            let response = response?;
+            let body = response.body();
+
+            match response.status() {
+                StatusCode::PARTIAL_CONTENT => {
+                    let (start, end, total) = content_range(&response).ok_or_else(|| {
+                        Error::InconsistentDownload("invalid Content-Range header".to_owned())
+                    })?;
+
+                    if start != written
+                        || end < start
+                        || end + 1 - start != body.len() as u64
+                        || end >= total
+                    {
+                        return Err(Error::InconsistentDownload(format!(
+                            "requested {}, got bytes {}-{}/{} with {} bytes",
+                            range,
+                            start,
+                            end,
+                            total,
+                            body.len()
+                        )));
+                    }
+
+                    writer.write_all(body)?;
+                    written += body.len() as u64;
+                    failures = 0;
+
+                    if written == total {
+                        break total;
+                    }
+                }
+                StatusCode::OK => {
+                    let total = body.len() as u64;
+
+                    if total < written {
+                        return Err(Error::InconsistentDownload(format!(
+                            "the media shrank from at least {} to {} bytes",
+                            written, total
+                        )));
+                    }
+
+                    writer.write_all(&body[written as usize..])?;
+                    written = total;
+
+                    break response
+                        .headers()
+                        .get(http::header::CONTENT_LENGTH)
+                        .and_then(|l| l.to_str().ok()?.parse().ok())
+                        .unwrap_or(total);
+                }
+                StatusCode::NOT_FOUND => return Err(Error::MediaNotFound(uri.to_owned())),
+                status => {
+                    // Let Ruma turn error responses into the matching error.
+                    Request::IncomingResponse::try_from(response)?;
+                    return Err(Error::InconsistentDownload(format!(
+                        "unexpected status {}",
+                        status
+                    )));
+                }
+            }
+        };
+
+        if written != total {
+            return Err(Error::InconsistentDownload(format!(
+                "expected {} bytes but got {}",
+                total, written
+            )));
+        }
+
+        writer.flush()?;
+
+        Ok(written)
+    }
+
+    /// Upload media whose content is produced by the given stream.
+    ///
+    /// The `request` describes the upload, its `file` is replaced by the
+    /// content of the stream.
+    pub async fn upload_stream(
+        &self,
+        request: create_content::Request<'_>,
+        body: BodyStream,
+    ) -> Result<create_content::Response> {
+        let request = self
+            .build_http_request(request, self.session.clone(), None)
+            .await?;
+        let response = self
+            .send_streaming_request(request, body, &*self.media)
+            .await?;
+        check_json_body(&response)?;
+
+        self.parse_response(response)
+    }
+}
+
+/// Copy a request so it can be sent out again.
+fn clone_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut clone = http::Request::new(request.body().clone());
+
+    *clone.method_mut() = request.method().clone();
+    *clone.uri_mut() = request.uri().clone();
+    *clone.version_mut() = request.version();
+    *clone.headers_mut() = request.headers().clone();
+
+    clone
+}
+
+/// Parse the `Content-Range` header of a partial response, of the form
+/// `bytes <start>-<end>/<total>`.
+fn content_range(response: &http::Response<Vec<u8>>) -> Option<(u64, u64, u64)> {
+    let value = response
+        .headers()
+        .get(http::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+
+    let mut parts = value.strip_prefix("bytes ")?.splitn(2, '/');
+    let mut range = parts.next()?.splitn(2, '-');
+
+    let start = range.next()?.parse().ok()?;
+    let end = range.next()?.parse().ok()?;
+    let total = parts.next()?.parse().ok()?;
+
+    Some((start, end, total))
+}
+
+/// Check if a request should be retried after it failed.
+///
+/// Rate limited and unavailable responses are retried for every request.
+/// Other server errors and failures to get a response at all only for the
+/// endpoints the retry config allows, by default `GET` requests, a
+/// non-idempotent request might have been processed already and retrying it
+/// could send it twice.
+fn should_retry(
+    retry: &RetryConfig,
+    request: &http::Request<Vec<u8>>,
+    path: &str,
+    response: &Result<http::Response<Vec<u8>>>,
+) -> bool {
+    let retryable = || retry.is_retryable(request.method(), path);
+
+    match response {
+        Ok(response) => match response.status() {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => true,
+            status => status.is_server_error() && retryable(),
+        },
+        Err(Error::Reqwest(_)) => retryable(),
+        Err(_) => false,
+    }
+}
+
+/// Get the reason for retrying a request that failed with the given response.
+fn retry_reason(response: &Result<http::Response<Vec<u8>>>) -> RetryReason {
+    match response.as_ref().map(|r| r.status()) {
+        Ok(StatusCode::TOO_MANY_REQUESTS) | Ok(StatusCode::SERVICE_UNAVAILABLE) => {
+            RetryReason::RateLimited
+        }
+        _ => RetryReason::Transient,
+    }
+}
+
+/// Get the time the homeserver asked us to wait before retrying, from the
+/// `retry_after_ms` field of a Matrix error or the `Retry-After` header.
+fn retry_after(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    let from_body = serde_json::from_slice::<serde_json::Value>(response.body())
+        .ok()
+        .and_then(|b| b.get("retry_after_ms")?.as_u64())
+        .map(Duration::from_millis);
+
+    from_body.or_else(|| retry_after_header(response))
+}
+
+/// Parse the `Retry-After` header of the response, it either contains the
+/// number of seconds to wait or the date after which the request can be
+/// retried.
+fn retry_after_header(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        if let Ok(date) = httpdate::parse_http_date(value) {
+            // A date in the past means that we can retry right away.
+            return Some(
+                date.duration_since(SystemTime::now())
+                    .unwrap_or_else(|_| Duration::from_secs(0)),
+            );
+        }
+    }
+
+    None
+}
+
+/// Check if the response is a `503 Service Unavailable` that tells us when to
+/// retry the request, e.g. because the homeserver is being restarted.
+fn service_unavailable_error(response: &http::Response<Vec<u8>>) -> Option<Error> {
+    if response.status() != StatusCode::SERVICE_UNAVAILABLE {
+        return None;
+    }
+
+    Some(Error::ServiceUnavailable {
+        retry_after: retry_after_header(response)?,
+    })
+}
+
+/// Check that the body of a response that should contain JSON can be parsed at
+/// all, so a misbehaving server results in a clear error instead of an opaque
+/// deserialization error.
+fn check_json_body(response: &http::Response<Vec<u8>>) -> Result<()> {
+    let status = response.status();
+
+    if response.body().is_empty() {
+        if status == StatusCode::NO_CONTENT {
+            Ok(())
+        } else {
+            Err(Error::EmptyResponseBody { status })
+        }
+    } else {
+        std::str::from_utf8(response.body())
+            .map(|_| ())
+            .map_err(|source| Error::InvalidResponseBody { status, source })
+    }
+}
+
+/// Get the time the homeserver spent on a request from the `Server-Timing`
+/// header of the response.
+///
+/// If the header contains a `total` metric its duration is used, otherwise the
+/// longest duration of all the metrics.
+fn server_timing(response: &http::Response<Vec<u8>>) -> Option<Duration> {
+    let mut total = None;
+    let mut longest: Option<f64> = None;
+
+    let metrics = response
+        .headers()
+        .get_all("server-timing")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','));
+
+    for metric in metrics {
+        let mut parts = metric.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+
+        let duration = parts
+            .filter_map(|p| {
+                let mut param = p.splitn(2, '=');
+                let key = param.next()?.trim();
+                let value = param.next()?.trim().trim_matches('"');
+
+                if key.eq_ignore_ascii_case("dur") {
+                    value.parse::<f64>().ok()
+                } else {
+                    None
+                }
+            })
+            .next();
+
+        if let Some(duration) = duration.filter(|d| d.is_finite() && *d >= 0.0) {
+            if name.eq_ignore_ascii_case("total") {
+                total = Some(duration);
+            }
+
+            longest = Some(longest.map_or(duration, |l| l.max(duration)));
+        }
+    }
+
+    // The durations are in milliseconds.
+    total
+        .or(longest)
+        .map(|d| Duration::from_secs_f64(d / 1000.0))
+}
+
+/// Headers whose values are replaced when logging requests and responses.
+const SENSITIVE_HEADERS: &[HeaderName] = &[
+    http::header::AUTHORIZATION,
+    http::header::PROXY_AUTHORIZATION,
+    http::header::COOKIE,
+    http::header::SET_COOKIE,
+];
+
+/// Fields of JSON bodies and query parameters whose values are replaced when
+/// logging requests and responses.
+const SENSITIVE_FIELDS: &[&str] = &[
+    "access_token",
+    "refresh_token",
+    "password",
+    "new_password",
+    "token",
+    "login_token",
+];
+
+/// The value secrets are replaced with.
+const REDACTED: &str = "***";
+
+/// HTTP messages that can be logged without leaking secrets.
+pub(crate) trait RedactSensitive {
+    /// Describe the message with the values of sensitive headers and fields
+    /// replaced.
+    fn redact_sensitive(&self) -> String;
+}
+
+impl RedactSensitive for http::Request<Vec<u8>> {
+    fn redact_sensitive(&self) -> String {
+        let uri = match self.uri().query() {
+            Some(query) => format!("{}?{}", self.uri().path(), redact_query(query)),
+            None => self.uri().path().to_owned(),
+        };
+
+        format!(
+            "{} {} {:?} {}",
+            self.method(),
+            uri,
+            redact_headers(self.headers()),
+            redact_body(self.body())
+        )
+    }
+}
+
+impl RedactSensitive for http::Response<Vec<u8>> {
+    fn redact_sensitive(&self) -> String {
+        format!(
+            "{} {:?} {}",
+            self.status(),
+            redact_headers(self.headers()),
+            redact_body(self.body())
+        )
+    }
+}
+
+fn redact_headers(headers: &HeaderMap) -> HeaderMap {
+    let mut headers = headers.clone();
+
+    for (name, value) in headers.iter_mut() {
+        if SENSITIVE_HEADERS.contains(name) {
+            *value = HeaderValue::from_static(REDACTED);
+        }
+    }
+
+    headers
+}
+
+fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| {
+            let key = pair.splitn(2, '=').next().unwrap_or_default();
+
+            if SENSITIVE_FIELDS.contains(&key) {
+                format!("{}={}", key, REDACTED)
+            } else {
+                pair.to_owned()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Describe the body, JSON bodies are logged with secrets replaced, other
+/// bodies, e.g. media, only by their size.
+fn redact_body(body: &[u8]) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(mut value) => {
+            redact_json(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes>", body.len()),
+    }
+}
+
+fn redact_json(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                if SENSITIVE_FIELDS.contains(&key.as_str()) {
+                    *value = serde_json::Value::String(REDACTED.to_owned());
+                } else {
+                    redact_json(value);
+                }
+            }
+        }
+        serde_json::Value::Array(array) => array.iter_mut().for_each(redact_json),
+        _ => {}
+    }
+}
+
+/// Get the access token a request is authenticated with.
+fn bearer_token(request: &http::Request<Vec<u8>>) -> Option<&str> {
+    request
+        .headers()
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+/// Check if the response is a `M_UNKNOWN_TOKEN` error that leaves the data of
+/// the session intact, i.e. one with `soft_logout` set.
+///
+/// Ruma doesn't keep the `soft_logout` field of the error around, so this
+/// looks at the raw response body.
+fn soft_logout_error(response: &http::Response<Vec<u8>>) -> Option<Error> {
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return None;
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(response.body()).ok()?;
+
+    if body.get("errcode")?.as_str()? != "M_UNKNOWN_TOKEN" || !body.get("soft_logout")?.as_bool()? {
+        return None;
+    }
+
+    let message = body
+        .get("error")
+        .and_then(|e| e.as_str())
+        .unwrap_or_default()
+        .to_owned();
+
+    Some(Error::SoftLogout { message })
+}
+
+/// Check if the response is a `M_CONSENT_NOT_GIVEN` error.
+///
+/// Ruma doesn't keep the `consent_uri` field of the error around, so this looks
+/// at the raw response body.
+fn consent_not_given_error(response: &http::Response<Vec<u8>>) -> Option<Error> {
+    if response.status() != StatusCode::FORBIDDEN {
+        return None;
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(response.body()).ok()?;
+
+    if body.get("errcode")?.as_str()? != "M_CONSENT_NOT_GIVEN" {
+        return None;
+    }
+
+    let consent_uri = Url::parse(body.get("consent_uri")?.as_str()?).ok()?;
+    let message = body
+        .get("error")
+        .and_then(|e| e.as_str())
+        .unwrap_or_default()
+        .to_owned();
+
+    Some(Error::ConsentNotGiven {
+        message,
+        consent_uri,
+    })
+}
+
+/// Wait for the given response, failing with an [`Error::RequestTimeout`] if
+/// it doesn't arrive within the `timeout`.
+async fn with_timeout(
+    response: impl Future<Output = Result<http::Response<Vec<u8>>>>,
+    timeout: Option<Duration>,
+) -> Result<http::Response<Vec<u8>>> {
+    match timeout {
+        Some(timeout) => match select(Box::pin(response), sleep::new(timeout)).await {
+            Either::Left((response, _)) => response,
+            Either::Right(_) => Err(Error::RequestTimeout(timeout)),
+        },
+        None => response.await,
+    }
+}
+
+/// Send a request over the given HTTP client, failing with a
+/// `RequestTimeout` error if no response was received within the timeout.
+async fn send_with_timeout(
+    inner: &dyn HttpSend,
+    request: http::Request<Vec<u8>>,
+    timeout: Option<Duration>,
+) -> Result<http::Response<Vec<u8>>> {
+    match timeout {
+        Some(timeout) if inner.enforces_timeout() => {
+            inner.send_request_with_timeout(request, timeout).await
+        }
+        Some(timeout) => {
+            with_timeout(
+                inner.send_request_with_timeout(request, timeout),
+                Some(timeout),
+            )
+            .await
+        }
+        None => inner.send_request(request).await,
+    }
+}
+
+/// Check if the response is a `M_RESOURCE_LIMIT_EXCEEDED` error.
+///
+/// Ruma doesn't keep the `limit_type` field of the error around, so this looks
+/// at the raw response body.
+fn resource_limit_exceeded_error(response: &http::Response<Vec<u8>>) -> Option<Error> {
+    if response.status().is_success() {
+        return None;
+    }
+
+    let body: serde_json::Value = serde_json::from_slice(response.body()).ok()?;
+
+    if body.get("errcode")?.as_str()? != "M_RESOURCE_LIMIT_EXCEEDED" {
+        return None;
+    }
+
+    let admin_contact = body.get("admin_contact")?.as_str()?.to_owned();
+    let limit_type = body
+        .get("limit_type")
+        .and_then(|l| l.as_str())
+        .map(|l| l.to_owned());
+    let message = body
+        .get("error")
+        .and_then(|e| e.as_str())
+        .unwrap_or_default()
+        .to_owned();
+
+    Some(Error::ResourceLimitExceeded(ResourceLimitInfo {
+        message,
+        limit_type,
+        admin_contact,
+    }))
+}
+
+/// Build a client with the specified configuration.
+pub(crate) fn client_with_config(
+    config: &ClientConfig,
+    timeout: Option<Duration>,
+) -> Result<ReqwestClient> {
+    let http_client = reqwest::Client::builder();
+
+    #[cfg(not(target_arch = "wasm32"))]
+    let http_client = {
+        let http_client = match timeout {
+            Some(x) => http_client.timeout(x),
+            None => http_client,
+        };
+
+        let http_client = match config.connect_timeout {
+            Some(x) => http_client.connect_timeout(x),
+            None => http_client,
+        };
+
+        let http_client = match config.pool_idle_timeout {
+            Some(x) => http_client.pool_idle_timeout(x),
+            None => http_client,
+        };
+
+        let http_client = match config.pool_max_idle_per_host {
+            Some(x) => http_client.pool_max_idle_per_host(x),
+            None => http_client,
+        };
+
+        let http_client = if config.disable_ssl_verification {
+            http_client.danger_accept_invalid_certs(true)
+        } else {
+            http_client
+        };
+
+        let http_client = config
+            .root_certificates
+            .iter()
+            .cloned()
+            .fold(http_client, |c, cert| c.add_root_certificate(cert));
+
+        let http_client = match &config.identity {
+            Some(i) => http_client.identity(i.clone()),
+            None => http_client,
+        };
+
+        #[cfg(feature = "rustls-tls")]
+        let http_client = match &config.tls_hostname {
+            Some(name) if !config.disable_ssl_verification => {
+                // The preconfigured TLS config replaces the root certificates
+                // and the identity, don't silently drop them.
+                if !config.root_certificates.is_empty() || config.identity.is_some() {
+                    return Err(Error::UnsupportedTlsConfig);
+                }
+
+                http_client.use_preconfigured_tls(crate::tls::config_for_hostname(name.clone()))
+            }
+            _ => http_client,
+        };
+
+        let http_client = match &config.proxy {
+            Some(p) => http_client.proxy(p.clone()),
+            None => http_client,
+        };
+
+        // reqwest requests compressed responses as soon as the feature is
+        // enabled, keep it off unless it was asked for.
+        #[cfg(feature = "gzip")]
+        let http_client = http_client.gzip(config.gzip);
+
+        let http_client = http_client.redirect(redirect_policy(
+            config.redirect_policy,
+            config.max_redirects.unwrap_or(DEFAULT_MAX_REDIRECTS),
+        ));
+
+        let mut headers = reqwest::header::HeaderMap::new();
+
+        let user_agent = match &config.user_agent {
+            Some(a) => a.clone(),
+            None => HeaderValue::from_str(&format!("matrix-rust-sdk {}", crate::VERSION)).unwrap(),
+        };
+
+        headers.insert(reqwest::header::USER_AGENT, user_agent);
+
+        http_client.default_headers(headers)
+    };
+
+    #[cfg(target_arch = "wasm32")]
+    #[allow(unused)]
+    let _ = (config, timeout);
+
+    #[cfg(all(feature = "gzip", not(target_arch = "wasm32")))]
+    let max_body_size = if config.gzip {
+        Some(
+            config
+                .max_decompressed_size
+                .unwrap_or(DEFAULT_MAX_DECOMPRESSED_SIZE),
+        )
+    } else {
+        None
+    };
+    #[cfg(not(all(feature = "gzip", not(target_arch = "wasm32"))))]
+    let max_body_size = None;
+
+    Ok(ReqwestClient {
+        client: http_client.build()?,
+        #[cfg(not(target_arch = "wasm32"))]
+        timeout,
+        max_body_size,
+    })
+}
+
+/// The HTTP client that is used if no custom one was configured, a reqwest
+/// client that enforces the limits of the `ClientConfig` it was created from.
+#[derive(Clone, Debug)]
+pub(crate) struct ReqwestClient {
+    client: Client,
+    /// The timeout the reqwest client was configured with, it limits the
+    /// timeout of single requests as well.
+    #[cfg(not(target_arch = "wasm32"))]
+    timeout: Option<Duration>,
+    /// The maximal size of a response body, only set if compressed responses
+    /// were requested.
+    max_body_size: Option<usize>,
+}
+
+impl ReqwestClient {
+    /// Get a client that shares the connection pool with this one but reads
+    /// response bodies of any size.
+    pub(crate) fn without_body_limit(self) -> Self {
+        Self {
+            max_body_size: None,
+            ..self
+        }
+    }
+}
+
+/// Create a reqwest redirect policy from the given policy that follows at
+/// most `max_redirects` redirects.
+///
+/// reqwest already removes the `Authorization` header if a redirect points to
+/// a different host or port, but it keeps the header if only the scheme
+/// changes. Redirects from `https` to `http` are refused so the access token
+/// can't be sent over a downgraded connection, upgrades are fine.
+#[cfg(not(target_arch = "wasm32"))]
+fn redirect_policy(policy: RedirectPolicy, max_redirects: usize) -> reqwest::redirect::Policy {
+    reqwest::redirect::Policy::custom(move |attempt| {
+        let previous = attempt.previous().last();
+        let url = attempt.url();
+
+        let downgraded = previous.map_or(false, |p| is_downgrade(p, url));
+        let origin_changed = previous.map_or(false, |p| {
+            p.host_str() != url.host_str()
+                || p.port_or_known_default() != url.port_or_known_default()
+        });
+
+        if policy == RedirectPolicy::None {
+            attempt.stop()
+        } else if attempt.previous().len() > max_redirects {
+            attempt.error("too many redirects")
+        } else if downgraded {
+            attempt.error("refusing to follow a redirect from https to http")
+        } else if origin_changed && policy == RedirectPolicy::SameOrigin {
+            attempt.error("refusing to follow a redirect to a different origin")
+        } else {
+            attempt.follow()
+        }
+    })
+}
+
+/// Check if a redirect from one URL to another downgrades the connection from
+/// `https` to `http`.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn is_downgrade(from: &Url, to: &Url) -> bool {
+    from.scheme() == "https" && to.scheme() == "http"
+}
+
+/// Convert an error that happened while sending a request into our error type.
+fn reqwest_error(error: reqwest::Error) -> Error {
+    #[cfg(all(feature = "rustls-tls", not(target_arch = "wasm32")))]
+    let hostname_mismatch = crate::tls::is_hostname_mismatch(&error);
+    #[cfg(not(all(feature = "rustls-tls", not(target_arch = "wasm32"))))]
+    let hostname_mismatch = false;
+
+    if hostname_mismatch {
+        Error::HostnameMismatch
+    } else {
+        Error::Reqwest(error)
+    }
+}
+
+async fn response_to_http_response(mut response: Response) -> Result<http::Response<Vec<u8>>> {
+    let status = response.status();
+
+    let mut http_builder = HttpResponse::builder().status(status);
+    let headers = http_builder.headers_mut().unwrap();
+
+    for (k, v) in response.headers_mut().drain() {
+        if let Some(key) = k {
+            headers.insert(key, v);
+        }
+    }
+
+    let body = response.bytes().await?.as_ref().to_owned();
+
+    Ok(http_builder.body(body).unwrap())
+}
+
+/// Like `response_to_http_response()`, but stops reading the body as soon as
+/// it grows larger than `max_size` bytes.
+///
+/// The body is decompressed while it's streamed, so this catches responses
+/// that are small on the wire but decompress to a huge body.
+#[cfg(not(target_arch = "wasm32"))]
+async fn limited_response_to_http_response(
+    mut response: Response,
+    max_size: usize,
+) -> Result<http::Response<Vec<u8>>> {
+    let status = response.status();
+
+    let mut http_builder = HttpResponse::builder().status(status);
+    let headers = http_builder.headers_mut().unwrap();
+
+    for (k, v) in response.headers_mut().drain() {
+        if let Some(key) = k {
+            headers.insert(key, v);
+        }
+    }
+
+    let mut body = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_size {
+            return Err(Error::DecompressionLimitExceeded(max_size));
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(http_builder.body(body).unwrap())
+}
+
+impl ReqwestClient {
+    async fn to_http_response(&self, response: Response) -> Result<http::Response<Vec<u8>>> {
+        match self.max_body_size {
+            #[cfg(not(target_arch = "wasm32"))]
+            Some(max_size) => limited_response_to_http_response(response, max_size).await,
+            _ => response_to_http_response(response).await,
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl HttpSend for ReqwestClient {
+    async fn send_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let response = self
+            .client
+            .execute(reqwest::Request::try_from(request)?)
+            .await
+            .map_err(reqwest_error)?;
+
+        self.to_http_response(response).await
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_request_with_timeout(
+        &self,
+        request: http::Request<Vec<u8>>,
+        timeout: Duration,
+    ) -> Result<http::Response<Vec<u8>>> {
+        // A timeout set on the request replaces the one of the client, don't
+        // let it exceed the configured one.
+        let effective = self.timeout.map_or(timeout, |t| t.min(timeout));
+
+        let mut request = reqwest::Request::try_from(request)?;
+        *request.timeout_mut() = Some(effective);
+
+        let response = self.client.execute(request).await.map_err(|e| {
+            if e.is_timeout() && effective == timeout {
+                Error::RequestTimeout(timeout)
+            } else {
+                reqwest_error(e)
+            }
+        })?;
+
+        self.to_http_response(response).await
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn enforces_timeout(&self) -> bool {
+        true
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<BodyStream>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let (parts, stream) = request.into_parts();
+
+        let mut request = reqwest::Request::try_from(http::Request::from_parts(parts, Vec::new()))?;
+        *request.body_mut() = Some(reqwest::Body::wrap_stream(stream));
+
+        let response = self.client.execute(request).await.map_err(reqwest_error)?;
+
+        self.to_http_response(response).await
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl HttpSend for Client {
     async fn send_request(
         &self,
         request: http::Request<Vec<u8>>,
     ) -> Result<http::Response<Vec<u8>>> {
-        Ok(
-            response_to_http_response(self.execute(reqwest::Request::try_from(request)?).await?)
-                .await?,
-        )
+        let response = self
+            .execute(reqwest::Request::try_from(request)?)
+            .await
+            .map_err(reqwest_error)?;
+
+        Ok(response_to_http_response(response).await?)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn send_streaming_request(
+        &self,
+        request: http::Request<BodyStream>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let (parts, stream) = request.into_parts();
+
+        let mut request = reqwest::Request::try_from(http::Request::from_parts(parts, Vec::new()))?;
+        *request.body_mut() = Some(reqwest::Body::wrap_stream(stream));
+
+        let response = self.execute(request).await.map_err(reqwest_error)?;
+
+        Ok(response_to_http_response(response).await?)
     }
 }
@@ -45,6 +45,11 @@
 //! of Synapse in compliance with the Matrix API specification.
 //! * `markdown`: Support for sending markdown formatted messages.
 //! * `socks`: Enables SOCKS support in reqwest, the default HTTP client.
+//! * `gzip`: Enables support for gzip compressed responses in reqwest, it
+//! needs to be turned on with `ClientConfig::gzip`.
+//! * `testing`: Provides `MockHttpSend`, a `HttpSend` implementation that
+//! answers requests with pre-programmed responses, for tests that shouldn't
+//! touch the network.
 
 #![deny(
     missing_debug_implementations,
@@ -69,15 +74,29 @@ compile_error!("only one of 'native-tls' or 'rustls-tls' features can be enabled
 pub use matrix_sdk_base::crypto::LocalTrust;
 pub use matrix_sdk_base::{
     Error as BaseError, EventEmitter, InvitedRoom, JoinedRoom, LeftRoom, RoomInfo, RoomMember,
-    RoomState, Session, StoreError,
+    RoomState, Session, SessionInfo, StoreError,
 };
 
+pub use bytes;
 pub use matrix_sdk_common::*;
 pub use reqwest;
 
 mod client;
+mod device_name;
 mod error;
+mod health;
 mod http_client;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "testing")]
+mod mock;
+mod profile_cache;
+mod queued_request;
+mod registration;
+mod support;
+#[cfg(all(feature = "rustls-tls", not(target_arch = "wasm32")))]
+mod tls;
+mod uiaa;
 
 #[cfg(feature = "encryption")]
 mod device;
@@ -86,15 +105,33 @@ mod sas;
 #[cfg(feature = "encryption")]
 mod verification_request;
 
-pub use client::{Client, ClientConfig, LoopCtrl, SyncSettings};
+pub use client::{
+    Client, ClientConfig, LoopCtrl, RedirectPolicy, RequestConfig, RetryConfig, SyncSettings,
+    TransactionIdGenerator,
+};
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use device::Device;
-pub use error::{Error, Result};
-pub use http_client::HttpSend;
+pub use device_name::device_display_name;
+pub use error::{Error, ResourceLimitInfo, Result};
+pub use health::HomeserverHealth;
+pub use http_client::{
+    BodyStream, HttpSend, PendingRequest, RequestInterceptor, RequestSigner, RequestTiming,
+    ResponseRewriter, RetryHandler, RetryInfo, RetryReason, TokenRefreshHandler,
+};
+#[cfg(feature = "metrics")]
+#[cfg_attr(feature = "docs", doc(cfg(metrics)))]
+pub use metrics::{BandwidthUsage, EndpointCategory};
+#[cfg(feature = "testing")]
+#[cfg_attr(feature = "docs", doc(cfg(testing)))]
+pub use mock::MockHttpSend;
+pub use queued_request::QueuedRequest;
+pub use registration::RegistrationRequirements;
 #[cfg(feature = "encryption")]
 #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
 pub use sas::Sas;
+pub use support::{SupportContact, SupportInfo};
+pub use uiaa::resume_uiaa_auth;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,316 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A small collector for request metrics that can be rendered in the
+//! Prometheus text exposition format.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use http::Method as HttpMethod;
+use matrix_sdk_common::instant::Duration;
+
+use crate::Result;
+
+/// The upper bounds, in seconds, of the request duration histogram buckets.
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// A coarse category of endpoints, used to aggregate the bandwidth usage of
+/// the client.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EndpointCategory {
+    /// The `/sync` endpoint.
+    Sync,
+    /// Media uploads, downloads and thumbnails.
+    Media,
+    /// Sending message and to-device events.
+    Send,
+    /// Reading and setting room state.
+    State,
+    /// A category defined by the application, see
+    /// [`ClientConfig::endpoint_category`].
+    ///
+    /// [`ClientConfig::endpoint_category`]: struct.ClientConfig.html#method.endpoint_category
+    Custom(String),
+    /// All the other endpoints.
+    Other,
+}
+
+impl EndpointCategory {
+    /// Get the built-in category of the endpoint with the given path.
+    fn from_path(path: &str) -> Self {
+        if path.contains("/_matrix/media/") {
+            Self::Media
+        } else if path.ends_with("/sync") {
+            Self::Sync
+        } else if path.contains("/send/") || path.contains("/sendToDevice/") {
+            Self::Send
+        } else if path.contains("/state") {
+            Self::State
+        } else {
+            Self::Other
+        }
+    }
+
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Sync => "sync",
+            Self::Media => "media",
+            Self::Send => "send",
+            Self::State => "state",
+            Self::Custom(name) => name,
+            Self::Other => "other",
+        }
+    }
+}
+
+/// The bandwidth the requests of an endpoint category used.
+///
+/// Only the bodies of the requests and responses are counted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthUsage {
+    /// The number of requests that received a response.
+    pub requests: u64,
+    /// The number of bytes sent to the homeserver.
+    pub bytes_sent: u64,
+    /// The number of bytes received from the homeserver.
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    /// The number of requests, keyed by the method and the status.
+    requests: BTreeMap<(String, String), u64>,
+    /// The request durations, keyed by the method.
+    durations: BTreeMap<String, Histogram>,
+    /// The durations the homeserver reported in the `Server-Timing` header,
+    /// keyed by the method.
+    server_durations: BTreeMap<String, Histogram>,
+    /// The bandwidth usage, keyed by the endpoint category.
+    bandwidth: BTreeMap<EndpointCategory, BandwidthUsage>,
+}
+
+/// Collector for the metrics of the requests the client sends out.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metrics {
+    inner: Arc<Mutex<MetricsInner>>,
+    /// Path fragments that map endpoints to custom categories, they take
+    /// precedence over the built-in categories.
+    categories: Arc<Vec<(String, EndpointCategory)>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(categories: Vec<(String, EndpointCategory)>) -> Self {
+        Self {
+            inner: Default::default(),
+            categories: Arc::new(categories),
+        }
+    }
+
+    fn category(&self, path: &str) -> EndpointCategory {
+        self.categories
+            .iter()
+            .find(|(fragment, _)| path.contains(fragment.as_str()))
+            .map(|(_, category)| category.clone())
+            .unwrap_or_else(|| EndpointCategory::from_path(path))
+    }
+
+    /// Record the bandwidth a request that received a response used.
+    pub(crate) fn record_bandwidth(&self, path: &str, bytes_sent: usize, bytes_received: usize) {
+        let category = self.category(path);
+        let mut inner = self.inner.lock().unwrap();
+        let usage = inner.bandwidth.entry(category).or_default();
+
+        usage.requests += 1;
+        usage.bytes_sent += bytes_sent as u64;
+        usage.bytes_received += bytes_received as u64;
+    }
+
+    /// Get the bandwidth usage per endpoint category.
+    pub(crate) fn bandwidth(&self) -> BTreeMap<EndpointCategory, BandwidthUsage> {
+        self.inner.lock().unwrap().bandwidth.clone()
+    }
+
+    /// Record a finished request.
+    ///
+    /// Requests that failed without a response from the homeserver are
+    /// recorded with an `error` status.
+    pub(crate) fn record(
+        &self,
+        method: &HttpMethod,
+        response: &Result<http::Response<Vec<u8>>>,
+        duration: Duration,
+        server_duration: Option<Duration>,
+    ) {
+        let status = match response {
+            Ok(response) => response.status().as_u16().to_string(),
+            Err(_) => "error".to_owned(),
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+
+        *inner
+            .requests
+            .entry((method.to_string(), status))
+            .or_default() += 1;
+
+        inner
+            .durations
+            .entry(method.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+
+        if let Some(server_duration) = server_duration {
+            inner
+                .server_durations
+                .entry(method.to_string())
+                .or_default()
+                .observe(server_duration.as_secs_f64());
+        }
+    }
+
+    /// Render the collected metrics in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let mut text = String::new();
+
+        // Writing into a `String` can't fail.
+        let _ = writeln!(
+            text,
+            "# HELP matrix_sdk_requests_total The number of requests sent to the homeserver."
+        );
+        let _ = writeln!(text, "# TYPE matrix_sdk_requests_total counter");
+
+        for ((method, status), count) in &inner.requests {
+            let _ = writeln!(
+                text,
+                "matrix_sdk_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        render_histograms(
+            &mut text,
+            "matrix_sdk_request_duration_seconds",
+            "The time it took to get a response from the homeserver.",
+            &inner.durations,
+        );
+
+        render_histograms(
+            &mut text,
+            "matrix_sdk_server_duration_seconds",
+            "The time the homeserver spent processing a request, as reported in the \
+             Server-Timing header.",
+            &inner.server_durations,
+        );
+
+        let _ = writeln!(
+            text,
+            "# HELP matrix_sdk_bytes_sent_total The number of body bytes sent to the homeserver."
+        );
+        let _ = writeln!(text, "# TYPE matrix_sdk_bytes_sent_total counter");
+
+        for (category, usage) in &inner.bandwidth {
+            let _ = writeln!(
+                text,
+                "matrix_sdk_bytes_sent_total{{category=\"{}\"}} {}",
+                category.as_str(),
+                usage.bytes_sent
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "# HELP matrix_sdk_bytes_received_total The number of body bytes received from the \
+             homeserver."
+        );
+        let _ = writeln!(text, "# TYPE matrix_sdk_bytes_received_total counter");
+
+        for (category, usage) in &inner.bandwidth {
+            let _ = writeln!(
+                text,
+                "matrix_sdk_bytes_received_total{{category=\"{}\"}} {}",
+                category.as_str(),
+                usage.bytes_received
+            );
+        }
+
+        text
+    }
+}
+
+fn render_histograms(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    histograms: &BTreeMap<String, Histogram>,
+) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} histogram", name);
+
+    for (method, histogram) in histograms {
+        for (bucket, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                text,
+                "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                name, method, bound, bucket
+            );
+        }
+
+        let _ = writeln!(
+            text,
+            "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+            name, method, histogram.count
+        );
+        let _ = writeln!(
+            text,
+            "{}_sum{{method=\"{}\"}} {}",
+            name, method, histogram.sum
+        );
+        let _ = writeln!(
+            text,
+            "{}_count{{method=\"{}\"}} {}",
+            name, method, histogram.count
+        );
+    }
+}
@@ -0,0 +1,170 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A `HttpSend` implementation for tests that don't touch the network.
+
+use std::sync::Mutex;
+
+use http::{HeaderMap, Method, StatusCode};
+use matrix_sdk_common::async_trait;
+
+use crate::{HttpSend, Result};
+
+#[derive(Debug)]
+struct MockResponse {
+    method: Method,
+    path: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+}
+
+impl MockResponse {
+    fn to_response(&self) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+
+        response
+    }
+}
+
+/// A `HttpSend` implementation that answers requests with pre-programmed
+/// responses and records the requests it saw.
+///
+/// Responses are matched by the method and the path of the request, the
+/// query string is ignored. A response answers every matching request, if
+/// multiple responses match, the one that was added last wins. Requests that
+/// don't match any response get a `404` with a `M_UNRECOGNIZED` error, like
+/// homeservers respond to unknown endpoints.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use matrix_sdk::{
+///     api::unversioned::get_supported_versions, Client, ClientConfig, MockHttpSend,
+/// };
+/// use url::Url;
+///
+/// # futures::executor::block_on(async {
+/// let mock = Arc::new(MockHttpSend::new());
+///
+/// mock.add_response(
+///     http::Method::GET,
+///     "/_matrix/client/versions",
+///     http::Response::new(br#"{ "versions": ["r0.6.1"] }"#.to_vec()),
+/// );
+///
+/// let homeserver = Url::parse("http://localhost:8080").unwrap();
+/// let config = ClientConfig::new().client(mock.clone());
+/// let client = Client::new_with_config(homeserver, config).unwrap();
+///
+/// let response = client
+///     .send(get_supported_versions::Request::new())
+///     .await
+///     .unwrap();
+///
+/// assert_eq!(response.versions, vec!["r0.6.1"]);
+/// assert_eq!(mock.requests()[0].uri().path(), "/_matrix/client/versions");
+/// # });
+/// ```
+#[derive(Debug, Default)]
+pub struct MockHttpSend {
+    responses: Mutex<Vec<MockResponse>>,
+    requests: Mutex<Vec<http::Request<Vec<u8>>>>,
+}
+
+impl MockHttpSend {
+    /// Create a new `MockHttpSend` without any responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer requests with the given method and path with the given
+    /// response.
+    ///
+    /// # Arguments
+    ///
+    /// * `method` - The method of the requests that should be answered.
+    ///
+    /// * `path` - The path of the requests that should be answered, e.g.
+    /// `/_matrix/client/r0/devices`.
+    ///
+    /// * `response` - The response the requests are answered with.
+    pub fn add_response(&self, method: Method, path: &str, response: http::Response<Vec<u8>>) {
+        let (parts, body) = response.into_parts();
+
+        self.responses.lock().unwrap().push(MockResponse {
+            method,
+            path: path.to_owned(),
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        });
+    }
+
+    /// Get copies of the requests that were sent so far, in the order they
+    /// were sent.
+    pub fn requests(&self) -> Vec<http::Request<Vec<u8>>> {
+        self.requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                let mut request = http::Request::new(r.body().clone());
+                *request.method_mut() = r.method().clone();
+                *request.uri_mut() = r.uri().clone();
+                *request.headers_mut() = r.headers().clone();
+
+                request
+            })
+            .collect()
+    }
+
+    /// Forget the requests that were recorded so far.
+    pub fn clear_requests(&self) {
+        self.requests.lock().unwrap().clear();
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl HttpSend for MockHttpSend {
+    async fn send_request(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<http::Response<Vec<u8>>> {
+        let response = self
+            .responses
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|r| r.method == request.method() && r.path == request.uri().path())
+            .map(|r| r.to_response());
+
+        self.requests.lock().unwrap().push(request);
+
+        Ok(response.unwrap_or_else(|| {
+            let mut response = http::Response::new(
+                br#"{ "errcode": "M_UNRECOGNIZED", "error": "Unrecognized request" }"#.to_vec(),
+            );
+            *response.status_mut() = StatusCode::NOT_FOUND;
+
+            response
+        }))
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A short-lived in-memory cache for the profiles of other users.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+use matrix_sdk_common::{
+    api::r0::profile::get_profile,
+    identifiers::UserId,
+    instant::{Duration, Instant},
+    locks::Mutex,
+};
+
+use crate::Result;
+
+/// How long a profile is cached if no TTL is configured.
+pub(crate) const DEFAULT_PROFILE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// Bumped on every invalidation, a fetched profile is only cached if no
+    /// invalidation happened while it was being fetched.
+    generation: u64,
+    profiles: HashMap<UserId, (Instant, get_profile::Response)>,
+}
+
+/// Cache for the profiles of other users.
+///
+/// Concurrent lookups of the same profile share a single request.
+#[derive(Clone, Debug)]
+pub(crate) struct ProfileCache {
+    ttl: Duration,
+    entries: Arc<SyncMutex<Entries>>,
+    /// Locks making sure only one request per user is in flight.
+    locks: Arc<SyncMutex<HashMap<UserId, Arc<Mutex<()>>>>>,
+}
+
+impl ProfileCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Default::default(),
+            locks: Default::default(),
+        }
+    }
+
+    fn cached(&self, user_id: &UserId) -> Option<get_profile::Response> {
+        self.entries
+            .lock()
+            .unwrap()
+            .profiles
+            .get(user_id)
+            .filter(|(fetched, _)| fetched.elapsed() < self.ttl)
+            .map(|(_, profile)| profile.clone())
+    }
+
+    /// Get the cached profile of the given user or fetch it using `fetch`.
+    ///
+    /// Failed fetches aren't cached, tasks that waited for a failed fetch
+    /// will try again themselves.
+    pub(crate) async fn get_or_fetch<F, Fut>(
+        &self,
+        user_id: &UserId,
+        fetch: F,
+    ) -> Result<get_profile::Response>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<get_profile::Response>>,
+    {
+        if let Some(profile) = self.cached(user_id) {
+            return Ok(profile);
+        }
+
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(user_id.clone())
+            .or_default()
+            .clone();
+
+        let result = {
+            let _guard = lock.lock().await;
+
+            // Another task might have fetched the profile while we were
+            // waiting for the lock.
+            if let Some(profile) = self.cached(user_id) {
+                return Ok(profile);
+            }
+
+            let generation = self.entries.lock().unwrap().generation;
+            let result = fetch().await;
+
+            if let Ok(profile) = &result {
+                let mut entries = self.entries.lock().unwrap();
+
+                if entries.generation == generation {
+                    entries
+                        .profiles
+                        .insert(user_id.clone(), (Instant::now(), profile.clone()));
+                }
+            }
+
+            result
+        };
+
+        let mut locks = self.locks.lock().unwrap();
+
+        // Only the map and we hold the lock, nobody else is waiting for it.
+        if Arc::strong_count(&lock) == 2 {
+            locks.remove(user_id);
+        }
+
+        result
+    }
+
+    /// Remove the cached profile of the given user.
+    pub(crate) fn invalidate(&self, user_id: &UserId) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.generation += 1;
+        entries.profiles.remove(user_id);
+    }
+}
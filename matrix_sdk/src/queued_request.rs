@@ -0,0 +1,190 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A serializable form of outgoing requests, used to persist requests that
+//! should be sent out at a later point, e.g. once the client is online again.
+
+use http::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    HeaderValue, Method as HttpMethod,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use matrix_sdk_common::AuthScheme;
+
+use crate::{OutgoingRequest, Result};
+
+/// The base URL that is used to convert requests, only the path of the
+/// resulting URL is kept.
+const PLACEHOLDER_BASE_URL: &str = "https://localhost";
+
+/// The access token that is used to convert requests that need
+/// authentication, the token gets removed again before the request is queued.
+const PLACEHOLDER_ACCESS_TOKEN: &str = "placeholder";
+
+/// A serializable representation of an outgoing request.
+///
+/// The queued form contains neither the homeserver nor the access token, both
+/// are added again when the request gets sent out using
+/// [`Client::send_raw`](struct.Client.html#method.send_raw).
+///
+/// # Example
+///
+/// ```no_run
+/// # use matrix_sdk::{Client, QueuedRequest};
+/// # use matrix_sdk::api::r0::device::get_devices;
+/// # use url::Url;
+/// # use futures::executor::block_on;
+/// # let homeserver = Url::parse("http://example.com").unwrap();
+/// # let client = Client::new(homeserver).unwrap();
+/// # block_on(async {
+/// let request = QueuedRequest::new(get_devices::Request::new()).unwrap();
+///
+/// // Store the request somewhere durable.
+/// let serialized = serde_json::to_string(&request).unwrap();
+///
+/// // And replay it once we're online again.
+/// let request: QueuedRequest = serde_json::from_str(&serialized).unwrap();
+/// let response = client.send_raw(&request).await.unwrap();
+/// # });
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedRequest {
+    /// The HTTP method of the request.
+    pub method: String,
+    /// The path and query of the request, relative to the homeserver.
+    pub path: String,
+    /// The headers of the request, sorted by name, without the
+    /// `Authorization` header.
+    pub headers: Vec<(String, String)>,
+    /// The body of the request.
+    pub body: Vec<u8>,
+    /// Does the request need to be authenticated with the access token when
+    /// it gets sent out.
+    pub authenticated: bool,
+}
+
+impl QueuedRequest {
+    /// Convert the given request into its queued form.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - A filled out and valid request for the endpoint to be hit
+    pub fn new<Request: OutgoingRequest>(request: Request) -> Result<Self> {
+        let authenticated = match Request::METADATA.authentication {
+            AuthScheme::AccessToken => true,
+            AuthScheme::None => false,
+            _ => return Err(crate::Error::NotClientRequest),
+        };
+
+        let access_token = if authenticated {
+            Some(PLACEHOLDER_ACCESS_TOKEN)
+        } else {
+            None
+        };
+
+        let mut request = request.try_into_http_request(PLACEHOLDER_BASE_URL, access_token)?;
+        request.headers_mut().remove(AUTHORIZATION);
+
+        // Mirror what the client does when it sends out requests directly.
+        if let HttpMethod::POST | HttpMethod::PUT | HttpMethod::DELETE = *request.method() {
+            if !request.headers().contains_key(CONTENT_TYPE) {
+                request
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            }
+        }
+
+        let path = request
+            .uri()
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or_else(|| request.uri().path())
+            .to_owned();
+
+        let mut headers: Vec<(String, String)> = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                value
+                    .to_str()
+                    .ok()
+                    .map(|v| (name.as_str().to_owned(), v.to_owned()))
+            })
+            .collect();
+        headers.sort();
+
+        Ok(Self {
+            method: request.method().as_str().to_owned(),
+            path,
+            headers,
+            body: request.into_body(),
+            authenticated,
+        })
+    }
+
+    /// Get a hash of the content of the request.
+    ///
+    /// Two queued requests that would result in the same HTTP request will
+    /// have the same hash, this can be used to deduplicate queued requests.
+    pub fn content_hash(&self) -> String {
+        let mut hasher = Sha256::new();
+
+        hasher.update(self.method.as_bytes());
+        hasher.update(&[0]);
+        hasher.update(self.path.as_bytes());
+        hasher.update(&[0]);
+
+        for (name, value) in &self.headers {
+            hasher.update(name.as_bytes());
+            hasher.update(b":");
+            hasher.update(value.as_bytes());
+            hasher.update(&[0]);
+        }
+
+        hasher.update(&[u8::from(self.authenticated)]);
+        hasher.update(&self.body);
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Convert the queued request back into a HTTP request for the given
+    /// homeserver.
+    pub(crate) fn to_http_request(
+        &self,
+        homeserver: &str,
+        access_token: Option<&str>,
+    ) -> Result<http::Request<Vec<u8>>> {
+        let uri = format!("{}{}", homeserver.trim_end_matches('/'), self.path);
+
+        let mut request = http::Request::builder()
+            .method(self.method.as_str())
+            .uri(uri);
+
+        for (name, value) in &self.headers {
+            request = request.header(name.as_str(), value.as_str());
+        }
+
+        if let Some(access_token) = access_token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", access_token));
+        }
+
+        Ok(request.body(self.body.clone())?)
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types describing what a homeserver requires to register a new account.
+
+use matrix_sdk_common::{api::r0::uiaa::UiaaInfo, identifiers::UserId};
+
+/// The stage type of the registration token stage.
+const REGISTRATION_TOKEN: &str = "m.login.registration_token";
+/// The stage type of the ReCaptcha stage.
+const RECAPTCHA: &str = "m.login.recaptcha";
+/// The stage type of the email verification stage.
+const EMAIL_IDENTITY: &str = "m.login.email.identity";
+/// The stage type of the terms of service stage.
+const TERMS: &str = "m.login.terms";
+
+/// The requirements a homeserver has for registering a new account.
+///
+/// A stage is only considered to be required if every flow the homeserver
+/// offers contains it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistrationRequirements {
+    /// The homeserver doesn't allow registration of new accounts.
+    Disabled,
+    /// Registration is possible by completing one of the flows.
+    Enabled {
+        /// The flows the homeserver offers, every flow is a list of the
+        /// stage types that need to be completed, e.g.
+        /// `m.login.registration_token`.
+        flows: Vec<Vec<String>>,
+        /// The id of the UIAA session the probe started, it can be reused for
+        /// the actual registration.
+        session: Option<String>,
+    },
+    /// The homeserver doesn't require anything to register and accepted the
+    /// probe itself, an account was created.
+    ///
+    /// The account isn't logged in, but it exists on the homeserver from now
+    /// on.
+    AccountCreated {
+        /// The user id of the account that was created.
+        user_id: UserId,
+    },
+}
+
+impl RegistrationRequirements {
+    pub(crate) fn from_uiaa_info(info: &UiaaInfo) -> Self {
+        Self::Enabled {
+            flows: info.flows.iter().map(|f| f.stages.clone()).collect(),
+            session: info.session.clone(),
+        }
+    }
+
+    /// Is registration disabled on the homeserver.
+    pub fn is_disabled(&self) -> bool {
+        matches!(self, Self::Disabled)
+    }
+
+    /// Does every registration flow of the homeserver contain the given
+    /// stage.
+    pub fn requires_stage(&self, stage: &str) -> bool {
+        match self {
+            Self::Disabled | Self::AccountCreated { .. } => false,
+            Self::Enabled { flows, .. } => {
+                !flows.is_empty() && flows.iter().all(|f| f.iter().any(|s| s == stage))
+            }
+        }
+    }
+
+    /// Does the homeserver require a registration token.
+    pub fn requires_registration_token(&self) -> bool {
+        self.requires_stage(REGISTRATION_TOKEN)
+    }
+
+    /// Does the homeserver require a ReCaptcha to be solved.
+    pub fn requires_recaptcha(&self) -> bool {
+        self.requires_stage(RECAPTCHA)
+    }
+
+    /// Does the homeserver require an email address to be verified.
+    pub fn requires_email(&self) -> bool {
+        self.requires_stage(EMAIL_IDENTITY)
+    }
+
+    /// Does the homeserver require its terms of service to be accepted.
+    pub fn requires_terms(&self) -> bool {
+        self.requires_stage(TERMS)
+    }
+}
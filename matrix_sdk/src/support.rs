@@ -0,0 +1,41 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Types for the support information a homeserver publishes.
+
+use serde::Deserialize;
+use url::Url;
+
+use matrix_sdk_common::identifiers::UserId;
+
+/// The content of the `/.well-known/matrix/support` document of a homeserver.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SupportInfo {
+    /// The people that can be contacted about the homeserver.
+    #[serde(default)]
+    pub contacts: Vec<SupportContact>,
+    /// A page with support information, e.g. a help desk.
+    pub support_page: Option<Url>,
+}
+
+/// A contact from the support information of a homeserver.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SupportContact {
+    /// The Matrix user id of the contact.
+    pub matrix_id: Option<UserId>,
+    /// The email address of the contact.
+    pub email_address: Option<String>,
+    /// The role of the contact, e.g. `admin` or `security`.
+    pub role: String,
+}
@@ -0,0 +1,96 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TLS configuration for the rustls backend.
+
+use std::{error::Error as StdError, sync::Arc};
+
+use rustls::{
+    Certificate, ClientConfig, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError,
+    WebPKIVerifier,
+};
+use webpki::{DNSName, DNSNameRef};
+
+/// Certificate verifier that checks the certificate of the server against a
+/// fixed hostname instead of the host of the URL that is connected to.
+struct ExpectedNameVerifier {
+    name: DNSName,
+    inner: WebPKIVerifier,
+}
+
+impl ServerCertVerifier for ExpectedNameVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _: DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        self.inner
+            .verify_server_cert(roots, presented_certs, self.name.as_ref(), ocsp_response)
+    }
+}
+
+/// Parse the given hostname into a name certificates can be checked against.
+pub(crate) fn parse_hostname(hostname: &str) -> Option<DNSName> {
+    DNSNameRef::try_from_ascii_str(hostname)
+        .ok()
+        .map(|n| n.to_owned())
+}
+
+/// Create a TLS config that accepts only certificates which are valid for the
+/// given name.
+pub(crate) fn config_for_hostname(name: DNSName) -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(ExpectedNameVerifier {
+            name,
+            inner: WebPKIVerifier::new(),
+        }));
+
+    config
+}
+
+/// Check if the given error was caused by a certificate that isn't valid for
+/// the expected hostname.
+pub(crate) fn is_hostname_mismatch(error: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(TLSError::WebPKIError(webpki::Error::CertNotValidForName)) =
+            error.downcast_ref::<TLSError>()
+        {
+            return true;
+        }
+
+        // The IO error the TLS error is wrapped in doesn't expose it as its
+        // source.
+        if let Some(inner) = error
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.get_ref())
+        {
+            if is_hostname_mismatch(inner) {
+                return true;
+            }
+        }
+
+        source = error.source();
+    }
+
+    false
+}
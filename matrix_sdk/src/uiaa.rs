@@ -0,0 +1,85 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for the User-Interactive Authentication API.
+
+use matrix_sdk_common::api::r0::uiaa::{AuthData, UiaaInfo};
+
+use crate::{Error, Result};
+
+/// Build the auth data that resumes an UIAA session after some of its stages
+/// were completed out-of-band, e.g. using SSO in a browser.
+///
+/// The homeserver already knows that the stages were completed, the returned
+/// auth data only echoes the session so the homeserver can continue with it.
+///
+/// # Arguments
+///
+/// * `info` - The UIAA info the homeserver responded with before the stages
+/// were completed.
+///
+/// * `completed_stages` - The types of the stages that were completed
+/// out-of-band, e.g. `m.login.sso`.
+///
+/// Fails with [`Error::UiaaSessionMissing`] if the homeserver didn't start a
+/// session that could be resumed and with [`Error::UiaaNoProgress`] if the
+/// completed stages aren't the next stages of any of the offered flows.
+///
+/// # Example
+///
+/// ```no_run
+/// # use futures::executor::block_on;
+/// # use matrix_sdk::{resume_uiaa_auth, Client};
+/// # use url::Url;
+/// # let homeserver = Url::parse("http://localhost:8080").unwrap();
+/// # let client = Client::new(homeserver).unwrap();
+/// # block_on(async {
+/// let devices = &["DEVICEID".into()];
+///
+/// if let Err(e) = client.delete_devices(devices, None).await {
+///     if let Some(info) = e.uiaa_response() {
+///         // Let the user complete the SSO stage in a browser, then resume.
+///         let auth = resume_uiaa_auth(info, &["m.login.sso"]).unwrap();
+///         client.delete_devices(devices, Some(auth)).await.unwrap();
+///     }
+/// }
+/// # });
+/// ```
+///
+/// [`Error::UiaaSessionMissing`]: enum.Error.html#variant.UiaaSessionMissing
+/// [`Error::UiaaNoProgress`]: enum.Error.html#variant.UiaaNoProgress
+pub fn resume_uiaa_auth<'a>(info: &'a UiaaInfo, completed_stages: &[&str]) -> Result<AuthData<'a>> {
+    let session = info.session.as_deref().ok_or(Error::UiaaSessionMissing)?;
+
+    let advances = |stages: &[String]| {
+        // The stages a flow contains are completed in order, the flow needs
+        // to start with the stages the homeserver already marked as done.
+        if !stages.starts_with(&info.completed) {
+            return false;
+        }
+
+        let next = &stages[info.completed.len()..];
+
+        next.len() >= completed_stages.len()
+            && next[..completed_stages.len()]
+                .iter()
+                .all(|s| completed_stages.contains(&s.as_str()))
+    };
+
+    if completed_stages.is_empty() || !info.flows.iter().any(|f| advances(&f.stages)) {
+        return Err(Error::UiaaNoProgress);
+    }
+
+    Ok(AuthData::FallbackAcknowledgement { session })
+}
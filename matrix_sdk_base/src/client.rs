@@ -22,6 +22,7 @@ use std::{
     sync::Arc,
 };
 
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use matrix_sdk_common::{
     api::r0 as api,
     deserialized_responses::{
@@ -37,7 +38,7 @@ use matrix_sdk_common::{
     },
     identifiers::{RoomId, UserId},
     instant::Instant,
-    locks::RwLock,
+    locks::{Mutex, RwLock},
     Raw,
 };
 #[cfg(feature = "encryption")]
@@ -45,7 +46,6 @@ use matrix_sdk_common::{
     api::r0::keys::claim_keys::Request as KeysClaimRequest,
     events::{room::encrypted::EncryptedEventContent, AnyMessageEventContent, AnySyncMessageEvent},
     identifiers::DeviceId,
-    locks::Mutex,
     uuid::Uuid,
 };
 #[cfg(feature = "encryption")]
@@ -61,7 +61,7 @@ use crate::{
     error::Result,
     event_emitter::Emitter,
     rooms::{RoomInfo, RoomType, StrippedRoomInfo},
-    session::Session,
+    session::{Session, SessionInfo},
     store::{ambiguity_map::AmbiguityCache, Result as StoreResult, StateChanges, Store},
     EventEmitter, RoomState,
 };
@@ -170,6 +170,8 @@ pub struct BaseClient {
     /// The current client session containing our user id, device id and access
     /// token.
     session: Arc<RwLock<Option<Session>>>,
+    /// The channels that get notified every time the session changes.
+    session_subscribers: Arc<Mutex<Vec<UnboundedSender<Option<SessionInfo>>>>>,
     /// The current sync token that should be used for the next sync call.
     pub(crate) sync_token: Arc<RwLock<Option<Token>>>,
     /// Database
@@ -320,6 +322,7 @@ impl BaseClient {
 
         Ok(BaseClient {
             session: store.session.clone(),
+            session_subscribers: Mutex::new(Vec::new()).into(),
             sync_token: store.sync_token.clone(),
             store,
             #[cfg(feature = "encryption")]
@@ -338,6 +341,36 @@ impl BaseClient {
         &self.session
     }
 
+    /// Subscribe to changes of the session.
+    ///
+    /// The returned receiver yields the current session state right away and
+    /// then a new item every time a session is restored or cleared. `None`
+    /// means that the client isn't logged in.
+    ///
+    /// The items don't contain the access token.
+    pub async fn session_changes(&self) -> UnboundedReceiver<Option<SessionInfo>> {
+        let mut subscribers = self.session_subscribers.lock().await;
+        let (sender, receiver) = unbounded();
+
+        let current = self.session.read().await.as_ref().map(SessionInfo::from);
+        let _ = sender.unbounded_send(current);
+        subscribers.push(sender);
+
+        receiver
+    }
+
+    /// Replace the current session and notify the session subscribers.
+    async fn set_session(&self, session: Option<Session>) {
+        // Holding the subscriber lock while we replace the session makes sure
+        // that new subscribers can't miss an update.
+        let mut subscribers = self.session_subscribers.lock().await;
+        let info = session.as_ref().map(SessionInfo::from);
+
+        *self.session.write().await = session;
+
+        subscribers.retain(|s| s.unbounded_send(info.clone()).is_ok());
+    }
+
     /// Get a reference to the store.
     pub fn store(&self) -> &Store {
         &self.store
@@ -364,6 +397,8 @@ impl BaseClient {
             access_token: response.access_token.clone(),
             device_id: response.device_id.clone(),
             user_id: response.user_id.clone(),
+            refresh_token: None,
+            expires_at: None,
         };
         self.restore_login(session).await
     }
@@ -416,11 +451,25 @@ impl BaseClient {
             }
         }
 
-        *self.session.write().await = Some(session);
+        self.set_session(Some(session)).await;
 
         Ok(())
     }
 
+    /// Forget the current session.
+    ///
+    /// This drops the access token and the E2EE state machine, afterwards the
+    /// client is in the same state as a client that was never logged in. Data
+    /// that was already persisted in the stores isn't removed.
+    pub async fn clear_session(&self) {
+        #[cfg(feature = "encryption")]
+        {
+            *self.olm.lock().await = None;
+        }
+
+        self.set_session(None).await;
+    }
+
     /// Get the current, if any, sync token of the client.
     /// This will be None if the client didn't sync at least once.
     pub async fn sync_token(&self) -> Option<String> {
@@ -1105,6 +1154,24 @@ impl BaseClient {
         }
     }
 
+    /// Record that the given to-device request is about to be sent out.
+    ///
+    /// The request stays pending until it's marked as sent using
+    /// [`mark_request_as_sent`](#method.mark_request_as_sent).
+    #[cfg(feature = "encryption")]
+    #[cfg_attr(feature = "docs", doc(cfg(encryption)))]
+    pub async fn record_to_device_request(&self, request: &ToDeviceRequest) -> Result<()> {
+        let olm = self.olm.lock().await;
+
+        match &*olm {
+            Some(o) => Ok(o
+                .record_to_device_request(request)
+                .await
+                .map_err(OlmError::Store)?),
+            None => Ok(()),
+        }
+    }
+
     /// Mark the request with the given request id as sent.
     ///
     /// # Arguments
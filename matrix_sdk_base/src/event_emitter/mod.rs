@@ -733,6 +733,8 @@ mod test {
             access_token: "1234".to_owned(),
             user_id: user_id!("@example:example.com"),
             device_id: "DEVICEID".into(),
+            refresh_token: None,
+            expires_at: None,
         };
         let client = BaseClient::new().unwrap();
         client.restore_login(session).await.unwrap();
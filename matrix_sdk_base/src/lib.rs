@@ -40,7 +40,7 @@
 
 pub use crate::{
     error::{Error, Result},
-    session::Session,
+    session::{Session, SessionInfo},
 };
 pub use matrix_sdk_common::*;
 
@@ -56,7 +56,7 @@ pub use rooms::{
     InvitedRoom, JoinedRoom, LeftRoom, Room, RoomInfo, RoomMember, RoomState, StrippedRoom,
     StrippedRoomInfo,
 };
-pub use store::{StateStore, Store, StoreError};
+pub use store::{StateStore, Store, StoreError, SyncState};
 
 pub use client::{BaseClient, BaseClientConfig, RoomStateType};
 
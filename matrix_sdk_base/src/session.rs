@@ -15,6 +15,8 @@
 
 //! User sessions.
 
+use std::time::SystemTime;
+
 use serde::{Deserialize, Serialize};
 
 use matrix_sdk_common::identifiers::{DeviceId, UserId};
@@ -29,4 +31,32 @@ pub struct Session {
     pub user_id: UserId,
     /// The ID of the client device
     pub device_id: Box<DeviceId>,
+    /// The token that can be used to get a new access token once the current
+    /// one expired, if the homeserver handed one out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_token: Option<String>,
+    /// When the access token expires, if the homeserver told us.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<SystemTime>,
+}
+
+/// The parts of a [`Session`] that identify the logged in user and device.
+///
+/// Unlike a `Session` this doesn't contain the access token, so it can be
+/// handed out freely, e.g. to update a "logged in as" display.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SessionInfo {
+    /// The user the session belongs to.
+    pub user_id: UserId,
+    /// The ID of the client device
+    pub device_id: Box<DeviceId>,
+}
+
+impl From<&Session> for SessionInfo {
+    fn from(session: &Session) -> Self {
+        Self {
+            user_id: session.user_id.clone(),
+            device_id: session.device_id.clone(),
+        }
+    }
 }
@@ -33,11 +33,12 @@ use tracing::info;
 
 use crate::deserialized_responses::{MemberEvent, StrippedMemberEvent};
 
-use super::{Result, RoomInfo, StateChanges, StateStore, StrippedRoomInfo};
+use super::{Result, RoomInfo, StateChanges, StateStore, StrippedRoomInfo, SyncState};
 
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
     sync_token: Arc<RwLock<Option<String>>>,
+    sync_filter_id: Arc<RwLock<Option<String>>>,
     filters: Arc<DashMap<String, String>>,
     account_data: Arc<DashMap<String, AnyBasicEvent>>,
     members: Arc<DashMap<RoomId, DashMap<UserId, MemberEvent>>>,
@@ -62,6 +63,7 @@ impl MemoryStore {
     pub fn new() -> Self {
         Self {
             sync_token: Arc::new(RwLock::new(None)),
+            sync_filter_id: Arc::new(RwLock::new(None)),
             filters: DashMap::new().into(),
             account_data: DashMap::new().into(),
             members: DashMap::new().into(),
@@ -94,6 +96,28 @@ impl MemoryStore {
         Ok(self.sync_token.read().unwrap().clone())
     }
 
+    async fn save_sync_state(&self, filter_id: &str, since: &str) -> Result<()> {
+        // The token lock is always taken first, holding both makes the update
+        // atomic for readers.
+        let mut sync_token = self.sync_token.write().unwrap();
+        let mut sync_filter_id = self.sync_filter_id.write().unwrap();
+
+        *sync_token = Some(since.to_owned());
+        *sync_filter_id = Some(filter_id.to_owned());
+
+        Ok(())
+    }
+
+    async fn load_sync_state(&self) -> Result<Option<SyncState>> {
+        let sync_token = self.sync_token.read().unwrap();
+        let sync_filter_id = self.sync_filter_id.read().unwrap();
+
+        Ok(sync_token
+            .clone()
+            .zip(sync_filter_id.clone())
+            .map(|(since, filter_id)| SyncState { filter_id, since }))
+    }
+
     async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
         let now = Instant::now();
 
@@ -316,6 +340,14 @@ impl StateStore for MemoryStore {
         self.get_sync_token().await
     }
 
+    async fn save_sync_state(&self, filter_id: &str, since: &str) -> Result<()> {
+        self.save_sync_state(filter_id, since).await
+    }
+
+    async fn load_sync_state(&self) -> Result<Option<SyncState>> {
+        self.load_sync_state().await
+    }
+
     async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
         self.get_presence_event(user_id).await
     }
@@ -78,6 +78,16 @@ pub enum StoreError {
 /// A `StateStore` specific result type.
 pub type Result<T> = std::result::Result<T, StoreError>;
 
+/// The state a sync loop needs to resume where it left off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncState {
+    /// The id of the filter the sync loop used.
+    pub filter_id: String,
+    /// The token of the last sync response that was processed, to be used as
+    /// the `since` token of the next sync.
+    pub since: String,
+}
+
 /// An abstract state store trait that can be used to implement different stores
 /// for the SDK.
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -105,6 +115,28 @@ pub trait StateStore: AsyncTraitDeps {
     /// Get the last stored sync token.
     async fn get_sync_token(&self) -> Result<Option<String>>;
 
+    /// Save the filter id and since token of a sync loop atomically.
+    ///
+    /// Either both values are stored or none of them, a crash while saving
+    /// will never leave a since token that belongs to another filter behind.
+    ///
+    /// The sync state should be saved after the crypto store persisted its
+    /// changes for the same sync response. A restart then never resumes
+    /// from a token whose to-device messages and key changes were lost, at
+    /// worst a sync response is processed a second time.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter_id` - The id of the filter the sync loop uses.
+    ///
+    /// * `since` - The token of the last processed sync response.
+    async fn save_sync_state(&self, filter_id: &str, since: &str) -> Result<()>;
+
+    /// Load the sync state that was stored with [`save_sync_state`].
+    ///
+    /// [`save_sync_state`]: #tymethod.save_sync_state
+    async fn load_sync_state(&self) -> Result<Option<SyncState>>;
+
     /// Get the stored presence event for the given user.
     ///
     /// # Arguments
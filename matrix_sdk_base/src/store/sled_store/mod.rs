@@ -41,7 +41,7 @@ use crate::{deserialized_responses::MemberEvent, rooms::StrippedRoomInfo};
 
 use self::store_key::{EncryptedEvent, StoreKey};
 
-use super::{Result, RoomInfo, StateChanges, StateStore, StoreError};
+use super::{Result, RoomInfo, StateChanges, StateStore, StoreError, SyncState};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum DatabaseType {
@@ -274,6 +274,40 @@ impl SledStore {
             .map(|t| String::from_utf8_lossy(&t).to_string()))
     }
 
+    pub async fn save_sync_state(&self, filter_id: &str, since: &str) -> Result<()> {
+        let ret: std::result::Result<(), TransactionError<SerializationError>> =
+            self.session.transaction(|session| {
+                session.insert("sync_filter_id".encode(), filter_id)?;
+                session.insert("sync_token".encode(), since)?;
+
+                Ok(())
+            });
+
+        ret?;
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub async fn load_sync_state(&self) -> Result<Option<SyncState>> {
+        let ret: std::result::Result<_, TransactionError<SerializationError>> =
+            self.session.transaction(|session| {
+                Ok((
+                    session.get("sync_filter_id".encode())?,
+                    session.get("sync_token".encode())?,
+                ))
+            });
+
+        Ok(match ret? {
+            (Some(filter_id), Some(since)) => Some(SyncState {
+                filter_id: String::from_utf8_lossy(&filter_id).to_string(),
+                since: String::from_utf8_lossy(&since).to_string(),
+            }),
+            _ => None,
+        })
+    }
+
     pub async fn save_changes(&self, changes: &StateChanges) -> Result<()> {
         let now = SystemTime::now();
 
@@ -583,6 +617,14 @@ impl StateStore for SledStore {
         self.get_sync_token().await
     }
 
+    async fn save_sync_state(&self, filter_id: &str, since: &str) -> Result<()> {
+        self.save_sync_state(filter_id, since).await
+    }
+
+    async fn load_sync_state(&self) -> Result<Option<SyncState>> {
+        self.load_sync_state().await
+    }
+
     async fn get_presence_event(&self, user_id: &UserId) -> Result<Option<PresenceEvent>> {
         self.get_presence_event(user_id).await
     }
@@ -703,4 +745,21 @@ mod test {
             .unwrap()
             .is_some());
     }
+
+    #[async_test]
+    async fn test_sync_state_saving() {
+        let store = SledStore::open().unwrap();
+        assert!(store.load_sync_state().await.unwrap().is_none());
+
+        store.save_sync_state("filter", "s1_token").await.unwrap();
+        store.save_sync_state("filter", "s2_token").await.unwrap();
+
+        let state = store.load_sync_state().await.unwrap().unwrap();
+        assert_eq!(state.filter_id, "filter");
+        assert_eq!(state.since, "s2_token");
+        assert_eq!(
+            store.get_sync_token().await.unwrap().as_deref(),
+            Some("s2_token")
+        );
+    }
 }
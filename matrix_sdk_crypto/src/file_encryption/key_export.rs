@@ -152,7 +152,7 @@ pub fn encrypt_key_export(
     Ok([HEADER.to_owned(), ciphertext, FOOTER.to_owned()].join("\n"))
 }
 
-fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
+pub(crate) fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> String {
     let mut salt = [0u8; SALT_SIZE];
     let mut iv = [0u8; IV_SIZE];
     let mut derived_keys = [0u8; KEY_SIZE * 2];
@@ -187,7 +187,7 @@ fn encrypt_helper(mut plaintext: &mut [u8], passphrase: &str, rounds: u32) -> St
     encode(payload)
 }
 
-fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExportError> {
+pub(crate) fn decrypt_helper(ciphertext: &str, passphrase: &str) -> Result<String, KeyExportError> {
     let decoded = decode(ciphertext)?;
 
     let mut decoded = Cursor::new(decoded);
@@ -235,11 +235,11 @@ mod test {
     use proptest::prelude::*;
     use std::io::Cursor;
 
-    use matrix_sdk_common::identifiers::room_id;
+    use matrix_sdk_common::identifiers::{room_id, user_id};
     use matrix_sdk_test::async_test;
 
     use super::{decode, decrypt_helper, decrypt_key_export, encrypt_helper, encrypt_key_export};
-    use crate::machine::test::get_prepared_machine;
+    use crate::{machine::test::get_prepared_machine, OlmMachine};
 
     const PASSPHRASE: &str = "1234";
 
@@ -319,6 +319,25 @@ mod test {
         assert_eq!(machine.import_keys(decrypted).await.unwrap(), (0, 1));
     }
 
+    #[async_test]
+    async fn test_session_export_import_round_trip() {
+        let (machine, _) = get_prepared_machine().await;
+        let room_id = room_id!("!test:localhost");
+
+        machine
+            .create_outbound_group_session_with_defaults(&room_id)
+            .await
+            .unwrap();
+        let export = machine.export_keys(|_| true).await.unwrap();
+        let encrypted = encrypt_key_export(&export, PASSPHRASE, 1).unwrap();
+
+        let other = OlmMachine::new(&user_id!("@bob:localhost"), "BOBDEVICE".into());
+        let decrypted = decrypt_key_export(Cursor::new(encrypted), PASSPHRASE).unwrap();
+
+        assert_eq!(other.import_keys(decrypted).await.unwrap(), (1, 1));
+        assert_eq!(other.export_keys(|_| true).await.unwrap(), export);
+    }
+
     #[test]
     fn test_real_decrypt() {
         let reader = Cursor::new(TEST_EXPORT);
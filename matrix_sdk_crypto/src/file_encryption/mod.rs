@@ -2,4 +2,6 @@ mod attachments;
 mod key_export;
 
 pub use attachments::{AttachmentDecryptor, AttachmentEncryptor, DecryptorError};
-pub use key_export::{decrypt_key_export, encrypt_key_export};
+#[cfg(feature = "sled_cryptostore")]
+pub(crate) use key_export::{decrypt_helper, encrypt_helper};
+pub use key_export::{decrypt_key_export, encrypt_key_export, KeyExportError};
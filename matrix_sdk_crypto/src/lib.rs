@@ -42,14 +42,14 @@ mod verification;
 pub use error::{MegolmError, OlmError};
 pub use file_encryption::{
     decrypt_key_export, encrypt_key_export, AttachmentDecryptor, AttachmentEncryptor,
-    DecryptorError,
+    DecryptorError, KeyExportError,
 };
 pub use identities::{
     Device, LocalTrust, OwnUserIdentity, ReadOnlyDevice, UserDevices, UserIdentities, UserIdentity,
 };
 pub use machine::OlmMachine;
-pub use olm::EncryptionSettings;
 pub(crate) use olm::ReadOnlyAccount;
+pub use olm::{EncryptionSettings, KeyUploadThresholds};
 pub use requests::{
     IncomingResponse, KeysQueryRequest, OutgoingRequest, OutgoingRequests,
     OutgoingVerificationRequest, RoomMessageRequest, ToDeviceRequest,
@@ -53,14 +53,14 @@ use crate::{
     key_request::KeyRequestMachine,
     olm::{
         Account, EncryptionSettings, ExportedRoomKey, GroupSessionKey, IdentityKeys,
-        InboundGroupSession, OlmDecryptionInfo, PrivateCrossSigningIdentity, ReadOnlyAccount,
-        SessionType,
+        InboundGroupSession, KeyUploadThresholds, OlmDecryptionInfo, PrivateCrossSigningIdentity,
+        ReadOnlyAccount, SessionType,
     },
     requests::{IncomingResponse, OutgoingRequest, UploadSigningKeysRequest},
     session_manager::{GroupSessionManager, SessionManager},
     store::{
-        Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, Result as StoreResult,
-        Store,
+        Changes, CryptoStore, DeviceChanges, IdentityChanges, MemoryStore, PendingToDevice,
+        Result as StoreResult, Store,
     },
     verification::{Sas, VerificationMachine, VerificationRequest},
     ToDeviceRequest,
@@ -221,18 +221,19 @@ impl OlmMachine {
         device_id: DeviceIdBox,
         store: Box<dyn CryptoStore>,
     ) -> StoreResult<Self> {
-        let account = match store.load_account().await? {
-            Some(a) => {
-                debug!("Restored account");
-                a
-            }
-            None => {
-                debug!("Creating a new account");
-                let account = ReadOnlyAccount::new(&user_id, &device_id);
-                store.save_account(account.clone()).await?;
-                account
-            }
-        };
+        let account = store
+            .get_or_create_account(Box::new({
+                let user_id = user_id.clone();
+                let device_id = device_id.clone();
+
+                move || {
+                    debug!("Creating a new account");
+                    ReadOnlyAccount::new(&user_id, &device_id)
+                }
+            }))
+            .await?;
+
+        account.check_key_counter(&*store).await?;
 
         let identity = match store.load_identity().await? {
             Some(i) => {
@@ -287,6 +288,25 @@ impl OlmMachine {
         self.account.identity_keys()
     }
 
+    /// Set the thresholds deciding when and how many one-time keys are
+    /// uploaded.
+    ///
+    /// The thresholds aren't persisted, they need to be set every time the
+    /// machine is created.
+    pub fn set_key_upload_thresholds(&self, thresholds: KeyUploadThresholds) {
+        self.account.set_key_upload_thresholds(thresholds)
+    }
+
+    /// Get the number of one-time keys that need to be uploaded.
+    ///
+    /// Returns 0 if the server holds enough one-time keys according to the
+    /// configured [`KeyUploadThresholds`].
+    ///
+    /// [`KeyUploadThresholds`]: struct.KeyUploadThresholds.html
+    pub async fn needs_key_upload(&self) -> u64 {
+        self.account.needs_key_upload().await
+    }
+
     /// Get the outgoing requests that need to be sent out.
     ///
     /// This returns a list of `OutGoingRequest`, those requests need to be sent
@@ -323,6 +343,31 @@ impl OlmMachine {
         requests
     }
 
+    /// Record that the given to-device request is about to be sent out.
+    ///
+    /// The request stays pending in the store until it's marked as sent using
+    /// [`mark_request_as_sent`](#method.mark_request_as_sent). Requests that
+    /// are still pending after a restart might not have reached their
+    /// recipients, they can be found using
+    /// [`pending_to_device_requests`](#method.pending_to_device_requests).
+    pub async fn record_to_device_request(&self, request: &ToDeviceRequest) -> StoreResult<()> {
+        let recipients = request
+            .messages
+            .iter()
+            .map(|(user_id, devices)| (user_id.clone(), devices.keys().cloned().collect()))
+            .collect();
+
+        self.store
+            .record_to_device_sent(&request.txn_id_string(), recipients)
+            .await
+    }
+
+    /// Get the to-device requests that were recorded as being sent out but
+    /// were never marked as sent.
+    pub async fn pending_to_device_requests(&self) -> StoreResult<Vec<PendingToDevice>> {
+        self.store.pending_to_device().await
+    }
+
     /// Mark the request with the given request id as sent.
     ///
     /// # Arguments
@@ -738,6 +783,9 @@ impl OlmMachine {
 
     /// Mark an outgoing to-device requests as sent.
     async fn mark_to_device_request_as_sent(&self, request_id: &Uuid) -> StoreResult<()> {
+        self.store
+            .mark_to_device_delivered(&request_id.to_string())
+            .await?;
         self.verification_machine.mark_request_as_sent(request_id);
         self.key_request_machine
             .mark_outgoing_request_as_sent(request_id)
@@ -1147,6 +1195,15 @@ impl OlmMachine {
 
         Ok(exported)
     }
+
+    /// Remove all the data of the crypto store of this machine, e.g. after
+    /// the user logged out.
+    ///
+    /// The machine can't be used anymore afterwards, a new one needs to be
+    /// created for the next login.
+    pub async fn clear_store(&self) -> StoreResult<()> {
+        self.store.clear().await
+    }
 }
 
 #[cfg(test)]
@@ -1166,8 +1223,9 @@ pub(crate) mod test {
     use crate::{
         machine::OlmMachine,
         olm::Utility,
+        store::CryptoStoreError,
         verification::test::{outgoing_request_to_event, request_to_event},
-        EncryptionSettings, ReadOnlyDevice, ToDeviceRequest,
+        EncryptionSettings, KeyUploadThresholds, ReadOnlyDevice, ToDeviceRequest,
     };
 
     use matrix_sdk_common::{
@@ -1399,6 +1457,38 @@ pub(crate) mod test {
         assert!(machine.account.generate_one_time_keys().await.is_err());
     }
 
+    #[tokio::test]
+    async fn key_upload_thresholds() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+        machine.set_key_upload_thresholds(KeyUploadThresholds {
+            minimum: Some(10),
+            target: Some(20),
+        });
+
+        let mut response = keys_upload_response();
+        response
+            .one_time_key_counts
+            .insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(15));
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert_eq!(machine.needs_key_upload().await, 0);
+        assert!(!machine.should_upload_keys().await);
+
+        response
+            .one_time_key_counts
+            .insert(DeviceKeyAlgorithm::SignedCurve25519, uint!(5));
+        machine
+            .receive_keys_upload_response(&response)
+            .await
+            .unwrap();
+        assert_eq!(machine.needs_key_upload().await, 15);
+
+        let request = machine.keys_for_upload().await.unwrap();
+        assert_eq!(request.one_time_keys.unwrap().len(), 15);
+    }
+
     #[tokio::test]
     async fn test_device_key_signing() {
         let machine = OlmMachine::new(&user_id(), &alice_device_id());
@@ -1525,6 +1615,55 @@ pub(crate) mod test {
         assert!(ret.is_none());
     }
 
+    #[tokio::test]
+    async fn one_time_key_counter_regression() {
+        let machine = OlmMachine::new(&user_id(), &alice_device_id());
+
+        for _ in 0..2 {
+            machine.account.inner.update_uploaded_key_count(0);
+
+            let request = machine
+                .keys_for_upload()
+                .await
+                .expect("Can't prepare the key upload");
+
+            let mut response = keys_upload_response();
+            response.one_time_key_counts.insert(
+                DeviceKeyAlgorithm::SignedCurve25519,
+                (request.one_time_keys.unwrap().len() as u64)
+                    .try_into()
+                    .unwrap(),
+            );
+
+            machine
+                .receive_keys_upload_response(&response)
+                .await
+                .unwrap();
+        }
+
+        // Pretend the stored account lost track of the published keys, e.g.
+        // because storing it after an upload failed.
+        machine
+            .store
+            .save_value(
+                "published_one_time_key_counter".to_owned(),
+                u32::MAX.to_string(),
+            )
+            .await
+            .unwrap();
+        machine.account.inner.update_uploaded_key_count(0);
+
+        assert!(machine.keys_for_upload().await.is_none());
+        assert!(matches!(
+            machine
+                .account
+                .inner
+                .check_key_counter(&*machine.store)
+                .await,
+            Err(CryptoStoreError::KeyCounterRegression { .. })
+        ));
+    }
+
     #[tokio::test]
     async fn test_keys_query() {
         let (machine, _) = get_prepared_machine().await;
@@ -1803,6 +1942,51 @@ pub(crate) mod test {
         assert_eq!(ed25519_key, machine.identity_keys().ed25519());
     }
 
+    #[tokio::test]
+    #[cfg(feature = "sled_cryptostore")]
+    async fn one_time_keys_survive_interrupted_upload() {
+        use tempfile::tempdir;
+
+        let tmpdir = tempdir().unwrap();
+
+        let machine = OlmMachine::new_with_default_store(
+            &user_id(),
+            &alice_device_id(),
+            tmpdir.as_ref(),
+            Some("test"),
+        )
+        .await
+        .unwrap();
+
+        let request = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare initial key upload");
+        let one_time_keys = request.one_time_keys.unwrap();
+
+        // Drop the machine before the upload response arrives.
+        drop(machine);
+
+        let machine = OlmMachine::new_with_default_store(
+            &user_id(),
+            &alice_device_id(),
+            tmpdir.as_ref(),
+            Some("test"),
+        )
+        .await
+        .unwrap();
+
+        let request = machine
+            .keys_for_upload()
+            .await
+            .expect("Can't prepare the repeated key upload");
+        let repeated_keys = request.one_time_keys.unwrap();
+
+        assert!(one_time_keys
+            .keys()
+            .all(|key_id| repeated_keys.contains_key(key_id)));
+    }
+
     #[tokio::test]
     async fn interactive_verification() {
         let (alice, bob) = get_machine_pair_with_setup_sessions().await;
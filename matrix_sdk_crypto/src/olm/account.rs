@@ -23,10 +23,10 @@ use std::{
     ops::Deref,
     sync::{
         atomic::{AtomicBool, AtomicI64, Ordering},
-        Arc,
+        Arc, Mutex as SyncMutex,
     },
 };
-use tracing::{debug, trace, warn};
+use tracing::{debug, error, trace, warn};
 
 #[cfg(test)]
 use matrix_sdk_common::events::EventType;
@@ -55,11 +55,15 @@ use crate::{
     error::{EventError, OlmResult, SessionCreationError},
     identities::ReadOnlyDevice,
     requests::UploadSigningKeysRequest,
-    store::{Changes, Store},
-    utilities::encode,
+    store::{Changes, CryptoStore, CryptoStoreError, Result as StoreResult, Store},
+    utilities::{decode, encode},
     OlmError,
 };
 
+/// The key under which the counter of the newest published one-time key is
+/// stored.
+const PUBLISHED_KEY_COUNTER: &str = "published_one_time_key_counter";
+
 use super::{
     EncryptionSettings, InboundGroupSession, OutboundGroupSession, PrivateCrossSigningIdentity,
     Session,
@@ -197,6 +201,41 @@ impl Account {
         self.inner.update_uploaded_key_count(count);
     }
 
+    /// Get a tuple of device and one-time keys that need to be uploaded.
+    ///
+    /// Freshly generated one-time keys are persisted before they are handed
+    /// out, so they survive a crash while the upload is in flight. The keys
+    /// stay unpublished until the upload response is received, if the upload
+    /// fails they will be part of the next upload.
+    ///
+    /// Returns None if no keys need to be uploaded or if the account couldn't
+    /// be persisted.
+    pub async fn keys_for_upload(
+        &self,
+    ) -> Option<(
+        Option<DeviceKeys>,
+        Option<BTreeMap<DeviceKeyId, OneTimeKey>>,
+    )> {
+        let keys = self.inner.keys_for_upload().await?;
+
+        if keys.1.is_some() {
+            if let Err(e) = self.inner.check_key_counter(&*self.store).await {
+                error!("Refusing to upload one-time keys {:?}", e);
+                return None;
+            }
+
+            if let Err(e) = self.store.save_account(self.inner.clone()).await {
+                warn!(
+                    "Failed to store the account before uploading one-time keys {:?}",
+                    e
+                );
+                return None;
+            }
+        }
+
+        Some(keys)
+    }
+
     pub async fn receive_keys_upload_response(
         &self,
         response: &upload_keys::Response,
@@ -217,9 +256,20 @@ impl Account {
             count
         );
         self.inner.update_uploaded_key_count(count);
+
+        let published = self.inner.newest_one_time_key_counter().await;
+
         self.inner.mark_keys_as_published().await;
         self.store.save_account(self.inner.clone()).await?;
 
+        // Only remember the published keys once the account that knows they
+        // are published was stored.
+        if let Some(published) = published {
+            self.store
+                .save_value(PUBLISHED_KEY_COUNTER.to_owned(), published.to_string())
+                .await?;
+        }
+
         Ok(())
     }
 
@@ -435,6 +485,19 @@ impl Account {
     }
 }
 
+/// Thresholds deciding when and how many one-time keys are uploaded.
+///
+/// Thresholds that aren't set default to half of the number of one-time keys
+/// the account can hold, meaning the server is topped up to that count as
+/// soon as it holds fewer keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyUploadThresholds {
+    /// Upload new one-time keys once the server holds fewer than this many.
+    pub minimum: Option<u64>,
+    /// The number of one-time keys the server should hold after an upload.
+    pub target: Option<u64>,
+}
+
 /// Account holding identity keys for which sessions can be created.
 ///
 /// An account is the central identity for encrypted communication between two
@@ -451,6 +514,7 @@ pub struct ReadOnlyAccount {
     /// needs to set this for us, depending on the count we will suggest the
     /// client to upload new keys.
     uploaded_signed_key_count: Arc<AtomicI64>,
+    key_upload_thresholds: Arc<SyncMutex<KeyUploadThresholds>>,
 }
 
 /// A typed representation of a base64 encoded string containing the account
@@ -518,6 +582,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::new(false)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(0)),
+            key_upload_thresholds: Default::default(),
         }
     }
 
@@ -572,6 +637,48 @@ impl ReadOnlyAccount {
         self.inner.lock().await.parsed_one_time_keys()
     }
 
+    /// Get the counters of the unpublished one-time keys.
+    ///
+    /// libolm uses a big-endian counter, encoded as base64, as the id of a
+    /// one-time key.
+    async fn one_time_key_counters(&self) -> Vec<u32> {
+        self.one_time_keys()
+            .await
+            .curve25519()
+            .keys()
+            .filter_map(|id| decode(id).ok()?.as_slice().try_into().ok())
+            .map(u32::from_be_bytes)
+            .collect()
+    }
+
+    /// Get the counter of the newest unpublished one-time key.
+    async fn newest_one_time_key_counter(&self) -> Option<u32> {
+        self.one_time_key_counters().await.into_iter().max()
+    }
+
+    /// Check that none of the unpublished one-time keys has an id that was
+    /// already published.
+    ///
+    /// The account needs to be stored every time one-time keys are generated
+    /// and published, if one of those writes gets lost the account would
+    /// hand out keys with the ids of keys that are already on the server.
+    pub(crate) async fn check_key_counter(&self, store: &dyn CryptoStore) -> StoreResult<()> {
+        let published: u32 = match store.get_value(PUBLISHED_KEY_COUNTER).await? {
+            Some(p) => p.parse().unwrap_or_default(),
+            None => return Ok(()),
+        };
+
+        match self.one_time_key_counters().await.into_iter().min() {
+            Some(generated) if generated <= published => {
+                Err(CryptoStoreError::KeyCounterRegression {
+                    published,
+                    generated,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
     /// Generate count number of one-time keys.
     pub(crate) async fn generate_one_time_keys_helper(&self, count: usize) {
         self.inner.lock().await.generate_one_time_keys(count);
@@ -582,44 +689,63 @@ impl ReadOnlyAccount {
         self.inner.lock().await.max_number_of_one_time_keys()
     }
 
-    /// Get a tuple of device and one-time keys that need to be uploaded.
+    /// Set the thresholds deciding when and how many one-time keys are
+    /// uploaded.
+    pub(crate) fn set_key_upload_thresholds(&self, thresholds: KeyUploadThresholds) {
+        *self.key_upload_thresholds.lock().unwrap() = thresholds;
+    }
+
+    /// Get the number of one-time keys that need to be uploaded.
+    ///
+    /// The count is based on the number of keys the server reported to hold,
+    /// the configured [`KeyUploadThresholds`] and the number of keys the
+    /// account can hold. Returns 0 if the server holds enough keys.
     ///
-    /// Returns an empty error if no keys need to be uploaded.
+    /// [`KeyUploadThresholds`]: struct.KeyUploadThresholds.html
+    pub async fn needs_key_upload(&self) -> u64 {
+        let thresholds = *self.key_upload_thresholds.lock().unwrap();
+        let max_keys = self.max_one_time_keys().await as u64;
+
+        let target = thresholds.target.unwrap_or(max_keys / 2).min(max_keys);
+        let minimum = thresholds.minimum.unwrap_or(target).min(target);
+        let count = self.uploaded_key_count().max(0) as u64;
+
+        if count < minimum {
+            target - count
+        } else {
+            0
+        }
+    }
+
+    /// Generate the one-time keys that need to be uploaded.
+    ///
+    /// Keys that were generated for a previous upload but weren't published
+    /// yet are counted as well, only the missing keys are generated.
+    ///
+    /// Returns the number of keys that need to be uploaded or an empty error
+    /// if no keys need to be uploaded.
     pub(crate) async fn generate_one_time_keys(&self) -> Result<u64, ()> {
-        let count = self.uploaded_key_count() as u64;
-        let max_keys = self.max_one_time_keys().await;
-        let max_on_server = (max_keys as u64) / 2;
+        let key_count = self.needs_key_upload().await;
 
-        if count >= (max_on_server) {
+        if key_count == 0 {
             return Err(());
         }
 
-        let key_count = (max_on_server) - count;
-        let key_count: usize = key_count.try_into().unwrap_or(max_keys);
+        let unpublished = self.one_time_key_counters().await.len() as u64;
+        let missing = key_count.saturating_sub(unpublished);
+
+        if missing > 0 {
+            let max_keys = self.max_one_time_keys().await;
+            let missing: usize = missing.try_into().unwrap_or(max_keys);
+            self.generate_one_time_keys_helper(missing).await;
+        }
 
-        self.generate_one_time_keys_helper(key_count).await;
-        Ok(key_count as u64)
+        Ok(key_count)
     }
 
     /// Should account or one-time keys be uploaded to the server.
     pub(crate) async fn should_upload_keys(&self) -> bool {
-        if !self.shared() {
-            return true;
-        }
-
-        let count = self.uploaded_key_count() as u64;
-
-        // If we have a known key count, check that we have more than
-        // max_one_time_Keys() / 2, otherwise tell the client to upload more.
-        let max_keys = self.max_one_time_keys().await as u64;
-        // If there are more keys already uploaded than max_key / 2
-        // bail out returning false, this also avoids overflow.
-        if count > (max_keys / 2) {
-            return false;
-        }
-
-        let key_count = (max_keys / 2) - count;
-        key_count > 0
+        !self.shared() || self.needs_key_upload().await > 0
     }
 
     /// Get a tuple of device and one-time keys that need to be uploaded.
@@ -698,6 +824,7 @@ impl ReadOnlyAccount {
             identity_keys: Arc::new(identity_keys),
             shared: Arc::new(AtomicBool::from(pickle.shared)),
             uploaded_signed_key_count: Arc::new(AtomicI64::new(pickle.uploaded_signed_key_count)),
+            key_upload_thresholds: Default::default(),
         })
     }
 
@@ -292,14 +292,20 @@ impl OutboundGroupSession {
     /// A session will expire after some time or if enough messages have been
     /// encrypted using it.
     pub fn expired(&self) -> bool {
+        self.expired_with(&self.settings)
+    }
+
+    /// Check if the session has expired according to the given settings
+    /// instead of the settings the session was created with.
+    pub(crate) fn expired_with(&self, settings: &EncryptionSettings) -> bool {
         let count = self.message_count.load(Ordering::SeqCst);
 
-        count >= self.settings.rotation_period_msgs
+        count >= settings.rotation_period_msgs
             || self.creation_time.elapsed()
                 // Since the encryption settings are provided by users and not
                 // checked someone could set a really low rotation period so
                 // clamp it to an hour.
-                >= max(self.settings.rotation_period, Duration::from_secs(3600))
+                >= max(settings.rotation_period, Duration::from_secs(3600))
     }
 
     /// Has the session been invalidated.
@@ -24,7 +24,9 @@ mod signing;
 mod utility;
 
 pub(crate) use account::{Account, OlmDecryptionInfo, SessionType};
-pub use account::{AccountPickle, OlmMessageHash, PickledAccount, ReadOnlyAccount};
+pub use account::{
+    AccountPickle, KeyUploadThresholds, OlmMessageHash, PickledAccount, ReadOnlyAccount,
+};
 pub use group_sessions::{
     EncryptionSettings, ExportedRoomKey, InboundGroupSession, InboundGroupSessionPickle,
     OutboundGroupSession, PickledInboundGroupSession, PickledOutboundGroupSession,
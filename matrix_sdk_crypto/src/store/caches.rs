@@ -75,6 +75,11 @@ impl SessionStore {
         self.entries
             .insert(sender_key.to_owned(), Arc::new(Mutex::new(sessions)));
     }
+
+    /// Remove all the sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 #[derive(Debug, Default, Clone)]
@@ -106,6 +111,26 @@ impl GroupSessionStore {
             .is_none()
     }
 
+    /// Remove all the group sessions of the given room.
+    ///
+    /// Returns the removed sessions.
+    pub fn remove_room(&self, room_id: &RoomId) -> Vec<InboundGroupSession> {
+        self.entries
+            .remove(room_id)
+            .map(|(_, sessions)| {
+                sessions
+                    .into_iter()
+                    .flat_map(|(_, s)| s.into_iter().map(|(_, s)| s))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remove all the group sessions from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
+
     /// Get all the group sessions the store knows about.
     pub fn get_all(&self) -> Vec<InboundGroupSession> {
         self.entries
@@ -191,6 +216,11 @@ impl DeviceStore {
             .map(|i| (i.key().to_owned(), i.value().clone()))
             .collect()
     }
+
+    /// Remove all the devices from the store.
+    pub fn clear(&self) {
+        self.entries.clear();
+    }
 }
 
 #[cfg(test)]
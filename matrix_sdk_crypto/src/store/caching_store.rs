@@ -0,0 +1,434 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{Arc, Mutex as SyncMutex},
+};
+
+use lru::LruCache;
+use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
+    async_trait,
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
+    locks::Mutex,
+};
+
+use super::{
+    Changes, CryptoStore, InboundGroupSession, MessageIndexStatus, PendingToDevice,
+    ReadOnlyAccount, Result, RoomPurgeCounts, Session, StoreCapabilities,
+};
+use crate::{
+    identities::{ReadOnlyDevice, UserIdentities},
+    olm::{EncryptionSettings, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity},
+};
+
+type GroupSessionKey = (RoomId, String, String);
+
+struct Cache {
+    /// Bumped on every write, a read only fills the cache if no write happened
+    /// while it was reading from the underlying store.
+    generation: u64,
+    account: Option<ReadOnlyAccount>,
+    inbound_group_sessions: LruCache<GroupSessionKey, InboundGroupSession>,
+}
+
+/// A `CryptoStore` that caches the account and recently read inbound group
+/// sessions of another `CryptoStore` in memory.
+///
+/// Writes go straight through to the wrapped store and invalidate the cached
+/// entries they touch.
+///
+/// # Example
+///
+/// ```
+/// use matrix_sdk_crypto::store::{CachingStore, MemoryStore};
+///
+/// let store = CachingStore::new(MemoryStore::new(), 500);
+/// ```
+#[derive(Clone)]
+pub struct CachingStore<S: CryptoStore> {
+    inner: S,
+    cache: Arc<SyncMutex<Cache>>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<S: CryptoStore> fmt::Debug for CachingStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachingStore")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<S: CryptoStore> CachingStore<S> {
+    /// Wrap the given store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store that should be cached.
+    ///
+    /// * `capacity` - The maximal number of inbound group sessions that are
+    /// kept in memory.
+    pub fn new(store: S, capacity: usize) -> Self {
+        Self {
+            inner: store,
+            cache: Arc::new(SyncMutex::new(Cache {
+                generation: 0,
+                account: None,
+                inbound_group_sessions: LruCache::new(capacity),
+            })),
+        }
+    }
+
+    /// Get the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn generation(&self) -> u64 {
+        self.cache.lock().unwrap().generation
+    }
+
+    /// Update the cache after a write, `update` gets access to the cache
+    /// while no reader can fill it.
+    fn invalidate(&self, update: impl FnOnce(&mut Cache)) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.generation += 1;
+        update(&mut cache);
+    }
+
+    /// Fill the cache after a read if no write happened since `generation`
+    /// was fetched.
+    fn fill(&self, generation: u64, update: impl FnOnce(&mut Cache)) {
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.generation == generation {
+            update(&mut cache);
+        }
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<S: CryptoStore> CryptoStore for CachingStore<S> {
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        let generation = {
+            let cache = self.cache.lock().unwrap();
+
+            if let Some(account) = &cache.account {
+                return Ok(Some(account.clone()));
+            }
+
+            cache.generation
+        };
+
+        let account = self.inner.load_account().await?;
+
+        if let Some(account) = &account {
+            self.fill(generation, |c| c.account = Some(account.clone()));
+        }
+
+        Ok(account)
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        let result = self.inner.save_account(account).await;
+        self.invalidate(|c| c.account = None);
+
+        result
+    }
+
+    async fn get_or_create_account(
+        &self,
+        factory: Box<dyn FnOnce() -> ReadOnlyAccount + Send>,
+    ) -> Result<ReadOnlyAccount> {
+        let generation = self.generation();
+        let account = self.inner.get_or_create_account(factory).await?;
+        self.fill(generation, |c| c.account = Some(account.clone()));
+
+        Ok(account)
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        self.inner.load_identity().await
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let account_changed = changes.account.is_some();
+        let sessions: Vec<GroupSessionKey> = changes
+            .inbound_group_sessions
+            .iter()
+            .map(|s| {
+                (
+                    s.room_id().clone(),
+                    s.sender_key().to_owned(),
+                    s.session_id().to_owned(),
+                )
+            })
+            .collect();
+
+        let result = self.inner.save_changes(changes).await;
+
+        self.invalidate(|c| {
+            if account_changed {
+                c.account = None;
+            }
+
+            for key in &sessions {
+                c.inbound_group_sessions.pop(key);
+            }
+        });
+
+        result
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        self.inner.get_sessions(sender_key).await
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        let key = (
+            room_id.clone(),
+            sender_key.to_owned(),
+            session_id.to_owned(),
+        );
+
+        let generation = {
+            let mut cache = self.cache.lock().unwrap();
+
+            if let Some(session) = cache.inbound_group_sessions.get(&key) {
+                return Ok(Some(session.clone()));
+            }
+
+            cache.generation
+        };
+
+        let session = self
+            .inner
+            .get_inbound_group_session(room_id, sender_key, session_id)
+            .await?;
+
+        if let Some(session) = &session {
+            self.fill(generation, |c| {
+                c.inbound_group_sessions.put(key, session.clone());
+            });
+        }
+
+        Ok(session)
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.inner.get_inbound_group_sessions().await
+    }
+
+    async fn get_outbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.inner.get_outbound_group_sessions(room_id).await
+    }
+
+    async fn needs_rotation(
+        &self,
+        room_id: &RoomId,
+        settings: &EncryptionSettings,
+    ) -> Result<bool> {
+        self.inner.needs_rotation(room_id, settings).await
+    }
+
+    fn is_user_tracked(&self, user_id: &UserId) -> bool {
+        self.inner.is_user_tracked(user_id)
+    }
+
+    fn has_users_for_key_query(&self) -> bool {
+        self.inner.has_users_for_key_query()
+    }
+
+    fn users_for_key_query(&self) -> HashSet<UserId> {
+        self.inner.users_for_key_query()
+    }
+
+    async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        self.inner.update_tracked_user(user, dirty).await
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        self.inner.get_device(user_id, device_id).await
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<DeviceIdBox, ReadOnlyDevice>> {
+        self.inner.get_user_devices(user_id).await
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentities>> {
+        self.inner.get_user_identity(user_id).await
+    }
+
+    async fn save_value(&self, key: String, value: String) -> Result<()> {
+        self.inner.save_value(key, value).await
+    }
+
+    async fn remove_value(&self, key: &str) -> Result<()> {
+        self.inner.remove_value(key).await
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get_value(key).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        self.inner.is_message_known(message_hash).await
+    }
+
+    async fn check_and_update_message_index(
+        &self,
+        session_id: &str,
+        event_id: &EventId,
+        index: u32,
+    ) -> Result<MessageIndexStatus> {
+        self.inner
+            .check_and_update_message_index(session_id, event_id, index)
+            .await
+    }
+
+    async fn record_to_device_sent(
+        &self,
+        txn_id: &str,
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+    ) -> Result<()> {
+        self.inner.record_to_device_sent(txn_id, recipients).await
+    }
+
+    async fn mark_to_device_delivered(&self, txn_id: &str) -> Result<()> {
+        self.inner.mark_to_device_delivered(txn_id).await
+    }
+
+    async fn pending_to_device(&self) -> Result<Vec<PendingToDevice>> {
+        self.inner.pending_to_device().await
+    }
+
+    async fn purge_room(&self, room_id: &RoomId, keep_history: bool) -> Result<RoomPurgeCounts> {
+        let result = self.inner.purge_room(room_id, keep_history).await;
+
+        if !keep_history {
+            self.invalidate(|c| {
+                let keys: Vec<GroupSessionKey> = c
+                    .inbound_group_sessions
+                    .iter()
+                    .filter(|(key, _)| &key.0 == room_id)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+
+                for key in &keys {
+                    c.inbound_group_sessions.pop(key);
+                }
+            });
+        }
+
+        result
+    }
+
+    async fn clear(&self) -> Result<()> {
+        let result = self.inner.clear().await;
+
+        self.invalidate(|c| {
+            c.account = None;
+            c.inbound_group_sessions.clear();
+        });
+
+        result
+    }
+
+    fn capabilities(&self) -> StoreCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use matrix_sdk_common::identifiers::room_id;
+
+    use crate::{
+        olm::{test::get_account_and_session, InboundGroupSession},
+        store::{CachingStore, Changes, CryptoStore, MemoryStore},
+    };
+
+    #[tokio::test]
+    async fn cache_invalidation_on_write() {
+        let (account, _) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+        let store = CachingStore::new(MemoryStore::new(), 10);
+
+        let (outbound, _) = account
+            .create_group_session_pair_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let first = InboundGroupSession::new(
+            "test_key",
+            "test_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        let mut changes = Changes::default();
+        changes.inbound_group_sessions.push(first);
+        store.save_changes(changes).await.unwrap();
+
+        let loaded = store
+            .get_inbound_group_session(&room_id, "test_key", outbound.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.first_known_index(), 0);
+
+        // Advance the ratchet so the new inbound session has a different first
+        // known index but the same session id.
+        outbound.encrypt_helper("It's a secret".to_owned()).await;
+
+        let second = InboundGroupSession::new(
+            "test_key",
+            "test_key",
+            &room_id,
+            outbound.session_key().await,
+        )
+        .unwrap();
+
+        let mut changes = Changes::default();
+        changes.inbound_group_sessions.push(second);
+        store.save_changes(changes).await.unwrap();
+
+        let loaded = store
+            .get_inbound_group_session(&room_id, "test_key", outbound.session_id())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.first_known_index(), 1);
+    }
+}
@@ -13,20 +13,22 @@
 // limitations under the License.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     sync::Arc,
 };
 
-use dashmap::{DashMap, DashSet};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
 use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
     async_trait,
-    identifiers::{DeviceId, DeviceIdBox, RoomId, UserId},
-    locks::Mutex,
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
+    locks::{Mutex, RwLock},
 };
 
 use super::{
     caches::{DeviceStore, GroupSessionStore, SessionStore},
-    Changes, CryptoStore, InboundGroupSession, ReadOnlyAccount, Result, Session,
+    Changes, CryptoStore, InboundGroupSession, MessageIndexStatus, PendingToDevice,
+    ReadOnlyAccount, Result, RoomPurgeCounts, Session, StoreCapabilities,
 };
 use crate::{
     identities::{ReadOnlyDevice, UserIdentities},
@@ -36,27 +38,37 @@ use crate::{
 /// An in-memory only store that will forget all the E2EE key once it's dropped.
 #[derive(Debug, Clone)]
 pub struct MemoryStore {
+    account: Arc<RwLock<Option<ReadOnlyAccount>>>,
+    private_identity: Arc<RwLock<Option<PrivateCrossSigningIdentity>>>,
     sessions: SessionStore,
     inbound_group_sessions: GroupSessionStore,
+    outbound_group_sessions: Arc<RwLock<HashMap<RoomId, OutboundGroupSession>>>,
     tracked_users: Arc<DashSet<UserId>>,
     users_for_key_query: Arc<DashSet<UserId>>,
     olm_hashes: Arc<DashMap<String, DashSet<String>>>,
     devices: DeviceStore,
     identities: Arc<DashMap<UserId, UserIdentities>>,
     values: Arc<DashMap<String, String>>,
+    message_indices: Arc<DashMap<(String, u32), EventId>>,
+    pending_to_device: Arc<DashMap<String, PendingToDevice>>,
 }
 
 impl Default for MemoryStore {
     fn default() -> Self {
         MemoryStore {
+            account: Arc::new(RwLock::new(None)),
+            private_identity: Arc::new(RwLock::new(None)),
             sessions: SessionStore::new(),
             inbound_group_sessions: GroupSessionStore::new(),
+            outbound_group_sessions: Arc::new(RwLock::new(HashMap::new())),
             tracked_users: Arc::new(DashSet::new()),
             users_for_key_query: Arc::new(DashSet::new()),
             olm_hashes: Arc::new(DashMap::new()),
             devices: DeviceStore::new(),
             identities: Arc::new(DashMap::new()),
             values: Arc::new(DashMap::new()),
+            message_indices: Arc::new(DashMap::new()),
+            pending_to_device: Arc::new(DashMap::new()),
         }
     }
 }
@@ -96,14 +108,40 @@ impl MemoryStore {
 #[cfg_attr(not(target_arch = "wasm32"), async_trait)]
 impl CryptoStore for MemoryStore {
     async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
-        Ok(None)
+        Ok(self.account.read().await.clone())
     }
 
-    async fn save_account(&self, _: ReadOnlyAccount) -> Result<()> {
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        *self.account.write().await = Some(account);
         Ok(())
     }
 
+    async fn get_or_create_account(
+        &self,
+        factory: Box<dyn FnOnce() -> ReadOnlyAccount + Send>,
+    ) -> Result<ReadOnlyAccount> {
+        let mut account = self.account.write().await;
+
+        Ok(account.get_or_insert_with(factory).clone())
+    }
+
     async fn save_changes(&self, mut changes: Changes) -> Result<()> {
+        if let Some(account) = changes.account {
+            *self.account.write().await = Some(account);
+        }
+
+        if let Some(identity) = changes.private_identity {
+            *self.private_identity.write().await = Some(identity);
+        }
+
+        {
+            let mut outbound_group_sessions = self.outbound_group_sessions.write().await;
+
+            for session in changes.outbound_group_sessions {
+                outbound_group_sessions.insert(session.room_id().to_owned(), session);
+            }
+        }
+
         self.save_sessions(changes.sessions).await;
         self.save_inbound_group_sessions(changes.inbound_group_sessions)
             .await;
@@ -222,7 +260,7 @@ impl CryptoStore for MemoryStore {
     }
 
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
-        Ok(None)
+        Ok(self.private_identity.read().await.clone())
     }
 
     async fn is_message_known(&self, message_hash: &crate::olm::OlmMessageHash) -> Result<bool> {
@@ -235,9 +273,123 @@ impl CryptoStore for MemoryStore {
 
     async fn get_outbound_group_sessions(
         &self,
-        _: &RoomId,
+        room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>> {
-        Ok(None)
+        Ok(self
+            .outbound_group_sessions
+            .read()
+            .await
+            .get(room_id)
+            .cloned())
+    }
+
+    async fn check_and_update_message_index(
+        &self,
+        session_id: &str,
+        event_id: &EventId,
+        index: u32,
+    ) -> Result<MessageIndexStatus> {
+        let status = match self.message_indices.entry((session_id.to_owned(), index)) {
+            Entry::Occupied(e) if e.get() == event_id => MessageIndexStatus::AlreadySeen,
+            Entry::Occupied(e) => MessageIndexStatus::Reused {
+                event_id: e.get().clone(),
+            },
+            Entry::Vacant(e) => {
+                e.insert(event_id.clone());
+                MessageIndexStatus::New
+            }
+        };
+
+        Ok(status)
+    }
+
+    async fn record_to_device_sent(
+        &self,
+        txn_id: &str,
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+    ) -> Result<()> {
+        self.pending_to_device.insert(
+            txn_id.to_owned(),
+            PendingToDevice {
+                txn_id: txn_id.to_owned(),
+                recipients,
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn mark_to_device_delivered(&self, txn_id: &str) -> Result<()> {
+        self.pending_to_device.remove(txn_id);
+        Ok(())
+    }
+
+    async fn pending_to_device(&self) -> Result<Vec<PendingToDevice>> {
+        Ok(self
+            .pending_to_device
+            .iter()
+            .map(|p| p.value().clone())
+            .collect())
+    }
+
+    async fn purge_room(&self, room_id: &RoomId, keep_history: bool) -> Result<RoomPurgeCounts> {
+        let mut counts = RoomPurgeCounts::default();
+
+        if self
+            .outbound_group_sessions
+            .write()
+            .await
+            .remove(room_id)
+            .is_some()
+        {
+            counts.outbound_group_sessions = 1;
+        }
+
+        if keep_history {
+            return Ok(counts);
+        }
+
+        let sessions: HashSet<String> = self
+            .inbound_group_sessions
+            .remove_room(room_id)
+            .iter()
+            .map(|s| s.session_id().to_owned())
+            .collect();
+
+        let indices = self.message_indices.len();
+        self.message_indices
+            .retain(|(session_id, _), _| !sessions.contains(session_id));
+
+        counts.inbound_group_sessions = sessions.len();
+        counts.message_indices = indices - self.message_indices.len();
+
+        Ok(counts)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        *self.account.write().await = None;
+        *self.private_identity.write().await = None;
+        self.outbound_group_sessions.write().await.clear();
+
+        self.sessions.clear();
+        self.inbound_group_sessions.clear();
+        self.devices.clear();
+        self.identities.clear();
+        self.tracked_users.clear();
+        self.users_for_key_query.clear();
+        self.olm_hashes.clear();
+        self.values.clear();
+        self.message_indices.clear();
+        self.pending_to_device.clear();
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> StoreCapabilities {
+        StoreCapabilities {
+            persistent: false,
+            ..Default::default()
+        }
     }
 }
 
@@ -246,9 +398,9 @@ mod test {
     use crate::{
         identities::device::test::get_device,
         olm::{test::get_account_and_session, InboundGroupSession, OlmMessageHash},
-        store::{memorystore::MemoryStore, Changes, CryptoStore},
+        store::{memorystore::MemoryStore, Changes, CryptoStore, MessageIndexStatus},
     };
-    use matrix_sdk_common::identifiers::room_id;
+    use matrix_sdk_common::identifiers::{event_id, room_id};
 
     #[tokio::test]
     async fn test_session_store() {
@@ -272,6 +424,40 @@ mod test {
         assert_eq!(&session, loaded_session);
     }
 
+    #[tokio::test]
+    async fn test_account_store() {
+        let (account, _) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+        let store = MemoryStore::new();
+
+        store.save_account(account.clone()).await.unwrap();
+        assert_eq!(store.load_account().await.unwrap(), Some(account.clone()));
+
+        let loaded = store
+            .get_or_create_account(Box::new(|| panic!("An account is already stored")))
+            .await
+            .unwrap();
+        assert_eq!(loaded, account);
+
+        let (outbound, _) = account
+            .create_group_session_pair_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let changes = Changes {
+            outbound_group_sessions: vec![outbound.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let loaded = store
+            .get_outbound_group_sessions(&room_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.session_id(), outbound.session_id());
+    }
+
     #[tokio::test]
     async fn test_group_session_store() {
         let (account, _) = get_account_and_session().await;
@@ -367,4 +553,34 @@ mod test {
         store.save_changes(changes).await.unwrap();
         assert!(store.is_message_known(&hash).await.unwrap());
     }
+
+    #[tokio::test]
+    async fn test_message_index_tracking() {
+        let store = MemoryStore::new();
+
+        let event_id = event_id!("$first:example.org");
+        let other_event_id = event_id!("$second:example.org");
+
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::New
+        );
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::AlreadySeen
+        );
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &other_event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::Reused { event_id }
+        );
+    }
 }
@@ -38,18 +38,22 @@
 //! [`CryptoStore`]: trait.Cryptostore.html
 
 pub mod caches;
+mod caching_store;
 mod memorystore;
+mod observed_store;
 mod pickle_key;
 #[cfg(feature = "sled_cryptostore")]
 pub(crate) mod sled;
 
 #[cfg(feature = "sled_cryptostore")]
 pub use self::sled::SledStore;
+pub use caching_store::CachingStore;
 pub use memorystore::MemoryStore;
+pub use observed_store::{ObservedStore, StoreEntry, StoreObserver, StoreOperation};
 pub use pickle_key::{EncryptedPickleKey, PickleKey};
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Debug,
     io::Error as IoError,
     ops::Deref,
@@ -62,10 +66,11 @@ use serde_json::Error as SerdeError;
 use thiserror::Error;
 
 use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
     async_trait,
     identifiers::{
-        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, Error as IdentifierValidationError, RoomId,
-        UserId,
+        DeviceId, DeviceIdBox, DeviceKeyAlgorithm, Error as IdentifierValidationError, EventId,
+        RoomId, UserId,
     },
     locks::Mutex,
     AsyncTraitDeps,
@@ -73,10 +78,11 @@ use matrix_sdk_common::{
 
 use crate::{
     error::SessionUnpicklingError,
+    file_encryption::KeyExportError,
     identities::{Device, ReadOnlyDevice, UserDevices, UserIdentities},
     olm::{
-        InboundGroupSession, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity,
-        ReadOnlyAccount, Session,
+        EncryptionSettings, InboundGroupSession, OlmMessageHash, OutboundGroupSession,
+        PrivateCrossSigningIdentity, ReadOnlyAccount, Session,
     },
     verification::VerificationMachine,
 };
@@ -84,6 +90,10 @@ use crate::{
 /// A `CryptoStore` specific result type.
 pub type Result<T> = std::result::Result<T, CryptoStoreError>;
 
+/// The key of the value the default to-device methods of [`CryptoStore`] keep
+/// the pending batches in.
+const PENDING_TO_DEVICE_KEY: &str = "pending_to_device";
+
 /// A wrapper for our CryptoStore trait object.
 ///
 /// This is needed because we want to have a generic interface so we can
@@ -111,6 +121,77 @@ pub struct Changes {
     pub devices: DeviceChanges,
 }
 
+/// The result of checking the message index of a Megolm encrypted event
+/// against the indices that were already seen.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageIndexStatus {
+    /// The index wasn't seen before, it is now recorded for the event.
+    New,
+    /// The index was already recorded for this exact event, e.g. because the
+    /// event was decrypted before.
+    AlreadySeen,
+    /// The index was already used by a different event, the event is most
+    /// likely a replay.
+    Reused {
+        /// The event that first used the message index.
+        event_id: EventId,
+    },
+}
+
+/// A batch of to-device messages that was sent out but wasn't yet
+/// acknowledged by the homeserver.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingToDevice {
+    /// The transaction id of the request that sends out the batch.
+    pub txn_id: String,
+    /// The devices the batch is addressed to.
+    pub recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+}
+
+/// The number of entries [`CryptoStore::purge_room`] removed from the
+/// store.
+///
+/// [`CryptoStore::purge_room`]: trait.CryptoStore.html#method.purge_room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoomPurgeCounts {
+    /// The number of removed inbound group sessions.
+    pub inbound_group_sessions: usize,
+    /// The number of removed outbound group sessions.
+    pub outbound_group_sessions: usize,
+    /// The number of removed message index records of the removed inbound
+    /// group sessions.
+    pub message_indices: usize,
+}
+
+/// The features a [`CryptoStore`] implementation supports.
+///
+/// The default describes a persistent, writable store that neither encrypts
+/// its data nor supports exporting it.
+///
+/// [`CryptoStore`]: trait.CryptoStore.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreCapabilities {
+    /// Does the data of the store survive a restart of the application.
+    pub persistent: bool,
+    /// Is the private data in the store encrypted at rest.
+    pub encrypted: bool,
+    /// Can the store save changes.
+    pub writable: bool,
+    /// Can the whole store be exported, e.g. as a backup.
+    pub export: bool,
+}
+
+impl Default for StoreCapabilities {
+    fn default() -> Self {
+        Self {
+            persistent: true,
+            encrypted: false,
+            writable: true,
+            export: false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 #[allow(missing_docs)]
 pub struct IdentityChanges {
@@ -287,11 +368,22 @@ pub enum CryptoStoreError {
     /// Error in the internal database
     #[cfg(feature = "sled_cryptostore")]
     #[error(transparent)]
-    Database(#[from] sled::Error),
+    Database(sled::Error),
 
     /// An IO error occurred.
     #[error(transparent)]
-    Io(#[from] IoError),
+    Io(IoError),
+
+    /// The store couldn't be written to because the disk is full.
+    ///
+    /// Nothing was written, the write can be retried once space was freed.
+    #[error("the disk is full")]
+    DiskFull(#[source] IoError),
+
+    /// The store couldn't be written to because the filesystem is mounted
+    /// read-only.
+    #[error("the filesystem is read-only")]
+    ReadOnlyFilesystem(#[source] IoError),
 
     /// The underlying Olm Account operation returned an error.
     #[error(transparent)]
@@ -320,6 +412,74 @@ pub enum CryptoStoreError {
     /// The store failed to (de)serialize a data type.
     #[error(transparent)]
     Serialization(#[from] SerdeError),
+
+    /// A store export couldn't be decrypted or decoded.
+    #[error(transparent)]
+    StoreExport(#[from] KeyExportError),
+
+    /// The account generated one-time keys with ids that were already
+    /// published, the stored account is older than the keys on the server.
+    ///
+    /// This happens if saving the account after a key upload was lost,
+    /// uploading the keys would replace published keys and break the Olm
+    /// sessions other devices create with them.
+    #[error(
+        "the one-time key counter regressed, key {generated} was generated but keys up to \
+         {published} were already published"
+    )]
+    KeyCounterRegression {
+        /// The counter of the newest published one-time key.
+        published: u32,
+        /// The counter of the oldest unpublished one-time key.
+        generated: u32,
+    },
+
+    /// A store export uses an archive version we don't know how to import.
+    #[error("the store export has an unsupported version {0}")]
+    UnsupportedExportVersion(u8),
+
+    /// A store export can only be imported into a store that is empty.
+    #[error("a store export can only be imported into an empty store")]
+    StoreNotEmpty,
+
+    /// The store doesn't implement the given operation.
+    #[error("the crypto store doesn't support {0}")]
+    Unsupported(&'static str),
+}
+
+impl From<IoError> for CryptoStoreError {
+    fn from(e: IoError) -> Self {
+        // Rust doesn't have stable error kinds for these yet, so look at the
+        // OS error codes.
+        #[cfg(unix)]
+        const DISK_FULL: &[i32] = &[28];
+        #[cfg(unix)]
+        const READ_ONLY: &[i32] = &[30];
+        #[cfg(windows)]
+        const DISK_FULL: &[i32] = &[39, 112];
+        #[cfg(windows)]
+        const READ_ONLY: &[i32] = &[19];
+        #[cfg(not(any(unix, windows)))]
+        const DISK_FULL: &[i32] = &[];
+        #[cfg(not(any(unix, windows)))]
+        const READ_ONLY: &[i32] = &[];
+
+        match e.raw_os_error() {
+            Some(code) if DISK_FULL.contains(&code) => CryptoStoreError::DiskFull(e),
+            Some(code) if READ_ONLY.contains(&code) => CryptoStoreError::ReadOnlyFilesystem(e),
+            _ => CryptoStoreError::Io(e),
+        }
+    }
+}
+
+#[cfg(feature = "sled_cryptostore")]
+impl From<sled::Error> for CryptoStoreError {
+    fn from(e: sled::Error) -> Self {
+        match e {
+            sled::Error::Io(e) => e.into(),
+            e => CryptoStoreError::Database(e),
+        }
+    }
 }
 
 /// Trait abstracting a store that the `OlmMachine` uses to store cryptographic
@@ -337,6 +497,32 @@ pub trait CryptoStore: AsyncTraitDeps {
     /// * `account` - The account that should be stored.
     async fn save_account(&self, account: ReadOnlyAccount) -> Result<()>;
 
+    /// Load the stored account or, if none is stored, create a new one and
+    /// store it.
+    ///
+    /// Checking for an existing account and storing the new one should happen
+    /// atomically, so that if multiple tasks call this concurrently all of
+    /// them get the same account back. The default implementation loads and
+    /// then saves the account, stores that can be shared between tasks need
+    /// to override it.
+    ///
+    /// # Arguments
+    ///
+    /// * `factory` - Function that creates the account if none is stored.
+    async fn get_or_create_account(
+        &self,
+        factory: Box<dyn FnOnce() -> ReadOnlyAccount + Send>,
+    ) -> Result<ReadOnlyAccount> {
+        if let Some(account) = self.load_account().await? {
+            return Ok(account);
+        }
+
+        let account = factory();
+        self.save_account(account.clone()).await?;
+
+        Ok(account)
+    }
+
     /// Try to load a private cross signing identity, if one is stored.
     async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>>;
 
@@ -378,6 +564,30 @@ pub trait CryptoStore: AsyncTraitDeps {
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>>;
 
+    /// Check if the outbound group session that is stored for the given room
+    /// needs to be rotated.
+    ///
+    /// Returns true if the stored session was used for longer or for more
+    /// messages than the given settings allow, if it was invalidated, or if no
+    /// session is stored for the room.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The room for which the session should be checked.
+    ///
+    /// * `settings` - The current encryption settings of the room.
+    async fn needs_rotation(
+        &self,
+        room_id: &RoomId,
+        settings: &EncryptionSettings,
+    ) -> Result<bool> {
+        Ok(self
+            .get_outbound_group_sessions(room_id)
+            .await?
+            .map(|s| s.invalidated() || s.expired_with(settings))
+            .unwrap_or(true))
+    }
+
     /// Is the given user already tracked.
     fn is_user_tracked(&self, user_id: &UserId) -> bool;
 
@@ -440,4 +650,154 @@ pub trait CryptoStore: AsyncTraitDeps {
 
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool>;
+
+    /// Check the message index of a Megolm encrypted event and record it if
+    /// it wasn't seen before.
+    ///
+    /// Every message index of a group session should be used by a single
+    /// event, an index that is reused by a different event points to a
+    /// replay attack.
+    ///
+    /// The default implementation keeps the indices as values of the store,
+    /// see [`save_value`](#tymethod.save_value).
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The id of the inbound group session that decrypted
+    /// the event.
+    ///
+    /// * `event_id` - The id of the decrypted event.
+    ///
+    /// * `index` - The message index of the decrypted event.
+    async fn check_and_update_message_index(
+        &self,
+        session_id: &str,
+        event_id: &EventId,
+        index: u32,
+    ) -> Result<MessageIndexStatus> {
+        let key = format!("megolm_message_index:{}:{}", session_id, index);
+
+        match self.get_value(&key).await? {
+            Some(known) => {
+                let known: EventId = serde_json::from_str(&known)?;
+
+                if &known == event_id {
+                    Ok(MessageIndexStatus::AlreadySeen)
+                } else {
+                    Ok(MessageIndexStatus::Reused { event_id: known })
+                }
+            }
+            None => {
+                self.save_value(key, serde_json::to_string(event_id)?)
+                    .await?;
+                Ok(MessageIndexStatus::New)
+            }
+        }
+    }
+
+    /// Record that a batch of to-device messages is being sent out.
+    ///
+    /// The batch stays pending until it's marked as delivered, batches that
+    /// are still pending after a restart need to be sent out again.
+    ///
+    /// The default implementation keeps the pending batches in a single value
+    /// of the store, see [`save_value`](#tymethod.save_value).
+    ///
+    /// # Arguments
+    ///
+    /// * `txn_id` - The transaction id of the request that sends out the
+    /// batch.
+    ///
+    /// * `recipients` - The devices the batch is addressed to.
+    async fn record_to_device_sent(
+        &self,
+        txn_id: &str,
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+    ) -> Result<()> {
+        let mut pending = self.pending_to_device().await?;
+        pending.retain(|p| p.txn_id != txn_id);
+        pending.push(PendingToDevice {
+            txn_id: txn_id.to_owned(),
+            recipients,
+        });
+
+        self.save_value(
+            PENDING_TO_DEVICE_KEY.to_owned(),
+            serde_json::to_string(&pending)?,
+        )
+        .await
+    }
+
+    /// Mark the batch of to-device messages with the given transaction id as
+    /// delivered, the homeserver acknowledged the request that sent it out.
+    async fn mark_to_device_delivered(&self, txn_id: &str) -> Result<()> {
+        let mut pending = self.pending_to_device().await?;
+        pending.retain(|p| p.txn_id != txn_id);
+
+        self.save_value(
+            PENDING_TO_DEVICE_KEY.to_owned(),
+            serde_json::to_string(&pending)?,
+        )
+        .await
+    }
+
+    /// Get all the batches of to-device messages that were sent out but not
+    /// yet marked as delivered.
+    async fn pending_to_device(&self) -> Result<Vec<PendingToDevice>> {
+        match self.get_value(PENDING_TO_DEVICE_KEY).await? {
+            Some(pending) => Ok(serde_json::from_str(&pending)?),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Remove all the crypto data that belongs to the given room, e.g. after
+    /// the user left and forgot the room.
+    ///
+    /// The outbound group session of the room, including the record of the
+    /// devices it was shared with, is always removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `room_id` - The id of the room that should be purged.
+    ///
+    /// * `keep_history` - Keep the inbound group sessions of the room. They
+    /// are needed to decrypt the history of the room, this should be set if
+    /// the user still has access to the history, e.g. because the room was
+    /// only left but not forgotten.
+    ///
+    /// The default implementation fails with a
+    /// [`CryptoStoreError::Unsupported`] error.
+    ///
+    /// [`CryptoStoreError::Unsupported`]: enum.CryptoStoreError.html#variant.Unsupported
+    async fn purge_room(&self, room_id: &RoomId, keep_history: bool) -> Result<RoomPurgeCounts> {
+        let _ = (room_id, keep_history);
+        Err(CryptoStoreError::Unsupported("purging rooms"))
+    }
+
+    /// Remove all the data of the store, the account, the sessions, the
+    /// group sessions, the devices and identities, as well as the tracked
+    /// users.
+    ///
+    /// This should be used when the user logs out and the store won't be used
+    /// again for this user and device. The key material the store held in
+    /// memory is dropped, and with it zeroized.
+    ///
+    /// A passphrase protected store keeps its passphrase, it can be reused
+    /// with the same passphrase.
+    ///
+    /// The default implementation fails with a
+    /// [`CryptoStoreError::Unsupported`] error.
+    ///
+    /// [`CryptoStoreError::Unsupported`]: enum.CryptoStoreError.html#variant.Unsupported
+    async fn clear(&self) -> Result<()> {
+        Err(CryptoStoreError::Unsupported("clearing the store"))
+    }
+
+    /// Get the features this store supports.
+    ///
+    /// Higher layers can use this to adapt their behaviour, e.g. to not
+    /// offer a key backup if the store can't be exported.
+    fn capabilities(&self) -> StoreCapabilities {
+        StoreCapabilities::default()
+    }
 }
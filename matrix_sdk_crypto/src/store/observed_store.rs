@@ -0,0 +1,631 @@
+// Copyright 2021 The Matrix.org Foundation C.I.C.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
+    async_trait,
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
+    locks::Mutex,
+    AsyncTraitDeps,
+};
+
+use super::{
+    Changes, CryptoStore, InboundGroupSession, MessageIndexStatus, PendingToDevice,
+    ReadOnlyAccount, Result, RoomPurgeCounts, Session, StoreCapabilities,
+};
+use crate::{
+    identities::{ReadOnlyDevice, UserIdentities},
+    olm::{EncryptionSettings, OlmMessageHash, OutboundGroupSession, PrivateCrossSigningIdentity},
+};
+
+/// The kind of write that happened in the store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreOperation {
+    /// An entry was added to the store or an existing one was updated.
+    Save,
+    /// An entry was removed from the store.
+    Delete,
+}
+
+/// The entry of the store a write touched.
+///
+/// Only identifiers are included, never pickles, keys or other secrets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreEntry {
+    /// Our own Olm account.
+    Account {
+        /// The user the account belongs to.
+        user_id: UserId,
+        /// The device the account belongs to.
+        device_id: DeviceIdBox,
+    },
+    /// Our private cross signing identity.
+    PrivateIdentity {
+        /// The user the identity belongs to.
+        user_id: UserId,
+    },
+    /// An Olm session.
+    Session {
+        /// The curve25519 key of the other side of the session.
+        sender_key: String,
+        /// The unique id of the session.
+        session_id: String,
+    },
+    /// An inbound Megolm session.
+    InboundGroupSession {
+        /// The room the session is used in.
+        room_id: RoomId,
+        /// The curve25519 key of the sender of the session.
+        sender_key: String,
+        /// The unique id of the session.
+        session_id: String,
+    },
+    /// An outbound Megolm session.
+    OutboundGroupSession {
+        /// The room the session is used in.
+        room_id: RoomId,
+        /// The unique id of the session.
+        session_id: String,
+    },
+    /// A device of some user.
+    Device {
+        /// The owner of the device.
+        user_id: UserId,
+        /// The id of the device.
+        device_id: DeviceIdBox,
+    },
+    /// The public cross signing identity of some user.
+    UserIdentity {
+        /// The owner of the identity.
+        user_id: UserId,
+    },
+    /// The hash of a received Olm message.
+    MessageHash {
+        /// The curve25519 key of the sender of the message.
+        sender_key: String,
+    },
+    /// The tracking state of some user.
+    TrackedUser {
+        /// The tracked user.
+        user_id: UserId,
+    },
+    /// A custom value.
+    Value {
+        /// The key the value is stored under.
+        key: String,
+    },
+    /// The record of a Megolm message index.
+    MessageIndex {
+        /// The id of the session the index belongs to.
+        session_id: String,
+        /// The message index.
+        index: u32,
+    },
+    /// A batch of to-device messages that was sent out.
+    PendingToDevice {
+        /// The transaction id of the request that sent the batch.
+        txn_id: String,
+    },
+    /// All the data of a room, see [`CryptoStore::purge_room`].
+    ///
+    /// [`CryptoStore::purge_room`]: trait.CryptoStore.html#method.purge_room
+    Room {
+        /// The purged room.
+        room_id: RoomId,
+    },
+    /// All the data of the store, see [`CryptoStore::clear`].
+    ///
+    /// [`CryptoStore::clear`]: trait.CryptoStore.html#method.clear
+    Store,
+}
+
+/// Observer that gets notified about the writes of an [`ObservedStore`].
+///
+/// [`ObservedStore`]: struct.ObservedStore.html
+pub trait StoreObserver: AsyncTraitDeps {
+    /// Called after a write to the store succeeded.
+    ///
+    /// The store method that did the write only returns once this returned,
+    /// it should return quickly.
+    fn on_write(&self, operation: StoreOperation, entry: &StoreEntry);
+}
+
+/// A `CryptoStore` that notifies a [`StoreObserver`] about every successful
+/// write to another `CryptoStore`.
+///
+/// This is meant as a debugging aid to trace the activity of a store, e.g. to
+/// find out when and why one-time keys or sessions disappeared.
+///
+/// # Example
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use matrix_sdk_crypto::store::{
+///     MemoryStore, ObservedStore, StoreEntry, StoreObserver, StoreOperation,
+/// };
+///
+/// #[derive(Debug)]
+/// struct Logger;
+///
+/// impl StoreObserver for Logger {
+///     fn on_write(&self, operation: StoreOperation, entry: &StoreEntry) {
+///         println!("{:?} {:?}", operation, entry);
+///     }
+/// }
+///
+/// let store = ObservedStore::new(MemoryStore::new(), Arc::new(Logger));
+/// ```
+///
+/// [`StoreObserver`]: trait.StoreObserver.html
+#[derive(Clone)]
+pub struct ObservedStore<S: CryptoStore> {
+    inner: S,
+    observer: Arc<dyn StoreObserver>,
+}
+
+#[cfg(not(tarpaulin_include))]
+impl<S: CryptoStore> fmt::Debug for ObservedStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObservedStore")
+            .field("inner", &self.inner)
+            .field("observer", &self.observer)
+            .finish()
+    }
+}
+
+impl<S: CryptoStore> ObservedStore<S> {
+    /// Wrap the given store.
+    ///
+    /// # Arguments
+    ///
+    /// * `store` - The store whose writes should be observed.
+    ///
+    /// * `observer` - The observer that gets notified about the writes.
+    pub fn new(store: S, observer: Arc<dyn StoreObserver>) -> Self {
+        Self {
+            inner: store,
+            observer,
+        }
+    }
+
+    /// Get the wrapped store.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn notify(&self, operation: StoreOperation, entry: StoreEntry) {
+        self.observer.on_write(operation, &entry);
+    }
+
+    fn account_entry(account: &ReadOnlyAccount) -> StoreEntry {
+        StoreEntry::Account {
+            user_id: account.user_id().clone(),
+            device_id: account.device_id().into(),
+        }
+    }
+
+    fn device_entry(device: &ReadOnlyDevice) -> StoreEntry {
+        StoreEntry::Device {
+            user_id: device.user_id().clone(),
+            device_id: device.device_id().into(),
+        }
+    }
+
+    /// Get the entries the given changes will touch.
+    fn changed_entries(changes: &Changes) -> Vec<(StoreOperation, StoreEntry)> {
+        use StoreOperation::{Delete, Save};
+
+        let mut entries = Vec::new();
+
+        if let Some(account) = &changes.account {
+            entries.push((Save, Self::account_entry(account)));
+        }
+
+        if let Some(identity) = &changes.private_identity {
+            entries.push((
+                Save,
+                StoreEntry::PrivateIdentity {
+                    user_id: identity.user_id().clone(),
+                },
+            ));
+        }
+
+        entries.extend(changes.sessions.iter().map(|s| {
+            (
+                Save,
+                StoreEntry::Session {
+                    sender_key: s.sender_key.to_string(),
+                    session_id: s.session_id().to_owned(),
+                },
+            )
+        }));
+
+        entries.extend(changes.message_hashes.iter().map(|h| {
+            (
+                Save,
+                StoreEntry::MessageHash {
+                    sender_key: h.sender_key.clone(),
+                },
+            )
+        }));
+
+        entries.extend(changes.inbound_group_sessions.iter().map(|s| {
+            (
+                Save,
+                StoreEntry::InboundGroupSession {
+                    room_id: s.room_id().clone(),
+                    sender_key: s.sender_key().to_owned(),
+                    session_id: s.session_id().to_owned(),
+                },
+            )
+        }));
+
+        entries.extend(changes.outbound_group_sessions.iter().map(|s| {
+            (
+                Save,
+                StoreEntry::OutboundGroupSession {
+                    room_id: s.room_id().clone(),
+                    session_id: s.session_id().to_owned(),
+                },
+            )
+        }));
+
+        entries.extend(
+            changes
+                .identities
+                .new
+                .iter()
+                .chain(changes.identities.changed.iter())
+                .map(|i| {
+                    (
+                        Save,
+                        StoreEntry::UserIdentity {
+                            user_id: i.user_id().clone(),
+                        },
+                    )
+                }),
+        );
+
+        entries.extend(
+            changes
+                .devices
+                .new
+                .iter()
+                .chain(changes.devices.changed.iter())
+                .map(|d| (Save, Self::device_entry(d))),
+        );
+
+        entries.extend(
+            changes
+                .devices
+                .deleted
+                .iter()
+                .map(|d| (Delete, Self::device_entry(d))),
+        );
+
+        entries
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait)]
+impl<S: CryptoStore> CryptoStore for ObservedStore<S> {
+    async fn load_account(&self) -> Result<Option<ReadOnlyAccount>> {
+        self.inner.load_account().await
+    }
+
+    async fn save_account(&self, account: ReadOnlyAccount) -> Result<()> {
+        let entry = Self::account_entry(&account);
+        self.inner.save_account(account).await?;
+        self.notify(StoreOperation::Save, entry);
+
+        Ok(())
+    }
+
+    async fn get_or_create_account(
+        &self,
+        factory: Box<dyn FnOnce() -> ReadOnlyAccount + Send>,
+    ) -> Result<ReadOnlyAccount> {
+        let created = Arc::new(AtomicBool::new(false));
+        let factory = {
+            let created = created.clone();
+
+            Box::new(move || {
+                created.store(true, Ordering::SeqCst);
+                factory()
+            })
+        };
+
+        let account = self.inner.get_or_create_account(factory).await?;
+
+        if created.load(Ordering::SeqCst) {
+            self.notify(StoreOperation::Save, Self::account_entry(&account));
+        }
+
+        Ok(account)
+    }
+
+    async fn load_identity(&self) -> Result<Option<PrivateCrossSigningIdentity>> {
+        self.inner.load_identity().await
+    }
+
+    async fn save_changes(&self, changes: Changes) -> Result<()> {
+        let entries = Self::changed_entries(&changes);
+        self.inner.save_changes(changes).await?;
+
+        for (operation, entry) in entries {
+            self.notify(operation, entry);
+        }
+
+        Ok(())
+    }
+
+    async fn get_sessions(&self, sender_key: &str) -> Result<Option<Arc<Mutex<Vec<Session>>>>> {
+        self.inner.get_sessions(sender_key).await
+    }
+
+    async fn get_inbound_group_session(
+        &self,
+        room_id: &RoomId,
+        sender_key: &str,
+        session_id: &str,
+    ) -> Result<Option<InboundGroupSession>> {
+        self.inner
+            .get_inbound_group_session(room_id, sender_key, session_id)
+            .await
+    }
+
+    async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>> {
+        self.inner.get_inbound_group_sessions().await
+    }
+
+    async fn get_outbound_group_sessions(
+        &self,
+        room_id: &RoomId,
+    ) -> Result<Option<OutboundGroupSession>> {
+        self.inner.get_outbound_group_sessions(room_id).await
+    }
+
+    async fn needs_rotation(
+        &self,
+        room_id: &RoomId,
+        settings: &EncryptionSettings,
+    ) -> Result<bool> {
+        self.inner.needs_rotation(room_id, settings).await
+    }
+
+    fn is_user_tracked(&self, user_id: &UserId) -> bool {
+        self.inner.is_user_tracked(user_id)
+    }
+
+    fn has_users_for_key_query(&self) -> bool {
+        self.inner.has_users_for_key_query()
+    }
+
+    fn users_for_key_query(&self) -> HashSet<UserId> {
+        self.inner.users_for_key_query()
+    }
+
+    async fn update_tracked_user(&self, user: &UserId, dirty: bool) -> Result<bool> {
+        let result = self.inner.update_tracked_user(user, dirty).await?;
+        self.notify(
+            StoreOperation::Save,
+            StoreEntry::TrackedUser {
+                user_id: user.clone(),
+            },
+        );
+
+        Ok(result)
+    }
+
+    async fn get_device(
+        &self,
+        user_id: &UserId,
+        device_id: &DeviceId,
+    ) -> Result<Option<ReadOnlyDevice>> {
+        self.inner.get_device(user_id, device_id).await
+    }
+
+    async fn get_user_devices(
+        &self,
+        user_id: &UserId,
+    ) -> Result<HashMap<DeviceIdBox, ReadOnlyDevice>> {
+        self.inner.get_user_devices(user_id).await
+    }
+
+    async fn get_user_identity(&self, user_id: &UserId) -> Result<Option<UserIdentities>> {
+        self.inner.get_user_identity(user_id).await
+    }
+
+    async fn save_value(&self, key: String, value: String) -> Result<()> {
+        let entry = StoreEntry::Value { key: key.clone() };
+        self.inner.save_value(key, value).await?;
+        self.notify(StoreOperation::Save, entry);
+
+        Ok(())
+    }
+
+    async fn remove_value(&self, key: &str) -> Result<()> {
+        self.inner.remove_value(key).await?;
+        self.notify(
+            StoreOperation::Delete,
+            StoreEntry::Value {
+                key: key.to_owned(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn get_value(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get_value(key).await
+    }
+
+    async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool> {
+        self.inner.is_message_known(message_hash).await
+    }
+
+    async fn check_and_update_message_index(
+        &self,
+        session_id: &str,
+        event_id: &EventId,
+        index: u32,
+    ) -> Result<MessageIndexStatus> {
+        let status = self
+            .inner
+            .check_and_update_message_index(session_id, event_id, index)
+            .await?;
+
+        if status == MessageIndexStatus::New {
+            self.notify(
+                StoreOperation::Save,
+                StoreEntry::MessageIndex {
+                    session_id: session_id.to_owned(),
+                    index,
+                },
+            );
+        }
+
+        Ok(status)
+    }
+
+    async fn record_to_device_sent(
+        &self,
+        txn_id: &str,
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+    ) -> Result<()> {
+        self.inner.record_to_device_sent(txn_id, recipients).await?;
+        self.notify(
+            StoreOperation::Save,
+            StoreEntry::PendingToDevice {
+                txn_id: txn_id.to_owned(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn mark_to_device_delivered(&self, txn_id: &str) -> Result<()> {
+        self.inner.mark_to_device_delivered(txn_id).await?;
+        self.notify(
+            StoreOperation::Delete,
+            StoreEntry::PendingToDevice {
+                txn_id: txn_id.to_owned(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn pending_to_device(&self) -> Result<Vec<PendingToDevice>> {
+        self.inner.pending_to_device().await
+    }
+
+    async fn purge_room(&self, room_id: &RoomId, keep_history: bool) -> Result<RoomPurgeCounts> {
+        let counts = self.inner.purge_room(room_id, keep_history).await?;
+        self.notify(
+            StoreOperation::Delete,
+            StoreEntry::Room {
+                room_id: room_id.clone(),
+            },
+        );
+
+        Ok(counts)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.inner.clear().await?;
+        self.notify(StoreOperation::Delete, StoreEntry::Store);
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> StoreCapabilities {
+        self.inner.capabilities()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use matrix_sdk_common::identifiers::room_id;
+
+    use super::{ObservedStore, StoreEntry, StoreObserver, StoreOperation};
+    use crate::{
+        olm::test::get_account_and_session,
+        store::{Changes, CryptoStore, MemoryStore},
+    };
+
+    #[derive(Debug, Default)]
+    struct Recorder(Mutex<Vec<(StoreOperation, StoreEntry)>>);
+
+    impl StoreObserver for Recorder {
+        fn on_write(&self, operation: StoreOperation, entry: &StoreEntry) {
+            self.0.lock().unwrap().push((operation, entry.clone()));
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_are_observed() {
+        let (account, session) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+        let recorder = Arc::new(Recorder::default());
+        let store = ObservedStore::new(MemoryStore::new(), recorder.clone());
+
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        store.get_sessions(&session.sender_key).await.unwrap();
+        store.remove_value("key").await.unwrap();
+        store.purge_room(&room_id, true).await.unwrap();
+
+        let writes = recorder.0.lock().unwrap();
+        assert_eq!(
+            *writes,
+            vec![
+                (
+                    StoreOperation::Save,
+                    StoreEntry::Session {
+                        sender_key: session.sender_key.to_string(),
+                        session_id: session.session_id().to_owned(),
+                    }
+                ),
+                (
+                    StoreOperation::Delete,
+                    StoreEntry::Value {
+                        key: "key".to_owned()
+                    }
+                ),
+                (StoreOperation::Delete, StoreEntry::Room { room_id }),
+            ]
+        );
+
+        drop(writes);
+        store.save_account(account).await.unwrap();
+        assert_eq!(recorder.0.lock().unwrap().len(), 4);
+    }
+}
@@ -13,7 +13,7 @@
 // limitations under the License.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     convert::TryFrom,
     path::Path,
     sync::Arc,
@@ -21,23 +21,27 @@ use std::{
 
 use dashmap::DashSet;
 use olm_rs::PicklingMode;
+use serde::{Deserialize, Serialize};
 pub use sled::Error;
 use sled::{
     transaction::{ConflictableTransactionError, TransactionError},
-    Config, Db, Transactional, Tree,
+    Batch, Config, Db, Transactional, Tree,
 };
 
 use matrix_sdk_common::{
+    api::r0::to_device::DeviceIdOrAllDevices,
     async_trait,
-    identifiers::{DeviceId, DeviceIdBox, RoomId, UserId},
+    identifiers::{DeviceId, DeviceIdBox, EventId, RoomId, UserId},
     locks::Mutex,
 };
 
 use super::{
-    caches::SessionStore, Changes, CryptoStore, CryptoStoreError, InboundGroupSession, PickleKey,
-    ReadOnlyAccount, Result, Session,
+    caches::SessionStore, Changes, CryptoStore, CryptoStoreError, EncryptedPickleKey,
+    InboundGroupSession, MessageIndexStatus, PendingToDevice, PickleKey, ReadOnlyAccount, Result,
+    RoomPurgeCounts, Session, StoreCapabilities,
 };
 use crate::{
+    file_encryption::{decrypt_helper, encrypt_helper, KeyExportError},
     identities::{ReadOnlyDevice, UserIdentities},
     olm::{OutboundGroupSession, PickledInboundGroupSession, PrivateCrossSigningIdentity},
 };
@@ -46,6 +50,39 @@ use crate::{
 /// panic once we try to pickle a Signing object.
 const DEFAULT_PICKLE: &str = "DEFAULT_PICKLE_PASSPHRASE_123456";
 
+/// The names of all the trees the store keeps its data in.
+const TREES: &[&str] = &[
+    "account",
+    "private_identity",
+    "session",
+    "inbound_group_sessions",
+    "outbound_group_sessions",
+    "tracked_users",
+    "users_for_key_query",
+    "olm_hashes",
+    "message_indices",
+    "pending_to_device",
+    "devices",
+    "identities",
+    "values",
+];
+
+/// The version of the archive format that `SledStore::export_store()`
+/// produces, bump this if the layout of the store changes.
+const STORE_EXPORT_VERSION: u8 = 1;
+
+#[derive(Debug, Deserialize)]
+struct StoreExportHeader {
+    version: u8,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoreExport {
+    version: u8,
+    pickle_key: Option<Vec<u8>>,
+    trees: BTreeMap<String, Vec<(Vec<u8>, Vec<u8>)>>,
+}
+
 trait EncodeKey {
     const SEPARATOR: u8 = 0xff;
     fn encode(&self) -> Vec<u8>;
@@ -100,6 +137,8 @@ impl EncodeKey for (&str, &str, &str) {
 pub struct SledStore {
     inner: Db,
     pickle_key: Arc<PickleKey>,
+    /// Was the store opened with a passphrase.
+    encrypted: bool,
 
     session_cache: SessionStore,
     tracked_users_cache: Arc<DashSet<UserId>>,
@@ -109,6 +148,8 @@ pub struct SledStore {
     private_identity: Tree,
 
     olm_hashes: Tree,
+    message_indices: Tree,
+    pending_to_device: Tree,
     sessions: Tree,
     inbound_group_sessions: Tree,
     outbound_group_sessions: Tree,
@@ -125,7 +166,7 @@ impl From<TransactionError<serde_json::Error>> for CryptoStoreError {
     fn from(e: TransactionError<serde_json::Error>) -> Self {
         match e {
             TransactionError::Abort(e) => CryptoStoreError::Serialization(e),
-            TransactionError::Storage(e) => CryptoStoreError::Database(e),
+            TransactionError::Storage(e) => e.into(),
         }
     }
 }
@@ -156,6 +197,8 @@ impl SledStore {
         let tracked_users = db.open_tree("tracked_users")?;
         let users_for_key_query = db.open_tree("users_for_key_query")?;
         let olm_hashes = db.open_tree("olm_hashes")?;
+        let message_indices = db.open_tree("message_indices")?;
+        let pending_to_device = db.open_tree("pending_to_device")?;
 
         let devices = db.open_tree("devices")?;
         let identities = db.open_tree("identities")?;
@@ -173,6 +216,7 @@ impl SledStore {
         Ok(Self {
             inner: db,
             pickle_key: pickle_key.into(),
+            encrypted: passphrase.is_some(),
             account,
             private_identity,
             sessions,
@@ -185,11 +229,159 @@ impl SledStore {
             tracked_users,
             users_for_key_query,
             olm_hashes,
+            message_indices,
+            pending_to_device,
             identities,
             values,
         })
     }
 
+    /// Check if the given passphrase is the one the store was opened with.
+    ///
+    /// Only the encrypted pickle key of the store is decrypted, this is a lot
+    /// cheaper than loading the account or any other data of the store.
+    ///
+    /// Returns false if the store isn't protected by a passphrase.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that should be checked.
+    pub fn verify_passphrase(&self, passphrase: &str) -> Result<bool> {
+        let encrypted: EncryptedPickleKey = match self.inner.get("pickle_key".encode())? {
+            Some(key) if self.encrypted => serde_json::from_slice(&key)?,
+            _ => return Ok(false),
+        };
+
+        Ok(PickleKey::from_encrypted(passphrase, encrypted).is_ok())
+    }
+
+    /// Export the whole store as a single encrypted archive.
+    ///
+    /// Unlike a room key export, the archive contains the account, the Olm
+    /// sessions, the device and identity trust state and all the other values
+    /// the store holds. It can be restored using
+    /// [`import_store`](#method.import_store).
+    ///
+    /// The pickles inside the archive stay encrypted with the pickle key of
+    /// this store, so the restored store needs to be opened using the same
+    /// store passphrase this store was opened with.
+    ///
+    /// Writes that happen while the export is running may or may not end up
+    /// in the archive, the store shouldn't be in use while it's exported.
+    ///
+    /// # Arguments
+    ///
+    /// * `passphrase` - The passphrase that will be used to encrypt the
+    /// archive.
+    ///
+    /// * `rounds` - The number of rounds that should be used for the key
+    /// derivation when the passphrase gets turned into an AES key.
+    pub fn export_store(&self, passphrase: &str, rounds: u32) -> Result<Vec<u8>> {
+        let mut trees = BTreeMap::new();
+
+        for name in TREES {
+            let tree = self.inner.open_tree(name)?;
+            let entries = tree
+                .iter()
+                .map(|e| e.map(|(k, v)| (k.to_vec(), v.to_vec())))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+
+            trees.insert((*name).to_owned(), entries);
+        }
+
+        let export = StoreExport {
+            version: STORE_EXPORT_VERSION,
+            pickle_key: self.inner.get("pickle_key".encode())?.map(|k| k.to_vec()),
+            trees,
+        };
+
+        let mut plaintext = serde_json::to_vec(&export)?;
+
+        Ok(encrypt_helper(&mut plaintext, passphrase, rounds).into_bytes())
+    }
+
+    /// Restore a store from an archive that was created using
+    /// [`export_store`](#method.export_store).
+    ///
+    /// The archive is restored in a single transaction into the store at the
+    /// given path, which needs to be empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The path where the restored store should live.
+    ///
+    /// * `export` - The encrypted archive.
+    ///
+    /// * `passphrase` - The passphrase that was used to encrypt the archive.
+    ///
+    /// * `store_passphrase` - The passphrase the exported store was opened
+    /// with.
+    pub fn import_store(
+        path: impl AsRef<Path>,
+        export: &[u8],
+        passphrase: &str,
+        store_passphrase: Option<&str>,
+    ) -> Result<Self> {
+        let export = String::from_utf8(export.to_vec()).map_err(KeyExportError::from)?;
+        let plaintext = decrypt_helper(&export, passphrase)?;
+
+        let header: StoreExportHeader = serde_json::from_str(&plaintext)?;
+
+        let export: StoreExport = match header.version {
+            STORE_EXPORT_VERSION => serde_json::from_str(&plaintext)?,
+            v => return Err(CryptoStoreError::UnsupportedExportVersion(v)),
+        };
+
+        let path = path.as_ref().join("matrix-sdk-crypto");
+        let db = Config::new().temporary(false).path(path).open()?;
+
+        let mut trees = Vec::with_capacity(TREES.len() + 1);
+        let mut batches = Vec::with_capacity(TREES.len() + 1);
+
+        for name in TREES {
+            let tree = db.open_tree(name)?;
+            let mut batch = Batch::default();
+
+            if !tree.is_empty() {
+                return Err(CryptoStoreError::StoreNotEmpty);
+            }
+
+            for (key, value) in export.trees.get(*name).into_iter().flatten() {
+                batch.insert(key.as_slice(), value.as_slice());
+            }
+
+            trees.push(tree);
+            batches.push(batch);
+        }
+
+        if db.contains_key("pickle_key".encode())? {
+            return Err(CryptoStoreError::StoreNotEmpty);
+        }
+
+        let mut batch = Batch::default();
+
+        if let Some(pickle_key) = &export.pickle_key {
+            batch.insert("pickle_key".encode(), pickle_key.as_slice());
+        }
+
+        trees.push((*db).clone());
+        batches.push(batch);
+
+        let ret: std::result::Result<(), TransactionError<serde_json::Error>> =
+            trees.as_slice().transaction(|trees| {
+                for (tree, batch) in trees.iter().zip(&batches) {
+                    tree.apply_batch(batch)?;
+                }
+
+                Ok(())
+            });
+
+        ret?;
+        db.flush()?;
+
+        SledStore::open_helper(db, store_passphrase)
+    }
+
     fn get_or_create_pickle_key(passphrase: &str, database: &Db) -> Result<PickleKey> {
         let key = if let Some(key) = database
             .get("pickle_key".encode())?
@@ -433,6 +625,32 @@ impl CryptoStore for SledStore {
         Ok(())
     }
 
+    async fn get_or_create_account(
+        &self,
+        factory: Box<dyn FnOnce() -> ReadOnlyAccount + Send>,
+    ) -> Result<ReadOnlyAccount> {
+        if let Some(account) = self.load_account().await? {
+            return Ok(account);
+        }
+
+        let account = factory();
+        let pickle = serde_json::to_vec(&account.pickle(self.get_pickle_mode()).await)?;
+
+        // Only store our account if no other task stored one in the meantime,
+        // otherwise the account of the other task wins.
+        match self.account.compare_and_swap(
+            "account".encode(),
+            None as Option<&[u8]>,
+            Some(pickle),
+        )? {
+            Ok(()) => Ok(account),
+            Err(_) => self
+                .load_account()
+                .await?
+                .ok_or(CryptoStoreError::AccountUnset),
+        }
+    }
+
     async fn save_changes(&self, changes: Changes) -> Result<()> {
         self.save_changes(changes).await
     }
@@ -609,6 +827,168 @@ impl CryptoStore for SledStore {
     ) -> Result<Option<OutboundGroupSession>> {
         self.load_outbound_group_session(room_id).await
     }
+
+    async fn check_and_update_message_index(
+        &self,
+        session_id: &str,
+        event_id: &EventId,
+        index: u32,
+    ) -> Result<MessageIndexStatus> {
+        let key = (session_id, index.to_string().as_str()).encode();
+
+        // Only record the event if no event was recorded for the index yet,
+        // otherwise compare against the event that was recorded.
+        let status = match self.message_indices.compare_and_swap(
+            key,
+            None as Option<&[u8]>,
+            Some(event_id.as_str().as_bytes()),
+        )? {
+            Ok(()) => MessageIndexStatus::New,
+            Err(e) => {
+                let current = e
+                    .current
+                    .map(|v| String::from_utf8_lossy(&v).to_string())
+                    .unwrap_or_default();
+
+                if current == event_id.as_str() {
+                    MessageIndexStatus::AlreadySeen
+                } else {
+                    MessageIndexStatus::Reused {
+                        event_id: EventId::try_from(current)?,
+                    }
+                }
+            }
+        };
+
+        Ok(status)
+    }
+
+    async fn record_to_device_sent(
+        &self,
+        txn_id: &str,
+        recipients: BTreeMap<UserId, Vec<DeviceIdOrAllDevices>>,
+    ) -> Result<()> {
+        let pending = PendingToDevice {
+            txn_id: txn_id.to_owned(),
+            recipients,
+        };
+
+        self.pending_to_device
+            .insert(txn_id.encode(), serde_json::to_vec(&pending)?)?;
+        self.pending_to_device.flush_async().await?;
+
+        Ok(())
+    }
+
+    async fn mark_to_device_delivered(&self, txn_id: &str) -> Result<()> {
+        self.pending_to_device.remove(txn_id.encode())?;
+        Ok(())
+    }
+
+    async fn pending_to_device(&self) -> Result<Vec<PendingToDevice>> {
+        self.pending_to_device
+            .iter()
+            .map(|p| Ok(serde_json::from_slice(&p?.1)?))
+            .collect()
+    }
+
+    async fn purge_room(&self, room_id: &RoomId, keep_history: bool) -> Result<RoomPurgeCounts> {
+        let mut inbound_keys = Vec::new();
+        let mut index_keys = Vec::new();
+
+        if !keep_history {
+            for key in self
+                .inbound_group_sessions
+                .scan_prefix(room_id.encode())
+                .keys()
+            {
+                let key = key?;
+
+                // The key is made out of the room id, the sender key and the
+                // session id, each one terminated by the separator.
+                if let Some(session_id) = key.split(|b| *b == <&str as EncodeKey>::SEPARATOR).nth(2)
+                {
+                    let prefix = [session_id, &[<&str as EncodeKey>::SEPARATOR][..]].concat();
+
+                    for index_key in self.message_indices.scan_prefix(prefix).keys() {
+                        index_keys.push(index_key?);
+                    }
+                }
+
+                inbound_keys.push(key);
+            }
+        }
+
+        let ret: std::result::Result<RoomPurgeCounts, TransactionError<serde_json::Error>> = (
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.message_indices,
+        )
+            .transaction(|(inbound_sessions, outbound_sessions, message_indices)| {
+                let mut counts = RoomPurgeCounts::default();
+
+                for key in &inbound_keys {
+                    if inbound_sessions.remove(key)?.is_some() {
+                        counts.inbound_group_sessions += 1;
+                    }
+                }
+
+                for key in &index_keys {
+                    if message_indices.remove(key)?.is_some() {
+                        counts.message_indices += 1;
+                    }
+                }
+
+                if outbound_sessions.remove(room_id.encode())?.is_some() {
+                    counts.outbound_group_sessions += 1;
+                }
+
+                Ok(counts)
+            });
+
+        let counts = ret?;
+        self.inner.flush_async().await?;
+
+        Ok(counts)
+    }
+
+    async fn clear(&self) -> Result<()> {
+        // The pickle key lives in the default tree of the database and is
+        // left alone, so the store can be reused with the same passphrase.
+        for tree in &[
+            &self.account,
+            &self.private_identity,
+            &self.olm_hashes,
+            &self.message_indices,
+            &self.pending_to_device,
+            &self.sessions,
+            &self.inbound_group_sessions,
+            &self.outbound_group_sessions,
+            &self.devices,
+            &self.identities,
+            &self.tracked_users,
+            &self.users_for_key_query,
+            &self.values,
+        ] {
+            tree.clear()?;
+        }
+
+        self.session_cache.clear();
+        self.tracked_users_cache.clear();
+        self.users_for_key_query_cache.clear();
+
+        self.inner.flush_async().await?;
+
+        Ok(())
+    }
+
+    fn capabilities(&self) -> StoreCapabilities {
+        StoreCapabilities {
+            encrypted: self.encrypted,
+            export: true,
+            ..Default::default()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -619,17 +999,22 @@ mod test {
             user::test::{get_other_identity, get_own_identity},
         },
         olm::{
-            GroupSessionKey, InboundGroupSession, OlmMessageHash, PrivateCrossSigningIdentity,
+            EncryptionSettings, GroupSessionKey, InboundGroupSession, OlmMessageHash,
+            OutboundGroupSession, PickledOutboundGroupSession, PrivateCrossSigningIdentity,
             ReadOnlyAccount, Session,
         },
-        store::{Changes, DeviceChanges, IdentityChanges},
+        store::{
+            Changes, CryptoStoreError, DeviceChanges, IdentityChanges, MessageIndexStatus,
+            RoomPurgeCounts,
+        },
     };
     use matrix_sdk_common::{
-        api::r0::keys::SignedKey,
-        identifiers::{room_id, user_id, DeviceId, UserId},
+        api::r0::{keys::SignedKey, to_device::DeviceIdOrAllDevices},
+        identifiers::{event_id, room_id, user_id, DeviceId, UserId},
+        instant::{Duration, Instant},
     };
     use matrix_sdk_test::async_test;
-    use olm_rs::outbound_group_session::OlmOutboundGroupSession;
+    use olm_rs::{outbound_group_session::OlmOutboundGroupSession, PicklingMode};
     use std::collections::BTreeMap;
     use tempfile::tempdir;
 
@@ -722,6 +1107,24 @@ mod test {
             .expect("Can't save account");
     }
 
+    #[async_test]
+    async fn get_or_create_account() {
+        let (store, _dir) = get_store(None).await;
+
+        let (first, second) = futures::join!(
+            store.get_or_create_account(Box::new(get_account)),
+            store.get_or_create_account(Box::new(get_account)),
+        );
+
+        let first = first.unwrap();
+        let second = second.unwrap();
+
+        assert_eq!(first, second);
+
+        let loaded_account = store.load_account().await.unwrap().unwrap();
+        assert_eq!(first, loaded_account);
+    }
+
     #[async_test]
     async fn load_account() {
         let (store, _dir) = get_store(None).await;
@@ -754,6 +1157,55 @@ mod test {
         assert_eq!(account, loaded_account);
     }
 
+    #[async_test]
+    async fn export_and_import_store() {
+        let (store, _dir) = get_store(Some("secret_passphrase")).await;
+        let (account, session) = get_account_and_session().await;
+
+        store
+            .save_account(account.clone())
+            .await
+            .expect("Can't save account");
+
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let export = store.export_store("export_passphrase", 1000).unwrap();
+
+        let tmpdir = tempdir().unwrap();
+
+        assert!(SledStore::import_store(
+            tmpdir.path(),
+            &export,
+            "wrong_passphrase",
+            Some("secret_passphrase")
+        )
+        .is_err());
+
+        let restored = SledStore::import_store(
+            tmpdir.path(),
+            &export,
+            "export_passphrase",
+            Some("secret_passphrase"),
+        )
+        .expect("Can't import the store");
+
+        let loaded_account = restored.load_account().await.unwrap().unwrap();
+        assert_eq!(account, loaded_account);
+
+        let sessions = restored
+            .get_sessions(&session.sender_key)
+            .await
+            .unwrap()
+            .unwrap();
+        let sessions = sessions.lock().await;
+
+        assert_eq!(&session, &sessions[0]);
+    }
+
     #[async_test]
     async fn save_and_share_account() {
         let (store, _dir) = get_store(None).await;
@@ -961,6 +1413,67 @@ mod test {
         assert!(!store.users_for_key_query().contains(device.user_id()));
     }
 
+    #[async_test]
+    async fn outbound_group_session_rotation() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+        let settings = EncryptionSettings {
+            rotation_period: Duration::from_secs(3600),
+            rotation_period_msgs: 10,
+            ..Default::default()
+        };
+
+        assert!(store.needs_rotation(&room_id, &settings).await.unwrap());
+
+        let (session, _) = account
+            .create_group_session_pair_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let restore = |mut pickle: PickledOutboundGroupSession| {
+            pickle.settings = settings.clone().into();
+
+            OutboundGroupSession::from_pickle(
+                account.device_id().to_owned().into(),
+                account.identity_keys.clone(),
+                pickle,
+                PicklingMode::Unencrypted,
+            )
+            .unwrap()
+        };
+
+        let pickle = session.pickle(PicklingMode::Unencrypted).await;
+        let changes = Changes {
+            outbound_group_sessions: vec![restore(pickle.clone())],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert!(!store.needs_rotation(&room_id, &settings).await.unwrap());
+
+        let mut used_pickle = pickle.clone();
+        used_pickle.message_count = 10;
+        let changes = Changes {
+            outbound_group_sessions: vec![restore(used_pickle)],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert!(store.needs_rotation(&room_id, &settings).await.unwrap());
+
+        let mut old_pickle = pickle;
+        old_pickle.creation_time = Instant::now()
+            .checked_sub(Duration::from_secs(7200))
+            .unwrap();
+        let changes = Changes {
+            outbound_group_sessions: vec![restore(old_pickle)],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        assert!(store.needs_rotation(&room_id, &settings).await.unwrap());
+    }
+
     #[async_test]
     async fn device_saving() {
         let (_account, store, dir) = get_loaded_store().await;
@@ -1161,6 +1674,19 @@ mod test {
         assert!(store.get_value(&key).await.unwrap().is_none());
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn disk_errors() {
+        let error: CryptoStoreError = sled::Error::Io(std::io::Error::from_raw_os_error(28)).into();
+        assert!(matches!(error, CryptoStoreError::DiskFull(_)));
+
+        let error: CryptoStoreError = sled::Error::Io(std::io::Error::from_raw_os_error(30)).into();
+        assert!(matches!(error, CryptoStoreError::ReadOnlyFilesystem(_)));
+
+        let error: CryptoStoreError = sled::Error::Io(std::io::Error::from_raw_os_error(5)).into();
+        assert!(matches!(error, CryptoStoreError::Io(_)));
+    }
+
     #[async_test]
     async fn olm_hash_saving() {
         let (_, store, _dir) = get_loaded_store().await;
@@ -1177,4 +1703,259 @@ mod test {
         store.save_changes(changes).await.unwrap();
         assert!(store.is_message_known(&hash).await.unwrap());
     }
+
+    #[async_test]
+    async fn pending_to_device_survives_restart() {
+        let (_, store, dir) = get_loaded_store().await;
+
+        let mut recipients = BTreeMap::new();
+        recipients.insert(
+            bob_id(),
+            vec![DeviceIdOrAllDevices::DeviceId(bob_device_id())],
+        );
+
+        store
+            .record_to_device_sent("first", recipients.clone())
+            .await
+            .unwrap();
+        store
+            .record_to_device_sent("second", recipients.clone())
+            .await
+            .unwrap();
+        store.mark_to_device_delivered("second").await.unwrap();
+
+        // Simulate a crash before the first request was acknowledged.
+        drop(store);
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        let pending = store.pending_to_device().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].txn_id, "first");
+        assert_eq!(pending[0].recipients, recipients);
+
+        store.mark_to_device_delivered("first").await.unwrap();
+
+        drop(store);
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        assert!(store.pending_to_device().await.unwrap().is_empty());
+    }
+
+    #[async_test]
+    async fn verify_passphrase() {
+        let (store, _dir) = get_store(Some("secret_passphrase")).await;
+
+        assert!(store.verify_passphrase("secret_passphrase").unwrap());
+        assert!(!store.verify_passphrase("wrong_passphrase").unwrap());
+
+        let (store, _dir) = get_store(None).await;
+        assert!(!store.verify_passphrase("secret_passphrase").unwrap());
+    }
+
+    #[async_test]
+    async fn capabilities() {
+        let (store, _dir) = get_store(None).await;
+        let capabilities = store.capabilities();
+        assert!(capabilities.persistent);
+        assert!(capabilities.export);
+        assert!(!capabilities.encrypted);
+
+        let (store, _dir) = get_store(Some("secret_passphrase")).await;
+        assert!(store.capabilities().encrypted);
+    }
+
+    #[async_test]
+    async fn purge_room() {
+        let (account, store, _dir) = get_loaded_store().await;
+        let room_id = room_id!("!test:localhost");
+        let other_room_id = room_id!("!other:localhost");
+        let identity_keys = account.identity_keys();
+
+        let (outbound, inbound) = account
+            .create_group_session_pair_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let other_session = InboundGroupSession::new(
+            identity_keys.curve25519(),
+            identity_keys.ed25519(),
+            &other_room_id,
+            GroupSessionKey(OlmOutboundGroupSession::new().session_key()),
+        )
+        .unwrap();
+
+        let changes = Changes {
+            outbound_group_sessions: vec![outbound],
+            inbound_group_sessions: vec![inbound.clone(), other_session.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+
+        let event_id = event_id!("$first:example.org");
+        store
+            .check_and_update_message_index(inbound.session_id(), &event_id, 0)
+            .await
+            .unwrap();
+        store
+            .check_and_update_message_index(other_session.session_id(), &event_id, 0)
+            .await
+            .unwrap();
+
+        // Keeping the history only removes the outbound session.
+        assert_eq!(
+            store.purge_room(&room_id, true).await.unwrap(),
+            RoomPurgeCounts {
+                outbound_group_sessions: 1,
+                ..Default::default()
+            }
+        );
+        assert!(store
+            .get_outbound_group_sessions(&room_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_inbound_group_session(&room_id, inbound.sender_key(), inbound.session_id())
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            store.purge_room(&room_id, false).await.unwrap(),
+            RoomPurgeCounts {
+                inbound_group_sessions: 1,
+                outbound_group_sessions: 0,
+                message_indices: 1,
+            }
+        );
+        assert!(store
+            .get_inbound_group_session(&room_id, inbound.sender_key(), inbound.session_id())
+            .await
+            .unwrap()
+            .is_none());
+
+        // The other room is left alone.
+        assert!(store
+            .get_inbound_group_session(
+                &other_room_id,
+                other_session.sender_key(),
+                other_session.session_id()
+            )
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            store
+                .check_and_update_message_index(other_session.session_id(), &event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::AlreadySeen
+        );
+    }
+
+    #[async_test]
+    async fn clear() {
+        let (account, store, dir) = get_loaded_store().await;
+        let (_, session) = get_account_and_session().await;
+        let room_id = room_id!("!test:localhost");
+
+        let (outbound, inbound) = account
+            .create_group_session_pair_with_defaults(&room_id)
+            .await
+            .unwrap();
+
+        let changes = Changes {
+            sessions: vec![session.clone()],
+            outbound_group_sessions: vec![outbound],
+            inbound_group_sessions: vec![inbound.clone()],
+            ..Default::default()
+        };
+        store.save_changes(changes).await.unwrap();
+        store
+            .update_tracked_user(account.user_id(), true)
+            .await
+            .unwrap();
+
+        store.clear().await.unwrap();
+        assert!(store.load_account().await.unwrap().is_none());
+
+        // Sessions can only be loaded with an account, a new one with the
+        // same identifiers doesn't bring any of the old data back.
+        store.save_account(get_account()).await.unwrap();
+
+        assert!(store
+            .get_sessions(&session.sender_key)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_inbound_group_session(&room_id, inbound.sender_key(), inbound.session_id())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(store
+            .get_outbound_group_sessions(&room_id)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!store.is_user_tracked(account.user_id()));
+
+        drop(store);
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        assert!(store
+            .get_sessions(&session.sender_key)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!store.is_user_tracked(account.user_id()));
+    }
+
+    #[async_test]
+    async fn message_index_tracking() {
+        let (_, store, dir) = get_loaded_store().await;
+
+        let event_id = event_id!("$first:example.org");
+        let other_event_id = event_id!("$second:example.org");
+
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::New
+        );
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &other_event_id, 1)
+                .await
+                .unwrap(),
+            MessageIndexStatus::New
+        );
+        assert_eq!(
+            store
+                .check_and_update_message_index("other_session", &other_event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::New
+        );
+
+        drop(store);
+        let store = SledStore::open_with_passphrase(dir.path(), None).expect("Can't create store");
+
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::AlreadySeen
+        );
+        assert_eq!(
+            store
+                .check_and_update_message_index("session", &other_event_id, 0)
+                .await
+                .unwrap(),
+            MessageIndexStatus::Reused { event_id }
+        );
+    }
 }